@@ -0,0 +1,815 @@
+use std::f64::consts::PI;
+
+use rand::Rng;
+
+use crate::camera::Camera;
+use crate::colour::Colour;
+use crate::geom::{Collision, Geometry, Ray};
+use crate::material::Material;
+use crate::scene::{Entity, Light, Object, Scene};
+use crate::spectrum::Wavelengths;
+use crate::trace::{light_hit_weight, offset_origin, path_light_weight, power_heuristic, russian_roulette, upsample};
+use crate::vector::Vector3;
+
+// Bidirectional path tracing.  One path is traced out from the camera and another out from a
+// light, and each vertex of one is joined to each vertex of the other.  Strategy (s, t) uses s
+// vertices from the camera path and t from the light path, so s = 1 is light tracing, which can
+// land on any pixel, and t = 1 is NEE.  Each is weighted against all the other strategies which
+// could have made the same path with the power heuristic.
+//
+// Lights at infinity and glowing materials can only be found from the camera, and are weighted
+// against NEE as in `trace_ray`.  Media and light linking aren't supported.
+
+// Most bounces in a path, as in `trace_ray`.
+const MAX_BOUNCES: usize = 10;
+
+enum VertexKind {
+    Camera,
+    // Along with its `colour * intensity`.
+    Light(Light, Colour),
+    Surface(Material),
+}
+
+struct Vertex {
+    kind: VertexKind,
+    location: Vector3,
+    // Facing the side of the surface the path is on.  The camera, and point and spot lights, have
+    // no surface.
+    normal: Option<Vector3>,
+    // Light (or importance, from the camera) carried along the subpath to here, over the pdf of
+    // picking it.
+    throughput: Colour,
+    // Whether the subpath scattered specularly here, so nothing can be joined to it.
+    delta: bool,
+    // Area pdfs of picking this vertex from the one before it on its own subpath, and from the
+    // one after it, as if the subpath had been traced the other way.
+    pdf_fwd: f64,
+    pdf_rev: f64,
+    // Whether dispersion has left only the hero wavelength being followed.
+    hero_only: bool,
+}
+
+impl Vertex {
+    fn camera(location: Vector3, pdf: f64) -> Vertex {
+        Vertex {
+            kind: VertexKind::Camera,
+            location,
+            normal: None,
+            throughput: Colour::WHITE,
+            delta: false,
+            pdf_fwd: pdf,
+            pdf_rev: 0.0,
+            hero_only: false,
+        }
+    }
+
+    fn light(light: Light, wavelengths: Option<Wavelengths>, location: Vector3, normal: Option<Vector3>) -> Vertex {
+        let radiance = upsample(light.colour, wavelengths) * light.intensity;
+        Vertex {
+            kind: VertexKind::Light(light, radiance),
+            location,
+            normal,
+            throughput: Colour::WHITE,
+            delta: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            hero_only: false,
+        }
+    }
+
+    // Lights and the camera are only joined to where they're picked.
+    fn is_connectible(&self) -> bool {
+        match &self.kind {
+            VertexKind::Surface(material) => !material.is_specular(),
+            _ => false,
+        }
+    }
+
+    fn is_delta_light(&self) -> bool {
+        match &self.kind {
+            VertexKind::Light(light, _) => light.is_delta(),
+            _ => false,
+        }
+    }
+
+    fn cos_theta(&self, direction: Vector3) -> f64 {
+        match self.normal {
+            Some(normal) => normal.dot(direction).abs(),
+            None => 1.0,
+        }
+    }
+
+    // Just off the surface, on the side facing `direction`.
+    fn offset(&self, direction: Vector3) -> Vector3 {
+        match self.normal {
+            Some(normal) if direction.dot(normal) >= 0.0 => self.location + normal * 0.0001,
+            Some(normal) => self.location - normal * 0.0001,
+            None => self.location,
+        }
+    }
+
+    // Turns the solid angle pdf of heading here from `from` into an area pdf.
+    fn area_pdf(&self, pdf: f64, from: Vector3) -> f64 {
+        let to_here = self.location - from;
+        pdf * self.cos_theta(to_here.normed()) / to_here.magnitude()
+    }
+
+    // Area pdf of picking `next` from here, having come from `prev`.
+    fn pdf(&self, camera: &Camera, prev: Option<Vector3>, next: &Vertex) -> f64 {
+        let direction = (next.location - self.location).normed();
+        let pdf = match (&self.kind, prev, self.normal) {
+            (VertexKind::Camera, _, _) => camera.direction_pdf(direction),
+            (VertexKind::Light(light, _), _, _) => light.emission_direction_pdf(self.normal, direction),
+            (VertexKind::Surface(material), Some(prev), Some(normal)) => {
+                material.weight_pdf((prev - self.location).normed(), direction * -1, normal)
+            },
+            _ => 0.0,
+        };
+        next.area_pdf(pdf, self.location)
+    }
+
+    // Area pdf of a light path starting here.
+    fn light_origin_pdf(&self, scene: &Scene) -> f64 {
+        match &self.kind {
+            VertexKind::Light(light, _) => scene.emitter_probability(light) * light.emission_position_pdf(),
+            _ => 0.0,
+        }
+    }
+
+    // Light given off from here towards `to`, for vertices on lights.
+    fn emitted(&self, to: Vector3) -> Colour {
+        match &self.kind {
+            VertexKind::Light(light, radiance) => *radiance * light.emission(self.normal, (to - self.location).normed()),
+            _ => Colour::BLACK,
+        }
+    }
+
+    // Fraction of the light arriving from `prev` which is scattered towards `next`, times the
+    // cosine at this end of the way to `next`.  By reciprocity it's the same the other way round.
+    // Lights give off their own light instead.
+    fn scattering(&self, prev: Option<Vector3>, next: Vector3) -> Colour {
+        let direction = (next - self.location).normed();
+        match (&self.kind, prev, self.normal) {
+            (VertexKind::Light(..), _, _) => self.emitted(next) * self.cos_theta(direction),
+            // As in `trace_ray`, only the side the path arrived on is lit.  The normal faces `prev`.
+            (VertexKind::Surface(_), _, Some(normal)) if normal.dot(direction) <= 0.0 => Colour::BLACK,
+            (VertexKind::Surface(material), Some(prev), Some(normal)) => {
+                material.brdf((prev - self.location).normed(), direction * -1, normal)
+            },
+            _ => Colour::BLACK,
+        }
+    }
+}
+
+// Returns the light reaching the camera along `ray`, already multiplied by the ray's `weight`
+// (other than the background, which is shown as it is), along with the light traced from the
+// light to any pixel.
+pub fn trace_bidirectional(
+    scene: &Scene,
+    camera: &Camera,
+    ray: Ray,
+    weight: f64,
+    screen: (f64, f64),
+    wavelengths: Option<Wavelengths>,
+) -> (Colour, Vec<(u32, u32, Colour)>) {
+    let mut camera_path = vec![Vertex::camera(ray.origin, 1.0 / camera.lens_area())];
+    let pdf = camera.direction_pdf(ray.direction);
+    let (mut colour, background) = random_walk(scene, &mut camera_path, ray, Colour::WHITE, pdf, wavelengths, Some(screen));
+
+    let light_path = light_subpath(scene, wavelengths);
+
+    let mut splats = Vec::new();
+    for s in 1 ..= camera_path.len() {
+        for t in 0 ..= light_path.len() {
+            // Joining the camera straight to a light is left to the camera path finding it.
+            if (s == 1 && t < 2) || s + t - 2 > MAX_BOUNCES {
+                continue;
+            }
+
+            if s == 1 {
+                splats.extend(connect_to_camera(scene, camera, &light_path, t, wavelengths));
+            } else {
+                let contribution = match t {
+                    0 => found_light(scene, camera, &camera_path, s, wavelengths),
+                    1 => connect_to_light(scene, camera, &camera_path, s, wavelengths),
+                    _ => connect(scene, camera, &camera_path, &light_path, s, t, wavelengths),
+                };
+                contribution.check();
+                colour += contribution;
+            }
+        }
+    }
+
+    (colour * weight + background, splats)
+}
+
+fn light_subpath(scene: &Scene, wavelengths: Option<Wavelengths>) -> Vec<Vertex> {
+    let mut path = Vec::new();
+    let (light, probability) = match scene.random_emitter() {
+        Some(picked) => picked,
+        None => return path,
+    };
+    let emission = match light.sample_emission() {
+        Some(emission) => emission,
+        None => return path,
+    };
+    let pdf_direction = light.emission_direction_pdf(emission.normal, emission.direction);
+    if pdf_direction <= 0.0 || !pdf_direction.is_finite() {
+        return path;
+    }
+
+    let mut origin = Vertex::light(light.clone(), wavelengths, emission.origin, emission.normal);
+    origin.pdf_fwd = probability * light.emission_position_pdf();
+    origin.throughput = Colour::WHITE / origin.pdf_fwd;
+    let throughput = origin.throughput * origin.scattering(None, emission.origin + emission.direction) / pdf_direction;
+    let ray = Ray::new(origin.offset(emission.direction), emission.direction);
+    path.push(origin);
+
+    if throughput.max() > 0.0 {
+        random_walk(scene, &mut path, ray, throughput, pdf_direction, wavelengths, None);
+    }
+    path
+}
+
+// Extends the subpath along `ray` until it's long enough, or is absorbed.  `pdf` is the solid
+// angle pdf of picking the ray's direction from the last vertex.
+// `screen` is given for paths from the camera, which return the light they find on their own:
+// glowing materials and lights at infinity, and separately the background if it's seen.  Light
+// paths just stop at lights.
+fn random_walk(
+    scene: &Scene,
+    path: &mut Vec<Vertex>,
+    mut ray: Ray,
+    mut throughput: Colour,
+    mut pdf: f64,
+    wavelengths: Option<Wavelengths>,
+    screen: Option<(f64, f64)>,
+) -> (Colour, Colour) {
+    let from_camera = screen.is_some();
+    // Light paths start on the light, but camera paths can end on one.
+    let max_vertices = if from_camera { MAX_BOUNCES + 2 } else { MAX_BOUNCES + 1 };
+    let mut found = Colour::BLACK;
+    let mut background = Colour::BLACK;
+    let mut hero_only = false;
+    let mut loops = 0;
+
+    while path.len() < max_vertices && loops <= MAX_BOUNCES {
+        loops += 1;
+        let prev = path.len() - 1;
+
+        let (collision, entity) = match (scene.find_intersection(ray), screen) {
+            (Some(hit), _) => hit,
+            (None, Some(screen)) => {
+                let primary = path.len() == 1;
+                match scene.background.as_ref().filter(|_| primary) {
+                    Some(colour) => background = throughput * upsample(colour.colour(screen), wavelengths),
+                    None => {
+                        let escaped = escaped(scene, &path[prev], ray, pdf, primary, wavelengths);
+                        found += terminate(throughput * escaped, hero_only, wavelengths);
+                    },
+                };
+                break;
+            },
+            (None, None) => break,
+        };
+
+        let cos_in = ray.direction.dot(collision.normal * -1);
+        if cos_in <= 0.0 {
+            break;
+        }
+
+        let object = match entity {
+            Entity::Light(light) => {
+                // The camera path becomes a full path to the light.
+                if from_camera && light.shines_towards(collision.front_face) {
+                    let mut vertex = Vertex::light(light, wavelengths, collision.location, Some(collision.normal));
+                    vertex.throughput = throughput;
+                    vertex.pdf_fwd = vertex.area_pdf(pdf, path[prev].location);
+                    vertex.hero_only = hero_only;
+                    path.push(vertex);
+                }
+                break;
+            },
+            Entity::Object(o) => o,
+        };
+
+        let material = surface_material(scene, &object, &collision, wavelengths);
+        let vec_out = ray.direction * -1;
+
+        // Medium boundaries aren't really there.
+        if material.is_null() {
+            ray = Ray::new(offset_origin(&collision, ray.direction), ray.direction);
+            continue;
+        }
+
+        if from_camera {
+            found += terminate(material.emittance(vec_out, cos_in) * throughput, hero_only, wavelengths);
+        }
+
+        // For light paths this picks where the light goes next, which works since the materials
+        // are reciprocal.
+        let (direction, sample_pdf, brdf, is_specular) = material.sample(vec_out, collision.normal);
+        let pdf_rev = if is_specular { 0.0 } else { material.weight_pdf(direction, vec_out * -1, collision.normal) };
+        let dispersive = material.is_dispersive();
+
+        let mut vertex = Vertex {
+            kind: VertexKind::Surface(material),
+            location: collision.location,
+            normal: Some(collision.normal),
+            throughput,
+            delta: is_specular,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            hero_only,
+        };
+        vertex.pdf_fwd = vertex.area_pdf(pdf, path[prev].location);
+        path[prev].pdf_rev = path[prev].area_pdf(pdf_rev, collision.location);
+        path.push(vertex);
+
+        if sample_pdf <= 0.0 {
+            break;
+        }
+
+        // Each wavelength would have been bent a different way, and we've only followed the hero
+        // wavelength.
+        if wavelengths.is_some() && dispersive && direction.dot(collision.normal) < 0.0 {
+            hero_only = true;
+        }
+
+        throughput = throughput * (brdf / sample_pdf);
+        if throughput.max() <= 0.0 || !russian_roulette(&mut throughput, loops as u32) {
+            break;
+        }
+
+        // Specular bounces can't be picked any other way, so their pdfs don't count.
+        pdf = if is_specular { 0.0 } else { sample_pdf };
+        ray = Ray::new(offset_origin(&collision, direction), direction);
+    }
+
+    (found, background)
+}
+
+fn surface_material(scene: &Scene, object: &Object, collision: &Collision, wavelengths: Option<Wavelengths>) -> Material {
+    let material = match object.geometry {
+        Geometry::Mesh(ref mesh) => object.material.resolve(collision, scene.models.get(mesh.model)),
+        _ => object.material.clone(),
+    }.facing(collision.front_face);
+
+    match wavelengths {
+        Some(ref wl) => material.upsample(wl),
+        None => material,
+    }
+}
+
+// Light from infinity seen by a camera path leaving the scene from `last`.
+fn escaped(scene: &Scene, last: &Vertex, ray: Ray, pdf: f64, primary: bool, wavelengths: Option<Wavelengths>) -> Colour {
+    let specular = primary || last.delta;
+    let weight = light_hit_weight(specular, pdf, scene.sky_pdf(last.location, ray.direction * -1));
+    let mut colour = upsample(scene.skybox.ambient_light(ray.origin, ray.direction * -1), wavelengths) * weight;
+    for l in scene.distant_lights(ray.direction) {
+        let weight = path_light_weight(scene, l, ray.direction, specular, pdf, last.location);
+        colour += upsample(l.colour, wavelengths) * l.intensity * weight;
+    }
+    colour
+}
+
+// The camera path hit a light by itself.
+fn found_light(scene: &Scene, camera: &Camera, camera_path: &[Vertex], s: usize, wavelengths: Option<Wavelengths>) -> Colour {
+    let end = &camera_path[s - 1];
+    let emitted = end.emitted(camera_path[s - 2].location);
+    if emitted.max() <= 0.0 {
+        return Colour::BLACK;
+    }
+
+    let weight = mis_weight(scene, camera, &camera_path[.. s - 1], end, &[], None);
+    terminate(end.throughput * emitted * weight, end.hero_only, wavelengths)
+}
+
+// Joins the end of the camera path to a point picked on a light, as with NEE in `trace_ray`.
+// The sky is sampled too, but can only be found this way or by the camera path, so is weighted
+// as in `trace_ray`.
+fn connect_to_light(scene: &Scene, camera: &Camera, camera_path: &[Vertex], s: usize, wavelengths: Option<Wavelengths>) -> Colour {
+    let end = &camera_path[s - 1];
+    let (material, normal) = match (&end.kind, end.normal) {
+        (VertexKind::Surface(material), Some(normal)) if !material.is_specular() => (material, normal),
+        _ => return Colour::BLACK,
+    };
+    let vec_out = (camera_path[s - 2].location - end.location).normed();
+    // Light arriving from behind the surface doesn't count, as in `trace_ray`.
+    let brdf = |in_dir: Vector3| if normal.dot(in_dir) < 0.0 {
+        material.brdf(vec_out, in_dir, normal)
+    } else {
+        Colour::BLACK
+    };
+
+    let from_light = match scene.random_light(end.location) {
+        Some((light, probability)) => {
+            let (in_dir, distance, inv_pdf) = light.sample(end.location);
+            let f = brdf(in_dir);
+            if inv_pdf <= 0.0 || f.max() <= 0.0 {
+                Colour::BLACK
+            } else if light.is_at_infinity() {
+                if reaches_sky(scene, end, in_dir * -1) {
+                    let weight = power_heuristic(probability / inv_pdf, material.weight_pdf(vec_out, in_dir, normal));
+                    f * upsample(light.colour, wavelengths) * light.intensity * (inv_pdf * weight / probability)
+                } else {
+                    Colour::BLACK
+                }
+            } else {
+                // Make the point on the light into the start of a light path.
+                let location = end.location - in_dir * distance;
+                let light_normal = light.surface_normal(location)
+                    .map(|n| if n.dot(in_dir) < 0.0 { n * -1 } else { n });
+                let mut sampled = Vertex::light(light.clone(), wavelengths, location, light_normal);
+                sampled.pdf_fwd = sampled.light_origin_pdf(scene);
+
+                if unoccluded(scene, end, &sampled) {
+                    let weight = mis_weight(scene, camera, &camera_path[.. s - 1], end, &[], Some(&sampled));
+                    let radiance = upsample(light.colour, wavelengths) * light.intensity;
+                    f * radiance * (inv_pdf * weight / probability)
+                } else {
+                    Colour::BLACK
+                }
+            }
+        },
+        None => Colour::BLACK,
+    };
+
+    let from_sky = match scene.sample_sky(end.location) {
+        Some((in_dir, pdf)) if pdf > 0.0 => {
+            let f = brdf(in_dir);
+            if f.max() > 0.0 && reaches_sky(scene, end, in_dir * -1) {
+                let weight = power_heuristic(pdf, material.weight_pdf(vec_out, in_dir, normal));
+                f * upsample(scene.skybox.ambient_light(end.location, in_dir), wavelengths) * (weight / pdf)
+            } else {
+                Colour::BLACK
+            }
+        },
+        _ => Colour::BLACK,
+    };
+
+    terminate(end.throughput * (from_light + from_sky), end.hero_only, wavelengths)
+}
+
+// Joins the end of the camera path to the end of the light path.
+fn connect(
+    scene: &Scene,
+    camera: &Camera,
+    camera_path: &[Vertex],
+    light_path: &[Vertex],
+    s: usize,
+    t: usize,
+    wavelengths: Option<Wavelengths>,
+) -> Colour {
+    let (c, l) = (&camera_path[s - 1], &light_path[t - 1]);
+    if !c.is_connectible() || !l.is_connectible() {
+        return Colour::BLACK;
+    }
+
+    let f = c.scattering(Some(camera_path[s - 2].location), l.location)
+        * l.scattering(Some(light_path[t - 2].location), c.location);
+    let contribution = c.throughput * f * l.throughput / (l.location - c.location).magnitude();
+    if contribution.max() <= 0.0 || !unoccluded(scene, c, l) {
+        return Colour::BLACK;
+    }
+
+    let weight = mis_weight(scene, camera, &camera_path[.. s - 1], c, &light_path[.. t - 1], Some(l));
+    terminate(contribution * weight, c.hero_only || l.hero_only, wavelengths)
+}
+
+// Joins the end of the light path to a point picked on the lens, returning the pixel it lands on.
+fn connect_to_camera(
+    scene: &Scene,
+    camera: &Camera,
+    light_path: &[Vertex],
+    t: usize,
+    wavelengths: Option<Wavelengths>,
+) -> Option<(u32, u32, Colour)> {
+    let l = &light_path[t - 1];
+    if !l.is_connectible() {
+        return None;
+    }
+
+    let mut rng = rand::thread_rng();
+    let r = rng.gen::<f64>().sqrt();
+    let phi = 2.0 * PI * rng.gen::<f64>();
+    let lens = camera.world_point_on_lens((r * phi.cos(), r * phi.sin()));
+    let (x, y, importance) = camera.project(lens, l.location)?;
+
+    let prev = if t >= 2 { Some(light_path[t - 2].location) } else { None };
+    let contribution = l.throughput * l.scattering(prev, lens) * importance;
+    let sampled = Vertex::camera(lens, 1.0 / camera.lens_area());
+    if contribution.max() <= 0.0 || !unoccluded(scene, l, &sampled) {
+        return None;
+    }
+
+    let weight = mis_weight(scene, camera, &[], &sampled, &light_path[.. t - 1], Some(l));
+    let contribution = terminate(contribution * weight, l.hero_only, wavelengths);
+    contribution.check();
+    Some((x, y, contribution))
+}
+
+// Power heuristic weight of a strategy against every other strategy which could have made the
+// same path.  `camera_end` and `light_end` are the vertices being joined, which may have been
+// picked just for this strategy, and `camera_path` and `light_path` are the vertices before them.
+// Walking out from the join, each vertex moved to the other subpath changes the pdf of the whole
+// path by the ratio of its pdf from the other side to its pdf from its own.
+fn mis_weight(
+    scene: &Scene,
+    camera: &Camera,
+    camera_path: &[Vertex],
+    camera_end: &Vertex,
+    light_path: &[Vertex],
+    light_end: Option<&Vertex>,
+) -> f64 {
+    let s = camera_path.len() + 1;
+    let t = light_path.len() + if light_end.is_some() { 1 } else { 0 };
+
+    // The only other way would be joining the camera straight to the light.
+    if s + t == 2 {
+        return 1.0;
+    }
+
+    let camera_before = camera_path.last();
+    let light_before = light_path.last();
+
+    // Pdfs of the vertices either side of the join being picked from the other side.
+    let (camera_end_rev, camera_before_rev) = match light_end {
+        Some(l) => (
+            l.pdf(camera, light_before.map(|v| v.location), camera_end),
+            camera_before.map_or(0.0, |v| camera_end.pdf(camera, Some(l.location), v)),
+        ),
+        // The end of the camera path is on a light, and would start the light path.
+        None => (
+            camera_end.light_origin_pdf(scene),
+            camera_before.map_or(0.0, |v| camera_end.pdf(camera, None, v)),
+        ),
+    };
+    let light_end_rev = light_end.map_or(0.0, |l| camera_end.pdf(camera, camera_before.map(|v| v.location), l));
+    let light_before_rev = match (light_end, light_before) {
+        (Some(l), Some(v)) => l.pdf(camera, Some(camera_end.location), v),
+        _ => 0.0,
+    };
+
+    // Specular bounces leave pdfs of 0, but any strategy joining to them is skipped anyway.
+    let remap = |pdf: f64| if pdf > 0.0 { pdf } else { 1.0 };
+
+    let mut sum = 0.0;
+    let mut ratio = 1.0;
+    for i in (1 .. s).rev() {
+        let (vertex, pdf_rev, delta) = if i == s - 1 {
+            (camera_end, camera_end_rev, false)
+        } else if i + 2 == s {
+            (&camera_path[i], camera_before_rev, camera_path[i].delta)
+        } else {
+            (&camera_path[i], camera_path[i].pdf_rev, camera_path[i].delta)
+        };
+        ratio *= remap(pdf_rev) / remap(vertex.pdf_fwd);
+        if !delta && !camera_path[i - 1].delta {
+            sum += ratio * ratio;
+        }
+    }
+
+    let mut ratio = 1.0;
+    for i in (0 .. t).rev() {
+        let (vertex, pdf_rev, delta) = match light_end {
+            Some(l) if i == t - 1 => (l, light_end_rev, false),
+            _ if i + 2 == t => (&light_path[i], light_before_rev, light_path[i].delta),
+            _ => (&light_path[i], light_path[i].pdf_rev, light_path[i].delta),
+        };
+        ratio *= remap(pdf_rev) / remap(vertex.pdf_fwd);
+        let delta_before = if i > 0 { light_path[i - 1].delta } else { vertex.is_delta_light() };
+        if !delta && !delta_before {
+            sum += ratio * ratio;
+        }
+    }
+
+    1.0 / (1.0 + sum)
+}
+
+// Whether nothing but medium boundaries lies between the two vertices.
+fn unoccluded(scene: &Scene, a: &Vertex, b: &Vertex) -> bool {
+    let from = a.offset(b.location - a.location);
+    let to = b.offset(a.location - b.location);
+    let between = to - from;
+    is_clear(scene, Ray::new(from, between.normed()), between.magnitude().sqrt())
+}
+
+fn reaches_sky(scene: &Scene, from: &Vertex, direction: Vector3) -> bool {
+    is_clear(scene, Ray::new(from.offset(direction), direction), f64::INFINITY)
+}
+
+fn is_clear(scene: &Scene, mut ray: Ray, distance: f64) -> bool {
+    let mut remaining = distance;
+    loop {
+        match scene.find_intersection(ray) {
+            Some((collision, Entity::Object(o))) if collision.distance < remaining && o.material.is_null() => {
+                ray = Ray::new(offset_origin(&collision, ray.direction), ray.direction);
+                remaining -= collision.distance;
+            },
+            Some((collision, _)) if collision.distance < remaining => return false,
+            _ => return true,
+        }
+    }
+}
+
+// Light which only made it along the path at the hero wavelength.
+fn terminate(colour: Colour, hero_only: bool, wavelengths: Option<Wavelengths>) -> Colour {
+    match wavelengths {
+        Some(mut wl) if hero_only => wl.terminate_secondary(colour),
+        _ => colour,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use rand::Rng;
+
+    use crate::bdpt::*;
+    use crate::geom::Primitive;
+    use crate::material::MaterialColour;
+    use crate::matrix::Matrix3;
+    use crate::model::ModelLibrary;
+    use crate::scene::{Background, Skybox};
+    use crate::serde::LightDescription;
+    use crate::trace::trace_ray;
+
+    fn test_camera() -> Camera {
+        let mut camera = Camera::new(16, 12);
+        camera.sensor_width = 0.144;
+        camera.sensor_height = 0.108;
+        camera.focal_length = 0.05;
+        camera.aperture = 4.0;
+        camera.distance_from_lens = 0.052;
+        camera.set_orientation(Matrix3::rotation(0.0, 0.0, 0.0));
+        camera.set_position(Vector3::new(0.0, 0.0, -1.0));
+        camera
+    }
+
+    fn grey() -> Material {
+        Material::lambertian(MaterialColour::Static(Colour::rgb(0.5, 0.5, 0.5)), Colour::BLACK)
+    }
+
+    fn light(id: usize, geometry: &str) -> Light {
+        let description: LightDescription = serde_yaml::from_str(&format!("{{ intensity: 1.0, geometry: {} }}", geometry)).unwrap();
+        description.to_light(id, &HashMap::new())
+    }
+
+    // A point light and a bulb.
+    fn lamps() -> Vec<Light> {
+        vec![
+            light(0, "{ type: Point, x: 0.5, y: 0.5, z: 0.5 }"),
+            light(1, "{ type: Sphere, center: { x: -0.5, y: -0.5, z: 0.8 }, radius: 0.2 }"),
+        ]
+    }
+
+    // A grey room, lit by the lamps.
+    fn closed_scene() -> Scene {
+        room(grey(), lamps())
+    }
+
+    fn room(material: Material, lights: Vec<Light>) -> Scene {
+        let room = Object {
+            id: 0,
+            geometry: Geometry::Primitive(Primitive::sphere(Vector3::new(0.0, 0.0, 0.0), 2.0)),
+            material,
+            medium: None,
+        };
+        Scene::new(ModelLibrary::new(), vec![room], lights, Skybox::flat(Colour::BLACK), None)
+    }
+
+    #[test]
+    fn test_background_unweighted() {
+        let ball = Object {
+            id: 0,
+            geometry: Geometry::Primitive(Primitive::sphere(Vector3::new(0.0, -10.0, 0.0), 1.0)),
+            material: grey(),
+            medium: None,
+        };
+        let mut scene = Scene::new(ModelLibrary::new(), vec![ball], vec![], Skybox::flat(Colour::WHITE), None);
+        let camera = test_camera();
+        let ray = Ray::new(Vector3::new(0.0, 0.0, -1.0), Vector3::new(0.0, 0.0, 1.0));
+
+        // As with `trace_ray`, the sky is exposed like the rest of the scene, but the background
+        // isn't.
+        assert_eq!(trace_bidirectional(&scene, &camera, ray, 0.25, (0.5, 0.5), None).0.g, 0.25);
+        scene.background = Some(Background::Flat(Colour::rgb(0.5, 0.5, 0.5)));
+        assert_eq!(trace_bidirectional(&scene, &camera, ray, 0.25, (0.5, 0.5), None).0.g, 0.5);
+    }
+
+    #[test]
+    fn test_matches_trace_ray() {
+        matches_trace_ray(&closed_scene());
+
+        // `trace_ray` can't find the point light's caustics off the gloss, so just the bulb.
+        let gloss = Material::gloss(MaterialColour::Static(Colour::rgb(0.5, 0.5, 0.5)), 0.04, 0.0);
+        let bulb = light(0, "{ type: Sphere, center: { x: -0.5, y: -0.5, z: 0.8 }, radius: 0.2 }");
+        matches_trace_ray(&room(gloss, vec![bulb]));
+    }
+
+    fn matches_trace_ray(scene: &Scene) {
+        let camera = test_camera();
+        let mut rng = rand::thread_rng();
+
+        let n = 20_000;
+        let (mut path_traced, mut bidirectional, mut splatted) = (0.0, 0.0, 0.0);
+        for _ in 0 .. n {
+            let (x, y) = (rng.gen_range(0, camera.width), rng.gen_range(0, camera.height));
+            let r = rng.gen::<f64>().sqrt();
+            let phi = 2.0 * PI * rng.gen::<f64>();
+            let square = (rng.gen(), rng.gen());
+            let (ray, weight) = camera.get_ray_for_pixel(x, y, square, (r * phi.cos(), r * phi.sin()));
+            let screen = camera.screen_position(x, y, square);
+
+            path_traced += trace_ray(scene, ray, weight, screen, None).g;
+
+            let (colour, splats) = trace_bidirectional(scene, &camera, ray, weight, screen, None);
+            bidirectional += colour.g;
+            splatted += splats.iter().map(|(_, _, c)| c.g).sum::<f64>();
+        }
+
+        // Means over the whole image.  Splats are over every light path, like in the estimator,
+        // but land anywhere, so are shared between all the pixels.
+        let pixels = (camera.width * camera.height) as f64;
+        let path_traced = path_traced / n as f64;
+        let bidirectional = (bidirectional + splatted / pixels) / n as f64;
+        assert!((path_traced - bidirectional).abs() < 0.02 * path_traced, "{} vs {}", path_traced, bidirectional);
+    }
+
+    fn surface(location: Vector3, normal: Vector3) -> Vertex {
+        Vertex {
+            kind: VertexKind::Surface(grey()),
+            location,
+            normal: Some(normal.normed()),
+            throughput: Colour::WHITE,
+            delta: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            hero_only: false,
+        }
+    }
+
+    // Fills in the pdfs as tracing the vertices in order would have.  The last two reverse pdfs
+    // depend on where the path would have gone next, so are left wrong for `mis_weight` to fix.
+    fn subpath(camera: &Camera, mut path: Vec<Vertex>, origin_pdf: f64) -> Vec<Vertex> {
+        path[0].pdf_fwd = origin_pdf;
+        for i in 1 .. path.len() {
+            let prev = if i >= 2 { Some(path[i - 2].location) } else { None };
+            path[i].pdf_fwd = path[i - 1].pdf(camera, prev, &path[i]);
+        }
+        for i in 0 .. path.len() {
+            path[i].pdf_rev = if i + 2 < path.len() {
+                path[i + 1].pdf(camera, Some(path[i + 2].location), &path[i])
+            } else {
+                123.0
+            };
+        }
+        path
+    }
+
+    #[test]
+    fn test_mis_weights_sum_to_one() {
+        let scene = closed_scene();
+        let camera = test_camera();
+
+        // Camera, two bounces, and a point on each light.
+        let (x1, x2) = (Vector3::new(0.1, -0.2, 1.5), Vector3::new(1.2, 0.3, 0.9));
+        let bulb_centre = Vector3::new(-0.5, -0.5, 0.8);
+        let on_bulb = bulb_centre + (x2 - bulb_centre).normed() * 0.2;
+        let ends = [(Vector3::new(0.5, 0.5, 0.5), None), (on_bulb, Some((on_bulb - bulb_centre).normed()))];
+
+        for (light, (location, normal)) in lamps().iter().zip(ends.iter()) {
+            let full_path = || {
+                let towards = |from: Vector3, a: Vector3, b: Vector3| (a - from).normed() + (b - from).normed();
+                vec![
+                    Vertex::camera(camera.location, 1.0 / camera.lens_area()),
+                    surface(x1, towards(x1, camera.location, x2)),
+                    surface(x2, towards(x2, x1, *location)),
+                    Vertex::light(light.clone(), None, *location, *normal),
+                ]
+            };
+
+            let mut total = 0.0;
+            for s in 1 ..= 4 {
+                let t = 4 - s;
+                let camera_path = subpath(&camera, full_path().into_iter().take(s).collect(), 1.0 / camera.lens_area());
+                let weight = if t == 0 {
+                    // Point lights can't be hit.
+                    if light.is_delta() {
+                        continue;
+                    }
+                    mis_weight(&scene, &camera, &camera_path[.. s - 1], &camera_path[s - 1], &[], None)
+                } else {
+                    let origin_pdf = scene.emitter_probability(light) * light.emission_position_pdf();
+                    let light_path = subpath(&camera, full_path().into_iter().rev().take(t).collect(), origin_pdf);
+                    mis_weight(&scene, &camera, &camera_path[.. s - 1], &camera_path[s - 1], &light_path[.. t - 1], Some(&light_path[t - 1]))
+                };
+                assert!(weight > 0.0 && weight < 1.0);
+                total += weight;
+            }
+            assert!((total - 1.0).abs() < 1e-9, "{}", total);
+        }
+    }
+}
@@ -13,7 +13,8 @@ fn ray_box_collide(ray: &Ray, aabb: &AABB, len: Option<f64>) -> Option<f64> {
     let tmin = tsmaller.max();
     let tmax = tbigger.min();
 
-    if tmin < tmax && len.map_or(true, |d| tmin < d) { 
+    // Flat boxes (e.g. around an axis-aligned quad) have tmin == tmax, so that must still count.
+    if tmin <= tmax && len.map_or(true, |d| tmin < d) {
         Some(tmin)
     } else {
         None
@@ -75,7 +76,18 @@ pub struct BVH<T> {
 }
 
 impl <T> BVH<T> {
+    pub fn aabb(&self) -> &AABB {
+        self.root.aabb()
+    }
+
     pub fn find_intersection(&self, ray: Ray) -> Option<(Collision, &T)> {
+        self.find_intersection_filtered(ray, |_, _| true)
+    }
+
+    // Like find_intersection, but collisions for which `accept` returns false are ignored, as if
+    // the ray passed straight through.
+    pub fn find_intersection_filtered<F>(&self, ray: Ray, accept: F) -> Option<(Collision, &T)>
+    where F: Fn(&Collision, &T) -> bool {
         let mut stack: [Option<&Node>; 100] = [None; 100];
         let mut stack_ptr: usize = 0;
 
@@ -91,15 +103,16 @@ impl <T> BVH<T> {
             match node {
                 Node::Leaf(ref leaf) => {
                     if let Some(col) = leaf.primitive.intersect(ray) {
+                        let item = &self.items[leaf.obj];
                         closest_collision = match closest_collision {
                             Some((best, o)) =>  {
-                                if col.distance < best.distance {
-                                    Some((col, &self.items[leaf.obj]))
+                                if col.distance < best.distance && accept(&col, item) {
+                                    Some((col, item))
                                 } else {
                                     Some((best, o))
                                 }
                             },
-                            None => Some((col, &self.items[leaf.obj])),
+                            None => if accept(&col, item) { Some((col, item)) } else { None },
                         };
                     }
                     if stack_ptr == 0 {
@@ -390,10 +403,24 @@ fn get_bit(mc: u64, bit: u16) -> bool {
 #[cfg(test)]
 mod test {
     use crate::bvh;
+    use crate::geom::{AABB, Ray};
+    use crate::vector::Vector3;
 
     #[test]
     fn test_morton_code() {
         let mc = bvh::morton_code(4, 0b0000_1001, 0b0000_1100, 0b0000_0011);
         assert_eq!(mc, 0b1100_1000_1101_0000__0000_0000_0000_0000__0000_0000_0000_0000__0000_0000_0000_0000);
     }
+
+    #[test]
+    fn test_flat_box() {
+        // Like the box around a horizontal quad.
+        let aabb = AABB::new(Vector3::new(-1.0, 2.0, -1.0), Vector3::new(1.0, 2.0, 1.0));
+        let ray = Ray::new(Vector3::new(0.5, 0.0, 0.5), Vector3::new(0.0, 1.0, 0.0));
+        assert_eq!(bvh::ray_box_collide(&ray, &aabb, None), Some(2.0));
+        assert_eq!(bvh::ray_box_collide(&ray, &aabb, Some(1.0)), None);
+
+        let miss = Ray::new(Vector3::new(1.5, 0.0, 0.5), Vector3::new(0.0, 1.0, 0.0));
+        assert_eq!(bvh::ray_box_collide(&miss, &aabb, None), None);
+    }
 }
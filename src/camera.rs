@@ -1,3 +1,5 @@
+use std::f64::consts::PI;
+
 use crate::colour::Colour;
 use crate::geom::Ray;
 use crate::matrix::Matrix3;
@@ -20,6 +22,8 @@ pub struct Camera {
     pub sensor_height: f64,
     pub width: u32,
     pub height: u32,
+    // Multiplies all the light reaching the sensor.
+    pub exposure: f64,
 }
 
 impl Camera {
@@ -34,6 +38,7 @@ impl Camera {
             sensor_height: height as f64,
             width,
             height,
+            exposure: 1.0,
         };
         camera
     }
@@ -44,6 +49,17 @@ impl Camera {
         Vector3::new(lens_x * aperture_radius, lens_y * aperture_radius, 0.0)
     }
 
+    // Where the ray from `get_ray_for_pixel` starts on the screen, from (0, 0) in the top left to
+    // (1, 1) in the bottom right.
+    pub fn screen_position(&self, x: u32, y: u32, point_on_square: (f64, f64)) -> (f64, f64) {
+        // Flipped like in `get_ray_for_pixel`.
+        let (x_offset, y_offset) = point_on_square;
+        (
+            (x as f64 + 1.0 - x_offset) / self.width as f64,
+            (y as f64 + 1.0 - y_offset) / self.height as f64,
+        )
+    }
+
     pub fn get_ray_for_pixel(
         &self,
         mut x: u32,
@@ -88,11 +104,73 @@ impl Camera {
         let direction = self.rot.clone() * norm_dir;
 
         // Weight is d.n, but sinze n is just (0,0,1) we can shortcut.
-        let weight = norm_dir.z;
+        let weight = norm_dir.z * self.exposure;
 
         (Ray::new(origin, direction), weight)
     }
 
+    // Area of the lens, which `get_ray_for_pixel` picks points on uniformly.
+    pub fn lens_area(&self) -> f64 {
+        let aperture_radius = self.focal_length / self.aperture;
+        PI * aperture_radius * aperture_radius
+    }
+
+    // A point on the lens in world space, as `get_ray_for_pixel` would pick it.
+    pub fn world_point_on_lens(&self, point_on_disk: (f64, f64)) -> Vector3 {
+        self.rot * self.point_on_lens(point_on_disk) + self.location
+    }
+
+    // Solid angle pdf of `get_ray_for_pixel` heading off in the given direction from its point
+    // on the lens, when the pixel is picked uniformly over the whole image.
+    pub fn direction_pdf(&self, direction: Vector3) -> f64 {
+        let cos_theta = (self.rot.transpose() * direction).normed().z;
+        if cos_theta <= 0.0 {
+            return 0.0;
+        }
+
+        // Each bit of the sensor is focused onto the focal plane, and seen from the lens.
+        let v = self.distance_from_lens;
+        let sensor_area = self.sensor_width * self.sensor_height;
+        v * v / (sensor_area * cos_theta * cos_theta * cos_theta)
+    }
+
+    // The inverse of `get_ray_for_pixel`: the pixel which light travelling from `point` to the
+    // given point on the lens (in world space) lands on, if any.
+    // Along with it is the weight to give light leaving `point` towards the lens with unit
+    // intensity, so that averaging over points on the lens matches the pixel's value.
+    pub fn project(&self, lens: Vector3, point: Vector3) -> Option<(u32, u32, f64)> {
+        let inverse = self.rot.transpose();
+        let l = inverse * (lens - self.location);
+        let to_point = inverse * (point - lens);
+        let distance_sq = to_point.magnitude();
+        let cos_theta = to_point.normed().z;
+        if cos_theta <= 0.0 {
+            return None;
+        }
+
+        // Follow it back through the focal plane and the lens to the sensor.
+        let f = self.focal_length;
+        let v = self.distance_from_lens;
+        let p = (f * v) / (v - f);
+        let k = (l + to_point * (p / to_point.z)) * (-v / p);
+
+        let x_scale = self.sensor_width / (self.width as f64);
+        let y_scale = self.sensor_height / (self.height as f64);
+        let sensor_x = k.x / x_scale + (self.width as f64) / 2.0;
+        let sensor_y = (self.height as f64) / 2.0 - k.y / y_scale;
+        if sensor_x < 0.0 || sensor_y < 0.0 || sensor_x >= self.width as f64 || sensor_y >= self.height as f64 {
+            return None;
+        }
+
+        // Flipped back like in `get_ray_for_pixel`.
+        let x = self.width - sensor_x as u32 - 1;
+        let y = self.height - sensor_y as u32 - 1;
+
+        // The ray weight is d.n, and one pixel covers (v^2 / (pixel area * cos^3)) steradians.
+        let weight = self.exposure * v * v / (x_scale * y_scale * cos_theta * cos_theta * distance_sq);
+        Some((x, y, weight))
+    }
+
     pub fn set_orientation(&mut self, orientation: Matrix3) {
         self.rot = orientation;
     }
@@ -101,3 +179,32 @@ impl Camera {
         self.location = location;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::camera::*;
+
+    #[test]
+    fn test_project_finds_pixel() {
+        let mut camera = Camera::new(64, 48);
+        camera.sensor_width = 0.036;
+        camera.sensor_height = 0.027;
+        camera.focal_length = 0.05;
+        camera.aperture = 4.0;
+        camera.distance_from_lens = 0.052;
+        camera.set_orientation(Matrix3::rotation(0.3, 0.1, 0.0));
+        camera.set_position(Vector3::new(1.0, 2.0, -3.0));
+
+        for &(x, y) in &[(0, 0), (10, 40), (32, 24), (63, 47)] {
+            let disk = (0.3, -0.5);
+            let (ray, _) = camera.get_ray_for_pixel(x, y, (0.5, 0.5), disk);
+            let point = ray.origin + ray.direction * 7.0;
+
+            // Light from the point back through the same bit of the lens lands on the same pixel.
+            let lens = camera.world_point_on_lens(disk);
+            let (px, py, weight) = camera.project(lens, point).expect("point should be in view");
+            assert_eq!((px, py), (x, y));
+            assert!(weight > 0.0);
+        }
+    }
+}
@@ -40,6 +40,15 @@ impl Colour {
         if w > self.b { w } else { self.b }
     }
 
+    pub fn mean(&self) -> f64 {
+        (self.r + self.g + self.b) / 3.0
+    }
+
+    // Perceived brightness, for linear sRGB.
+    pub fn luminance(&self) -> f64 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
     pub fn min(&self) -> f64 {
         let w = if self.r < self.g { self.r } else { self.g };
         if w < self.b { w } else { self.b }
@@ -86,6 +95,18 @@ impl ops::Add<Colour> for Colour {
     }
 }
 
+impl ops::Sub<Colour> for Colour {
+    type Output = Colour;
+
+    fn sub(self, other: Colour) -> Colour {
+        Colour {
+            r: self.r - other.r,
+            g: self.g - other.g,
+            b: self.b - other.b,
+        }
+    }
+}
+
 impl ops::Mul<Colour> for Colour {
     type Output = Colour;
 
@@ -17,7 +17,7 @@ pub fn cosine_sample_hemisphere() -> Vector3 {
 
     // y is up.
     let x = r * theta.cos();
-    let y = 1.0 - u;
+    let y = (1.0 - u).sqrt();
     let z = r * theta.sin();
 
     Vector3::new(x, y, z)
@@ -61,6 +61,9 @@ pub struct Collision {
     pub location: Vector3,
     pub normal: Vector3,
     pub metadata: CollisionMetadata,
+    // True if the ray hit the outside of the surface.
+    // The normal always faces back towards the ray, so this is the only way to tell.
+    pub front_face: bool,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -69,6 +72,7 @@ pub enum CollisionMetadata {
     Mesh(usize, f64, f64, f64),
 }
 
+#[derive(Clone, Copy, Debug)]
 pub struct AABB {
     pub min: Vector3,
     pub max: Vector3,
@@ -125,6 +129,9 @@ impl Mesh {
 pub enum Primitive {
     Sphere(SpherePrimitive),
     Triangle(TrianglePrimitive),
+    Box(BoxPrimitive),
+    Rectangle(RectanglePrimitive),
+    Disk(DiskPrimitive),
 }
 
 impl Primitive {
@@ -136,39 +143,91 @@ impl Primitive {
         Primitive::Triangle(TrianglePrimitive{ index, vertices, surface_normal })
     }
 
+    pub fn aabb_box(aabb: AABB) -> Primitive {
+        Primitive::Box(BoxPrimitive{ min: aabb.min, max: aabb.max })
+    }
+
+    // The front is the side `edge_u` x `edge_v` points to.
+    pub fn rectangle(corner: Vector3, edge_u: Vector3, edge_v: Vector3) -> Primitive {
+        Primitive::Rectangle(RectanglePrimitive::new(corner, edge_u, edge_v))
+    }
+
+    pub fn disk(center: Vector3, normal: Vector3, radius: f64) -> Primitive {
+        Primitive::Disk(DiskPrimitive{ center, normal: normal.normed(), radius })
+    }
+
     pub fn transform(&self, translation: Vector3, rotation: Matrix3, scale: f64) -> Primitive {
         match self {
             Primitive::Sphere(sphere) => Primitive::Sphere(sphere.transform(translation, rotation, scale)),
             Primitive::Triangle(triangle) => Primitive::Triangle(triangle.transform(translation, rotation, scale)),
+            Primitive::Box(b) => Primitive::Box(b.transform(translation, rotation, scale)),
+            Primitive::Rectangle(rect) => Primitive::Rectangle(rect.transform(translation, rotation, scale)),
+            Primitive::Disk(disk) => Primitive::Disk(disk.transform(translation, rotation, scale)),
         }
     }
 
-    pub fn sample(&self, from: Vector3) -> (Vector3, f64) {
+    // Returns (direction from the sampled point to `from`, distance, inv_pdf)
+    pub fn sample(&self, from: Vector3) -> (Vector3, f64, f64) {
+        match self {
+            Primitive::Sphere(sphere) => sphere.sample(from),
+            Primitive::Triangle(triangle) => {
+                let point = triangle.random_point();
+                area_sample(from, point, triangle.surface_normal, self.area())
+            },
+            Primitive::Box(_) => panic!("random_point() not supported on Box Primitive."),
+            Primitive::Rectangle(rect) => rect.sample(from),
+            Primitive::Disk(disk) => area_sample(from, disk.random_point(), disk.normal, self.area()),
+        }
+    }
+
+    // Picks a point uniformly over the surface.  Returns the point and the normal there, which
+    // points outwards for spheres.
+    pub fn sample_surface(&self) -> (Vector3, Vector3) {
         match self {
             Primitive::Sphere(sphere) => {
                 let mut rng = rand::thread_rng();
-                let u: f64 = rng.gen();
-                let v: f64 = rng.gen();
-                let theta = 2.0 * PI * u;
-                let phi = (2.0 * v - 1.0).acos();
-
-                let n = Vector3::new(
-                    phi.sin() * theta.cos(),
-                    phi.sin() * theta.sin(),
-                    phi.cos(),
-                );
-
-                let point = sphere.center + n * sphere.radius;
-                let out_vec = from - point;
-                let out_dir = out_vec.normed();
-                let distance_sq = out_vec.magnitude();
-
-                let area = 4.0 * PI * sphere.radius * sphere.radius;
-                let inv_pdf = area * n.dot(out_dir) / distance_sq;
-
-                (out_dir, f64::max(0.0, inv_pdf))
+                let y = 1.0 - 2.0 * rng.gen::<f64>();
+                let r = f64::max(0.0, 1.0 - y * y).sqrt();
+                let phi = 2.0 * PI * rng.gen::<f64>();
+                let normal = Vector3::new(r * phi.cos(), y, r * phi.sin());
+                (sphere.center + normal * sphere.radius, normal)
+            },
+            Primitive::Triangle(triangle) => (triangle.random_point(), triangle.surface_normal),
+            Primitive::Box(_) => panic!("random_point() not supported on Box Primitive."),
+            Primitive::Rectangle(rect) => {
+                let mut rng = rand::thread_rng();
+                (rect.corner + rect.edge_u * rng.gen::<f64>() + rect.edge_v * rng.gen::<f64>(), rect.normal)
+            },
+            Primitive::Disk(disk) => (disk.random_point(), disk.normal),
+        }
+    }
+
+    pub fn area(&self) -> f64 {
+        match self {
+            Primitive::Sphere(sphere) => 4.0 * PI * sphere.radius * sphere.radius,
+            Primitive::Triangle(triangle) => {
+                let [a, b, c] = triangle.vertices;
+                (b - a).cross(c - a).magnitude().sqrt() / 2.0
+            },
+            Primitive::Box(b) => {
+                let size = b.max - b.min;
+                2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+            },
+            Primitive::Rectangle(rect) => rect.edge_u.cross(rect.edge_v).magnitude().sqrt(),
+            Primitive::Disk(disk) => PI * disk.radius * disk.radius,
+        }
+    }
+
+    // Solid angle pdf of `sample` picking the given point, as seen from `from`.
+    pub fn pdf(&self, from: Vector3, point: Vector3, normal: Vector3) -> f64 {
+        match self {
+            Primitive::Sphere(sphere) => sphere.pdf(from),
+            Primitive::Triangle(_) | Primitive::Disk(_) => area_pdf(from, point, normal, self.area()),
+            Primitive::Box(_) => panic!("pdf() not supported on Box Primitive."),
+            Primitive::Rectangle(rect) => match rect.spherical(from) {
+                Some(spherical) => 1.0 / spherical.solid_angle,
+                None => area_pdf(from, point, normal, self.area()),
             },
-            Primitive::Triangle(_) => panic!("random_point() not supported on Triangle Primitive."),
         }
     }
 }
@@ -178,6 +237,9 @@ impl BoundedVolume for Primitive {
         match self {
             Primitive::Sphere(sphere) => sphere.aabb(),
             Primitive::Triangle(triangle) => triangle.aabb(),
+            Primitive::Box(b) => b.aabb(),
+            Primitive::Rectangle(rect) => rect.aabb(),
+            Primitive::Disk(disk) => disk.aabb(),
         }
     }
 
@@ -185,6 +247,9 @@ impl BoundedVolume for Primitive {
         match self {
             Primitive::Sphere(sphere) => sphere.intersect(ray),
             Primitive::Triangle(triangle) => triangle.intersect(ray),
+            Primitive::Box(b) => b.intersect(ray),
+            Primitive::Rectangle(rect) => rect.intersect(ray),
+            Primitive::Disk(disk) => disk.intersect(ray),
         }
     }
 }
@@ -202,6 +267,66 @@ impl SpherePrimitive {
             radius: self.radius * scale,
         }
     }
+
+    // Sin squared of the half angle of the cone the sphere covers, seen from `from`.
+    // Inside the sphere it covers everything.
+    fn sin2_max(&self, from: Vector3) -> Option<f64> {
+        let distance_sq = (self.center - from).magnitude();
+        let radius_sq = self.radius * self.radius;
+        if distance_sq <= radius_sq {
+            None
+        } else {
+            Some(radius_sq / distance_sq)
+        }
+    }
+
+    // 1 - cos, without losing precision for small distant spheres.
+    fn one_minus_cos(sin2: f64) -> f64 {
+        sin2 / (1.0 + (1.0 - sin2).sqrt())
+    }
+
+    // Picks a direction uniformly over the cone of directions which hit the sphere.
+    // Returns (direction from the sampled point to `from`, distance, inv_pdf)
+    pub fn sample(&self, from: Vector3) -> (Vector3, f64, f64) {
+        let mut rng = rand::thread_rng();
+        let u: f64 = rng.gen();
+        let v: f64 = rng.gen();
+        let phi = 2.0 * PI * v;
+
+        let to_center = self.center - from;
+        let (cos_theta, inv_pdf) = match self.sin2_max(from) {
+            Some(sin2_max) => {
+                let one_minus_cos_max = SpherePrimitive::one_minus_cos(sin2_max);
+                (1.0 - u * one_minus_cos_max, 2.0 * PI * one_minus_cos_max)
+            },
+            None => (1.0 - 2.0 * u, 4.0 * PI),
+        };
+        let sin_theta = f64::max(0.0, 1.0 - cos_theta * cos_theta).sqrt();
+
+        // y is up.
+        let local = Vector3::new(sin_theta * phi.cos(), cos_theta, sin_theta * phi.sin());
+        let (i, j, k) = to_center.normed().form_basis();
+        let direction = switch_basis(local, i, j, k).normed();
+
+        // Nearest point on the sphere in that direction, or the only one from inside.
+        let along = direction.dot(to_center);
+        let discriminant = f64::max(0.0, along * along - to_center.magnitude() + self.radius * self.radius);
+        let distance = if self.sin2_max(from).is_some() {
+            along - discriminant.sqrt()
+        } else {
+            along + discriminant.sqrt()
+        };
+
+        (direction * -1, distance, inv_pdf)
+    }
+
+    // Solid angle pdf of `sample`, for any direction which hits the sphere.
+    pub fn pdf(&self, from: Vector3) -> f64 {
+        match self.sin2_max(from) {
+            Some(sin2_max) => 1.0 / (2.0 * PI * SpherePrimitive::one_minus_cos(sin2_max)),
+            None => 1.0 / (4.0 * PI),
+        }
+    }
 }
 
 impl BoundedVolume for SpherePrimitive {
@@ -229,9 +354,11 @@ impl BoundedVolume for SpherePrimitive {
 
         let distance = if d2 > 0.0 { d2 } else { d1 };
         let location = o + (l * distance);
-        let normal = (location - c).normed();
+        let outward_normal = (location - c).normed();
+        let front_face = outward_normal.dot(l) < 0.0;
+        let normal = if front_face { outward_normal } else { outward_normal * -1 };
         let metadata = CollisionMetadata::None;
-        Some(Collision{ distance, location, normal, metadata })
+        Some(Collision{ distance, location, normal, metadata, front_face })
     }
 
     fn aabb(&self) -> AABB {
@@ -261,6 +388,18 @@ impl TrianglePrimitive {
     }
 }
 
+impl TrianglePrimitive {
+    // Uniformly over the area.
+    pub fn random_point(&self) -> Vector3 {
+        let mut rng = rand::thread_rng();
+        let su = rng.gen::<f64>().sqrt();
+        let b0 = 1.0 - su;
+        let b1 = rng.gen::<f64>() * su;
+        let [a, b, c] = self.vertices;
+        a * b0 + b * b1 + c * (1.0 - b0 - b1)
+    }
+}
+
 impl BoundedVolume for TrianglePrimitive {
     fn intersect(&self, ray: Ray) -> Option<Collision> {
         let a = self.vertices[0];
@@ -296,9 +435,10 @@ impl BoundedVolume for TrianglePrimitive {
             None
         } else {
             // Flip the normal if we're hitting the triangle from the back;
-            let back_side_multiplier = if cos_theta > 0.0 { -1.0 } else { 1.0 };
+            let front_face = cos_theta <= 0.0;
+            let back_side_multiplier = if front_face { 1.0 } else { -1.0 };
             let metadata = CollisionMetadata::Mesh(self.index, bx, by, bz);
-            Some(Collision{ distance: t, location: p, normal: n * back_side_multiplier, metadata })
+            Some(Collision{ distance: t, location: p, normal: n * back_side_multiplier, metadata, front_face })
         }
     }
 
@@ -320,6 +460,301 @@ impl BoundedVolume for TrianglePrimitive {
     }
 }
 
+// Axis-aligned box.
+#[derive(Clone, Copy, Debug)]
+pub struct BoxPrimitive {
+    pub min: Vector3,
+    pub max: Vector3,
+}
+
+impl BoxPrimitive {
+    // Boxes are always axis-aligned, so can only be moved and scaled.
+    pub fn transform(&self, translation: Vector3, rotation: Matrix3, scale: f64) -> BoxPrimitive {
+        if rotation != Matrix3::identity() {
+            panic!("Boxes are axis-aligned and can't be rotated");
+        }
+
+        BoxPrimitive {
+            min: self.min * scale + translation,
+            max: self.max * scale + translation,
+        }
+    }
+}
+
+impl BoundedVolume for BoxPrimitive {
+    fn intersect(&self, ray: Ray) -> Option<Collision> {
+        // Slab test, same as for the BVH nodes.
+        let t0s = (self.min - ray.origin) * ray.inv_direction;
+        let t1s = (self.max - ray.origin) * ray.inv_direction;
+        let tmin = Vector3::componentwise_min(t0s, t1s).max();
+        let tmax = Vector3::componentwise_max(t0s, t1s).min();
+
+        if tmax < tmin || tmax < 0.0 {
+            return None;
+        }
+
+        // If the near side is behind us, we're inside the box.
+        let (distance, front_face) = if tmin > 0.0 { (tmin, true) } else { (tmax, false) };
+        let location = ray.origin + ray.direction * distance;
+
+        // The face we hit is the one we're furthest along towards, relative to the box's size.
+        let center = (self.min + self.max) * 0.5;
+        let half_size = (self.max - self.min) * 0.5;
+        let rel = (location - center) * half_size.invert();
+        let outward_normal = if rel.x.abs() >= rel.y.abs() && rel.x.abs() >= rel.z.abs() {
+            Vector3::new(rel.x.signum(), 0.0, 0.0)
+        } else if rel.y.abs() >= rel.z.abs() {
+            Vector3::new(0.0, rel.y.signum(), 0.0)
+        } else {
+            Vector3::new(0.0, 0.0, rel.z.signum())
+        };
+        let normal = if front_face { outward_normal } else { outward_normal * -1 };
+
+        let metadata = CollisionMetadata::None;
+        Some(Collision{ distance, location, normal, metadata, front_face })
+    }
+
+    fn aabb(&self) -> AABB {
+        AABB::new(self.min, self.max)
+    }
+}
+
+// Turns a point picked uniformly over a surface of the given area into a sample as seen from
+// `from`, as for `Primitive::sample`.  Works from either side.
+fn area_sample(from: Vector3, point: Vector3, normal: Vector3, area: f64) -> (Vector3, f64, f64) {
+    let out_vec = from - point;
+    let distance_sq = out_vec.magnitude();
+    let out_dir = out_vec.normed();
+    let inv_pdf = area * normal.dot(out_dir).abs() / distance_sq;
+    (out_dir, distance_sq.sqrt(), inv_pdf)
+}
+
+fn area_pdf(from: Vector3, point: Vector3, normal: Vector3, area: f64) -> f64 {
+    let out_vec = from - point;
+    let cos_theta = normal.dot(out_vec.normed()).abs();
+    if cos_theta <= 0.0 {
+        0.0
+    } else {
+        out_vec.magnitude() / (area * cos_theta)
+    }
+}
+
+// Flat parallelogram with perpendicular sides, i.e. a rectangle.
+#[derive(Clone, Copy, Debug)]
+pub struct RectanglePrimitive {
+    pub corner: Vector3,
+    pub edge_u: Vector3,
+    pub edge_v: Vector3,
+    pub normal: Vector3,
+}
+
+// Below this solid angle, sampling the spherical rectangle isn't accurate enough, and sampling
+// the area is just as good anyway.
+const MIN_SPHERICAL_SOLID_ANGLE: f64 = 1e-3;
+
+// A rectangle as seen from a point, for picking directions uniformly over the solid angle it
+// covers.
+// See Urena, Fajardo and King, "An Area-Preserving Parametrization for Spherical Rectangles".
+struct SphericalRectangle {
+    from: Vector3,
+    // Local frame, with the rectangle in the plane z = z0 < 0.
+    x: Vector3,
+    y: Vector3,
+    z: Vector3,
+    x0: f64,
+    x1: f64,
+    y0: f64,
+    y1: f64,
+    z0: f64,
+    b0: f64,
+    b1: f64,
+    k: f64,
+    solid_angle: f64,
+}
+
+impl SphericalRectangle {
+    fn sample(&self, u: f64, v: f64) -> Vector3 {
+        let au = u * self.solid_angle + self.k;
+        let fu = (au.cos() * self.b0 - self.b1) / au.sin();
+        let cu = (1.0 / (fu * fu + self.b0 * self.b0).sqrt()).copysign(fu).clamp(-1.0, 1.0);
+        let xu = (-(cu * self.z0) / f64::max(1e-12, 1.0 - cu * cu).sqrt()).clamp(self.x0, self.x1);
+
+        let d = (xu * xu + self.z0 * self.z0).sqrt();
+        let h0 = self.y0 / (d * d + self.y0 * self.y0).sqrt();
+        let h1 = self.y1 / (d * d + self.y1 * self.y1).sqrt();
+        let hv = h0 + v * (h1 - h0);
+        let yv = if hv * hv < 1.0 - 1e-9 { hv * d / (1.0 - hv * hv).sqrt() } else { self.y1 };
+
+        self.from + self.x * xu + self.y * yv + self.z * self.z0
+    }
+}
+
+impl RectanglePrimitive {
+    pub fn new(corner: Vector3, edge_u: Vector3, edge_v: Vector3) -> RectanglePrimitive {
+        // Make sure the sides are perpendicular.
+        let edge_v = edge_v - edge_u * (edge_u.dot(edge_v) / edge_u.magnitude());
+        RectanglePrimitive { corner, edge_u, edge_v, normal: edge_u.cross(edge_v).normed() }
+    }
+
+    pub fn transform(&self, translation: Vector3, rotation: Matrix3, scale: f64) -> RectanglePrimitive {
+        RectanglePrimitive::new(
+            rotation * self.corner * scale + translation,
+            rotation * self.edge_u * scale,
+            rotation * self.edge_v * scale,
+        )
+    }
+
+    // None if the rectangle is too small (or edge on) to sample by solid angle.
+    fn spherical(&self, from: Vector3) -> Option<SphericalRectangle> {
+        let len_u = self.edge_u.magnitude().sqrt();
+        let len_v = self.edge_v.magnitude().sqrt();
+        let x = self.edge_u / len_u;
+        let y = self.edge_v / len_v;
+        let mut z = self.normal;
+
+        let d = self.corner - from;
+        let x0 = d.dot(x);
+        let y0 = d.dot(y);
+        let mut z0 = d.dot(z);
+        if z0.abs() < 1e-9 {
+            return None;
+        }
+        if z0 > 0.0 {
+            z0 *= -1.0;
+            z = z * -1;
+        }
+        let x1 = x0 + len_u;
+        let y1 = y0 + len_v;
+
+        // Normals of the planes through `from` and each edge, and the angles between them.
+        let v00 = Vector3::new(x0, y0, z0);
+        let v01 = Vector3::new(x0, y1, z0);
+        let v10 = Vector3::new(x1, y0, z0);
+        let v11 = Vector3::new(x1, y1, z0);
+        let n0 = v00.cross(v10).normed();
+        let n1 = v10.cross(v11).normed();
+        let n2 = v11.cross(v01).normed();
+        let n3 = v01.cross(v00).normed();
+        let angle = |a: Vector3, b: Vector3| (-a.dot(b)).clamp(-1.0, 1.0).acos();
+        let g0 = angle(n0, n1);
+        let g1 = angle(n1, n2);
+        let g2 = angle(n2, n3);
+        let g3 = angle(n3, n0);
+
+        let k = 2.0 * PI - g2 - g3;
+        let solid_angle = g0 + g1 - k;
+        if solid_angle.is_nan() || solid_angle < MIN_SPHERICAL_SOLID_ANGLE {
+            return None;
+        }
+
+        Some(SphericalRectangle { from, x, y, z, x0, x1, y0, y1, z0, b0: n0.z, b1: n2.z, k, solid_angle })
+    }
+
+    // Uniformly over the solid angle if it's big enough, otherwise over the area.
+    pub fn sample(&self, from: Vector3) -> (Vector3, f64, f64) {
+        let mut rng = rand::thread_rng();
+        let (u, v) = (rng.gen::<f64>(), rng.gen::<f64>());
+        match self.spherical(from) {
+            Some(spherical) => {
+                let out_vec = from - spherical.sample(u, v);
+                (out_vec.normed(), out_vec.magnitude().sqrt(), spherical.solid_angle)
+            },
+            None => {
+                let point = self.corner + self.edge_u * u + self.edge_v * v;
+                area_sample(from, point, self.normal, self.edge_u.cross(self.edge_v).magnitude().sqrt())
+            },
+        }
+    }
+}
+
+impl BoundedVolume for RectanglePrimitive {
+    fn intersect(&self, ray: Ray) -> Option<Collision> {
+        let cos_theta = self.normal.dot(ray.direction);
+        let distance = self.normal.dot(self.corner - ray.origin) / cos_theta;
+        if !distance.is_finite() || distance < 0.0 {
+            return None;
+        }
+
+        let location = ray.origin + ray.direction * distance;
+        let rel = location - self.corner;
+        let u = rel.dot(self.edge_u) / self.edge_u.magnitude();
+        let v = rel.dot(self.edge_v) / self.edge_v.magnitude();
+        if !(0.0 ..= 1.0).contains(&u) || !(0.0 ..= 1.0).contains(&v) {
+            return None;
+        }
+
+        let front_face = cos_theta < 0.0;
+        let normal = if front_face { self.normal } else { self.normal * -1 };
+        let metadata = CollisionMetadata::None;
+        Some(Collision{ distance, location, normal, metadata, front_face })
+    }
+
+    fn aabb(&self) -> AABB {
+        let corners = [
+            self.corner,
+            self.corner + self.edge_u,
+            self.corner + self.edge_v,
+            self.corner + self.edge_u + self.edge_v,
+        ];
+        let min = corners.iter().fold(corners[0], |acc, c| Vector3::componentwise_min(acc, *c));
+        let max = corners.iter().fold(corners[0], |acc, c| Vector3::componentwise_max(acc, *c));
+        AABB::new(min, max)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DiskPrimitive {
+    pub center: Vector3,
+    pub normal: Vector3,
+    pub radius: f64,
+}
+
+impl DiskPrimitive {
+    pub fn transform(&self, translation: Vector3, rotation: Matrix3, scale: f64) -> DiskPrimitive {
+        DiskPrimitive {
+            center: rotation * self.center * scale + translation,
+            normal: rotation * self.normal,
+            radius: self.radius * scale,
+        }
+    }
+
+    // Uniformly over the area.
+    pub fn random_point(&self) -> Vector3 {
+        let mut rng = rand::thread_rng();
+        let r = self.radius * rng.gen::<f64>().sqrt();
+        let phi = 2.0 * PI * rng.gen::<f64>();
+        let (i, _, k) = self.normal.form_basis();
+        self.center + i * (r * phi.cos()) + k * (r * phi.sin())
+    }
+}
+
+impl BoundedVolume for DiskPrimitive {
+    fn intersect(&self, ray: Ray) -> Option<Collision> {
+        let cos_theta = self.normal.dot(ray.direction);
+        let distance = self.normal.dot(self.center - ray.origin) / cos_theta;
+        if !distance.is_finite() || distance < 0.0 {
+            return None;
+        }
+
+        let location = ray.origin + ray.direction * distance;
+        if (location - self.center).magnitude() > self.radius * self.radius {
+            return None;
+        }
+
+        let front_face = cos_theta < 0.0;
+        let normal = if front_face { self.normal } else { self.normal * -1 };
+        let metadata = CollisionMetadata::None;
+        Some(Collision{ distance, location, normal, metadata, front_face })
+    }
+
+    fn aabb(&self) -> AABB {
+        let n = self.normal;
+        let extent = |c: f64| self.radius * f64::max(0.0, 1.0 - c * c).sqrt();
+        let half_size = Vector3::new(extent(n.x), extent(n.y), extent(n.z));
+        AABB::new(self.center - half_size, self.center + half_size)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::geom::*;
@@ -331,6 +766,114 @@ mod test {
         }
     }
 
+    #[test]
+    fn sphere_sampling() {
+        let sphere = SpherePrimitive{ center: Vector3::new(0.0, 0.0, 5.0), radius: 1.0 };
+        let on_surface = |from: Vector3, (dir, distance, _): (Vector3, f64, f64)| {
+            let point = from - dir * distance;
+            ((point - sphere.center).magnitude().sqrt() - 1.0).abs() < 1e-9
+        };
+
+        // From outside, only the near side gets picked, and the estimate of the cosine weighted
+        // solid angle matches pi * sin^2.
+        let from = Vector3::zero();
+        let normal = Vector3::new(0.0, 0.0, 1.0);
+        let n = 10000;
+        let mut total = 0.0;
+        for _ in 0 .. n {
+            let sample = sphere.sample(from);
+            let (dir, distance, inv_pdf) = sample;
+            assert!(on_surface(from, sample));
+            let point = from - dir * distance;
+            assert!((point - sphere.center).dot(dir) >= -1e-9);
+            assert!((inv_pdf * sphere.pdf(from) - 1.0).abs() < 1e-9);
+            total += normal.dot(dir * -1) * inv_pdf / n as f64;
+        }
+        let expected = PI * 1.0 / 25.0;
+        assert!((total - expected).abs() < 1e-3, "{} vs {}", total, expected);
+
+        // From inside, everywhere is covered.
+        let from = Vector3::new(0.2, 0.3, 5.1);
+        for _ in 0 .. 100 {
+            let sample = sphere.sample(from);
+            assert!(on_surface(from, sample));
+            assert!(sample.1 > 0.0);
+            assert_eq!(sample.2, 4.0 * PI);
+        }
+    }
+
+    #[test]
+    fn flat_light_sampling() {
+        // A 2x1 rectangle facing down, above the origin.
+        let rect = Primitive::rectangle(Vector3::new(-1.0, 1.0, -0.5), Vector3::new(2.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 1.0));
+        let from = Vector3::new(0.3, 0.0, 0.1);
+        let ray = Ray::new(from, Vector3::new(0.0, 1.0, 0.0));
+        let col = rect.intersect(ray).unwrap();
+        assert!(col.front_face);
+        assert_eq!(col.normal, Vector3::new(0.0, -1.0, 0.0));
+        assert!(rect.intersect(Ray::new(from, Vector3::new(1.0, 0.2, 0.0).normed())).is_none());
+
+        // Solid angle sampling lands on the rectangle, with a pdf that matches, and agrees with
+        // the solid angle estimated by sampling the area.
+        let n = 10000;
+        let mut estimate = 0.0;
+        let mut inv_pdf = 0.0;
+        for _ in 0 .. n {
+            let (dir, distance, sample_inv_pdf) = rect.sample(from);
+            let col = rect.intersect(Ray::new(from, dir * -1)).unwrap();
+            assert!((col.distance - distance).abs() < 1e-6);
+            assert!((sample_inv_pdf * rect.pdf(from, col.location, col.normal) - 1.0).abs() < 1e-9);
+            inv_pdf = sample_inv_pdf;
+
+            if let Primitive::Rectangle(r) = rect {
+                let point = r.corner + r.edge_u * rand::random::<f64>() + r.edge_v * rand::random::<f64>();
+                estimate += area_sample(from, point, r.normal, 2.0).2 / n as f64;
+            }
+        }
+        assert!((estimate - inv_pdf).abs() < 0.02 * inv_pdf, "{} vs {}", estimate, inv_pdf);
+
+        // Disks and triangles are hit the same way, from either side.
+        let disk = Primitive::disk(Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, 1.0, 0.0), 0.5);
+        let col = disk.intersect(Ray::new(Vector3::zero(), Vector3::new(0.0, 1.0, 0.0))).unwrap();
+        assert!(!col.front_face);
+        assert!(disk.intersect(Ray::new(Vector3::zero(), Vector3::new(0.6, 1.0, 0.0).normed())).is_none());
+        let (dir, distance, _) = disk.sample(Vector3::zero());
+        assert!(disk.intersect(Ray::new(Vector3::zero(), dir * -1)).map_or(false, |c| (c.distance - distance).abs() < 1e-6));
+        assert!((disk.area() - PI * 0.25).abs() < 1e-12);
+    }
+
+    #[test]
+    fn box_intersection() {
+        let b = Primitive::aabb_box(AABB::new(Vector3::new(-1.0, -1.0, -1.0), Vector3::new(1.0, 1.0, 1.0)));
+
+        // From outside.
+        let col = b.intersect(Ray::new(Vector3::new(0.0, 0.0, -5.0), Vector3::new(0.0, 0.0, 1.0))).unwrap();
+        assert_eq!(col.distance, 4.0);
+        assert_eq!(col.normal, Vector3::new(0.0, 0.0, -1.0));
+        assert!(col.front_face);
+
+        // From inside, normal should still face back along the ray.
+        let col = b.intersect(Ray::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 1.0, 0.0))).unwrap();
+        assert_eq!(col.distance, 1.0);
+        assert_eq!(col.normal, Vector3::new(0.0, -1.0, 0.0));
+        assert!(!col.front_face);
+
+        // Miss.
+        assert!(b.intersect(Ray::new(Vector3::new(0.0, 3.0, -5.0), Vector3::new(0.0, 0.0, 1.0))).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "can't be rotated")]
+    fn box_rotation() {
+        let b = Primitive::aabb_box(AABB::new(Vector3::new(-1.0, -1.0, -1.0), Vector3::new(1.0, 1.0, 1.0)));
+
+        // Moving and scaling is fine.
+        let moved = b.transform(Vector3::new(1.0, 0.0, 0.0), Matrix3::rotation(0.0, 0.0, 0.0), 2.0);
+        assert_eq!(moved.aabb().max, Vector3::new(3.0, 2.0, 2.0));
+
+        b.transform(Vector3::zero(), Matrix3::rotation(0.5, 0.0, 0.0), 1.0);
+    }
+
     mod switch_basis {
         macro_rules! test_switch_basis{
             ($name:ident: ($ix:expr, $iy:expr, $iz:expr), ($nx:expr, $ny:expr, $nz:expr) => ($ox:expr, $oy:expr, $oz:expr)) => {
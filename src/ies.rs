@@ -0,0 +1,167 @@
+use std::fs;
+
+// Photometric data for a real light fitting, in the IES LM-63 format manufacturers publish.
+// Only type C photometry is supported, which is what almost all interior fittings use.  The
+// vertical angle goes from 0 straight down (the nadir) to 180 straight up, and the horizontal
+// angle goes round the fitting.
+#[derive(Clone, Debug)]
+pub struct IesProfile {
+    // In degrees, ascending.
+    vertical: Vec<f64>,
+    horizontal: Vec<f64>,
+    // For each horizontal angle, the intensity at each vertical angle, relative to the peak.
+    values: Vec<Vec<f64>>,
+    // Brightest intensity, in candela.
+    peak_candela: f64,
+}
+
+pub fn load_ies_file(filename: &str) -> IesProfile {
+    println!("Loading IES profile: {}", filename);
+    let text = fs::read_to_string(filename).unwrap();
+    IesProfile::parse(&text)
+}
+
+impl IesProfile {
+    pub fn parse(text: &str) -> IesProfile {
+        // Keywords come first, up to the TILT line.  Everything after that is just numbers.
+        let mut lines = text.lines();
+        let tilt = lines.by_ref()
+            .find(|l| l.trim_start().starts_with("TILT="))
+            .expect("IES file is missing its TILT line");
+
+        let mut numbers = lines
+            .flat_map(|l| l.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|t| !t.is_empty())
+            .map(|t| t.parse::<f64>().expect("IES file has a bad number"));
+        let mut next = || numbers.next().expect("IES file ended early");
+
+        match tilt.trim().trim_start_matches("TILT=") {
+            "NONE" => (),
+            "INCLUDE" => {
+                // Lamp geometry, then how the output changes with tilt, which we ignore.
+                next();
+                let pairs = next() as usize;
+                for _ in 0 .. 2 * pairs {
+                    next();
+                }
+            },
+            other => println!("[WARN] Ignoring tilt data in IES file: {}", other),
+        }
+
+        let _lamps = next();
+        let _lumens_per_lamp = next();
+        let multiplier = next();
+        let num_vertical = next() as usize;
+        let num_horizontal = next() as usize;
+        let photometric_type = next();
+        let _units = next();
+        let _size = (next(), next(), next());
+        let ballast_factor = next();
+        let _future_use = next();
+        let _watts = next();
+
+        if photometric_type != 1.0 {
+            println!("[WARN] Only type C IES photometry is supported, treating it as type C");
+        }
+
+        let vertical: Vec<f64> = (0 .. num_vertical).map(|_| next()).collect();
+        let horizontal: Vec<f64> = (0 .. num_horizontal).map(|_| next()).collect();
+        let mut values: Vec<Vec<f64>> = (0 .. num_horizontal)
+            .map(|_| (0 .. num_vertical).map(|_| next()).collect())
+            .collect();
+
+        let peak = values.iter().flatten().fold(0.0, |acc: f64, v| acc.max(*v));
+        if peak <= 0.0 {
+            panic!("IES file doesn't give off any light");
+        }
+        for v in values.iter_mut().flatten() {
+            *v /= peak;
+        }
+
+        IesProfile { vertical, horizontal, values, peak_candela: peak * multiplier * ballast_factor }
+    }
+
+    pub fn peak_candela(&self) -> f64 {
+        self.peak_candela
+    }
+
+    // Intensity relative to the peak, at the given angles in degrees.
+    pub fn evaluate(&self, vertical: f64, horizontal: f64) -> f64 {
+        let (v0, v1, tv) = match locate(&self.vertical, vertical) {
+            Some(found) => found,
+            None => return 0.0,
+        };
+
+        // Fill in the rest of the way round from the symmetry of the data.
+        let last = *self.horizontal.last().expect("IES file has no horizontal angles");
+        let horizontal = horizontal.rem_euclid(360.0);
+        let horizontal = if self.horizontal.len() == 1 {
+            last
+        } else if last <= 90.0 {
+            let h = horizontal % 180.0;
+            if h > 90.0 { 180.0 - h } else { h }
+        } else if last <= 180.0 {
+            if horizontal > 180.0 { 360.0 - horizontal } else { horizontal }
+        } else {
+            horizontal
+        };
+        let (h0, h1, th) = locate(&self.horizontal, horizontal)
+            .unwrap_or_else(|| if horizontal < self.horizontal[0] { (0, 0, 0.0) } else { (self.horizontal.len() - 1, self.horizontal.len() - 1, 0.0) });
+
+        let at = |h: usize| self.values[h][v0] * (1.0 - tv) + self.values[h][v1] * tv;
+        at(h0) * (1.0 - th) + at(h1) * th
+    }
+}
+
+// Where `x` falls in the ascending `angles`, as the indices either side and how far between
+// them it is.
+fn locate(angles: &[f64], x: f64) -> Option<(usize, usize, f64)> {
+    let first = *angles.first()?;
+    let last = *angles.last()?;
+    if x < first || x > last {
+        return None;
+    }
+    if angles.len() == 1 {
+        return Some((0, 0, 0.0));
+    }
+
+    let ix = angles.iter().position(|a| *a > x).unwrap_or(angles.len() - 1).max(1) - 1;
+    let span = angles[ix + 1] - angles[ix];
+    let t = if span > 0.0 { (x - angles[ix]) / span } else { 0.0 };
+    Some((ix, ix + 1, t.clamp(0.0, 1.0)))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ies::*;
+
+    const DOWNLIGHT: &str = "IESNA:LM-63-2002
+[MANUFAC] Test
+TILT=NONE
+1 1000 2.0 3 2 1 2 0.1 0.1 0.0
+1.0 1.0 10.0
+0 45 90
+0 90
+1000 500 0
+800, 400, 0
+";
+
+    #[test]
+    fn test_ies_profile() {
+        let profile = IesProfile::parse(DOWNLIGHT);
+        assert_eq!(profile.peak_candela(), 2000.0);
+
+        // Straight down is brightest, and nothing goes above the horizon.
+        assert_eq!(profile.evaluate(0.0, 0.0), 1.0);
+        assert_eq!(profile.evaluate(120.0, 0.0), 0.0);
+
+        // Interpolates between angles.
+        assert!((profile.evaluate(22.5, 0.0) - 0.75).abs() < 1e-9);
+        assert!((profile.evaluate(0.0, 45.0) - 0.9).abs() < 1e-9);
+
+        // Quadrant symmetry fills in the other horizontal angles.
+        assert!((profile.evaluate(45.0, 180.0) - 0.5).abs() < 1e-9);
+        assert!((profile.evaluate(45.0, 270.0) - 0.4).abs() < 1e-9);
+        assert!((profile.evaluate(45.0, 135.0) - profile.evaluate(45.0, 45.0)).abs() < 1e-9);
+    }
+}
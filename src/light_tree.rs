@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+use rand;
+use rand::Rng;
+
+use crate::geom::AABB;
+use crate::vector::Vector3;
+
+// A hierarchy of lights for picking one in proportion to roughly how much it could light a
+// point, so scenes with lots of lights only need to sample the ones that matter.
+// Based on Conty Estevez and Kulla, "Importance Sampling of Many Lights with Adaptive Tree
+// Splitting".
+
+// Where some lights are, how much light they give off and which way.
+// Every light faces within `theta_o` of `axis`, and gives off light up to `theta_e` beyond that.
+#[derive(Clone, Copy, Debug)]
+pub struct LightBounds {
+    pub aabb: AABB,
+    pub power: f64,
+    pub axis: Vector3,
+    pub theta_o: f64,
+    pub theta_e: f64,
+}
+
+impl LightBounds {
+    pub fn new(aabb: AABB, power: f64, axis: Vector3, theta_o: f64, theta_e: f64) -> LightBounds {
+        // Anything emitting past 90 degrees is treated as facing more ways instead, which keeps
+        // the cosine in `importance` positive wherever the light reaches.
+        let extra = f64::max(0.0, theta_e - PI / 2.0);
+        LightBounds {
+            aabb,
+            power,
+            axis: axis.normed(),
+            theta_o: f64::min(PI, theta_o + extra),
+            theta_e: theta_e - extra,
+        }
+    }
+
+    pub fn union(&self, other: &LightBounds) -> LightBounds {
+        let min = Vector3::componentwise_min(self.aabb.min, other.aabb.min);
+        let max = Vector3::componentwise_max(self.aabb.max, other.aabb.max);
+        let (axis, theta_o) = union_cones((self.axis, self.theta_o), (other.axis, other.theta_o));
+        LightBounds {
+            aabb: AABB::new(min, max),
+            power: self.power + other.power,
+            axis,
+            theta_o,
+            theta_e: f64::max(self.theta_e, other.theta_e),
+        }
+    }
+
+    // Estimate of how much light could reach `point`.  Only 0 if none of it can.
+    pub fn importance(&self, point: Vector3) -> f64 {
+        let to_point = point - self.aabb.center;
+        let radius_sq = (self.aabb.max - self.aabb.min).magnitude() / 4.0;
+        let distance_sq = f64::max(to_point.magnitude(), radius_sq);
+
+        // Angle the bounding sphere of the lights takes up, seen from the point.
+        let theta_b = if to_point.magnitude() <= radius_sq {
+            PI
+        } else {
+            (radius_sq / to_point.magnitude()).sqrt().asin()
+        };
+
+        // Closest the point could be to the way any of the lights face.
+        let theta_w = self.axis.dot(to_point.normed()).clamp(-1.0, 1.0).acos();
+        let theta = f64::max(0.0, theta_w - self.theta_o - theta_b);
+        if theta >= self.theta_e {
+            return 0.0;
+        }
+
+        self.power * theta.cos() / distance_sq
+    }
+}
+
+// Smallest cone (axis, half angle) containing both cones.
+fn union_cones(a: (Vector3, f64), b: (Vector3, f64)) -> (Vector3, f64) {
+    let (a, b) = if b.1 > a.1 { (b, a) } else { (a, b) };
+    let theta_d = a.0.dot(b.0).clamp(-1.0, 1.0).acos();
+    if f64::min(theta_d + b.1, PI) <= a.1 {
+        return a;
+    }
+
+    let theta_o = (a.1 + theta_d + b.1) / 2.0;
+    if theta_o >= PI {
+        return (a.0, PI);
+    }
+
+    // Rotate the wider cone's axis towards the other one.
+    let rotation_axis = a.0.cross(b.0);
+    if rotation_axis.magnitude() < 1e-12 {
+        return (a.0, PI);
+    }
+    let k = rotation_axis.normed();
+    let theta_r = theta_o - a.1;
+    let axis = a.0 * theta_r.cos() + k.cross(a.0) * theta_r.sin();
+    (axis.normed(), theta_o)
+}
+
+enum LightNode {
+    Leaf(usize, LightBounds),
+    Split(Box<LightNode>, Box<LightNode>, LightBounds),
+}
+
+impl LightNode {
+    fn bounds(&self) -> &LightBounds {
+        match self {
+            LightNode::Leaf(_, bounds) => bounds,
+            LightNode::Split(_, _, bounds) => bounds,
+        }
+    }
+}
+
+pub struct LightTree {
+    root: Option<LightNode>,
+    // For each light, the way down the tree to it.  Bit n is set if it's on the right at depth n.
+    trails: HashMap<usize, u64>,
+}
+
+impl LightTree {
+    // Lights are given as (index, bounds).  Ones which give off no light are left out.
+    pub fn new(lights: Vec<(usize, LightBounds)>) -> LightTree {
+        let lights: Vec<(usize, LightBounds)> = lights.into_iter().filter(|(_, b)| b.power > 0.0).collect();
+        let mut trails = HashMap::new();
+        let root = if lights.is_empty() {
+            None
+        } else {
+            Some(build(lights, 0, 0, &mut trails))
+        };
+        LightTree { root, trails }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
+
+    // Returns (light index, chance of picking it).
+    pub fn sample(&self, point: Vector3) -> Option<(usize, f64)> {
+        let mut rng = rand::thread_rng();
+        let mut node = self.root.as_ref()?;
+        let mut probability = 1.0;
+
+        loop {
+            match node {
+                LightNode::Leaf(ix, _) => return Some((*ix, probability)),
+                LightNode::Split(left, right, _) => {
+                    let (p_left, p_right) = child_probabilities(left, right, point);
+                    if p_left + p_right <= 0.0 {
+                        return None;
+                    }
+
+                    if rng.gen::<f64>() < p_left {
+                        node = left;
+                        probability *= p_left;
+                    } else {
+                        node = right;
+                        probability *= p_right;
+                    }
+                },
+            }
+        }
+    }
+
+    // Chance of `sample` picking the given light.
+    pub fn probability(&self, point: Vector3, light: usize) -> f64 {
+        let (mut node, mut trail) = match (self.root.as_ref(), self.trails.get(&light)) {
+            (Some(root), Some(trail)) => (root, *trail),
+            _ => return 0.0,
+        };
+        let mut probability = 1.0;
+
+        loop {
+            match node {
+                LightNode::Leaf(_, _) => return probability,
+                LightNode::Split(left, right, _) => {
+                    let (p_left, p_right) = child_probabilities(left, right, point);
+                    if trail & 1 == 0 {
+                        node = left;
+                        probability *= p_left;
+                    } else {
+                        node = right;
+                        probability *= p_right;
+                    }
+                    trail >>= 1;
+                },
+            }
+        }
+    }
+}
+
+fn child_probabilities(left: &LightNode, right: &LightNode, point: Vector3) -> (f64, f64) {
+    let left = left.bounds().importance(point);
+    let right = right.bounds().importance(point);
+    let total = left + right;
+    if total <= 0.0 {
+        (0.0, 0.0)
+    } else {
+        (left / total, right / total)
+    }
+}
+
+// Splits the lights in half along the axis where they are most spread out.
+fn build(mut lights: Vec<(usize, LightBounds)>, depth: u32, trail: u64, trails: &mut HashMap<usize, u64>) -> LightNode {
+    if lights.len() == 1 {
+        let (ix, bounds) = lights[0];
+        trails.insert(ix, trail);
+        return LightNode::Leaf(ix, bounds);
+    }
+
+    let centers: Vec<Vector3> = lights.iter().map(|(_, b)| b.aabb.center).collect();
+    let min = centers.iter().fold(centers[0], |acc, c| Vector3::componentwise_min(acc, *c));
+    let max = centers.iter().fold(centers[0], |acc, c| Vector3::componentwise_max(acc, *c));
+    let extent = max - min;
+    let key = |b: &LightBounds| {
+        if extent.x >= extent.y && extent.x >= extent.z {
+            b.aabb.center.x
+        } else if extent.y >= extent.z {
+            b.aabb.center.y
+        } else {
+            b.aabb.center.z
+        }
+    };
+    lights.sort_by(|(_, a), (_, b)| key(a).partial_cmp(&key(b)).unwrap());
+
+    let right = lights.split_off(lights.len() / 2);
+    let left = build(lights, depth + 1, trail, trails);
+    let right = build(right, depth + 1, trail | (1 << depth), trails);
+    let bounds = left.bounds().union(right.bounds());
+    LightNode::Split(Box::new(left), Box::new(right), bounds)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::light_tree::*;
+
+    fn point_light(position: Vector3, power: f64) -> LightBounds {
+        LightBounds::new(AABB::new(position, position), power, Vector3::new(0.0, 1.0, 0.0), PI, PI / 2.0)
+    }
+
+    #[test]
+    fn test_light_tree() {
+        let lights: Vec<(usize, LightBounds)> = (0 .. 100)
+            .map(|ix| (ix, point_light(Vector3::new(ix as f64, 0.0, 0.0), 1.0)))
+            .collect();
+        let tree = LightTree::new(lights);
+
+        // Probabilities add up, and match what's sampled.
+        let point = Vector3::new(10.0, 1.0, 0.0);
+        let total: f64 = (0 .. 100).map(|ix| tree.probability(point, ix)).sum();
+        assert!((total - 1.0).abs() < 1e-9);
+
+        for _ in 0 .. 100 {
+            let (ix, probability) = tree.sample(point).unwrap();
+            assert!((probability - tree.probability(point, ix)).abs() < 1e-12);
+        }
+
+        // Nearby lights are more likely.
+        assert!(tree.probability(point, 10) > 10.0 * tree.probability(point, 90));
+    }
+
+    #[test]
+    fn test_light_bounds_orientation() {
+        // Facing down, only lighting within 45 degrees.
+        let position = Vector3::new(0.0, 1.0, 0.0);
+        let spot = LightBounds::new(AABB::new(position, position), 1.0, Vector3::new(0.0, -1.0, 0.0), 0.0, PI / 4.0);
+        assert!(spot.importance(Vector3::new(0.0, 0.0, 0.0)) > 0.0);
+        assert!(spot.importance(Vector3::new(0.9, 0.0, 0.0)) > 0.0);
+        assert_eq!(spot.importance(Vector3::new(1.1, 0.0, 0.0)), 0.0);
+        assert_eq!(spot.importance(Vector3::new(0.0, 2.0, 0.0)), 0.0);
+
+        // Wider than a hemisphere still reaches behind.
+        let wide = LightBounds::new(AABB::new(position, position), 1.0, Vector3::new(0.0, -1.0, 0.0), 0.0, 0.75 * PI);
+        assert!(wide.importance(Vector3::new(1.0, 1.5, 0.0)) > 0.0);
+        assert_eq!(wide.importance(Vector3::new(0.0, 2.0, 0.0)), 0.0);
+
+        // The union covers both.
+        let up = LightBounds::new(AABB::new(position, position), 1.0, Vector3::new(0.0, 1.0, 0.0), 0.0, PI / 4.0);
+        let both = spot.union(&up);
+        assert!(both.importance(Vector3::new(0.0, 0.0, 0.0)) > 0.0);
+        assert!(both.importance(Vector3::new(0.0, 2.0, 0.0)) > 0.0);
+    }
+}
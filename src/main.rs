@@ -1,12 +1,16 @@
 #[macro_use] extern crate serde_derive;
 
+pub mod bdpt;
 pub mod bvh;
 pub mod camera;
 pub mod colour;
 pub mod controller;
 pub mod geom;
+pub mod ies;
+pub mod light_tree;
 pub mod material;
 pub mod matrix;
+pub mod medium;
 pub mod model;
 #[macro_use] pub mod obj;
 pub mod pixels;
@@ -15,10 +19,14 @@ pub mod renderer;
 pub mod sampling;
 pub mod scene;
 pub mod serde;
+pub mod sky;
+pub mod spectrum;
 pub mod stress;
+pub mod texture;
 pub mod timing;
 pub mod trace;
 pub mod vector;
+pub mod volume;
 pub mod worker;
 
 use std::env;
@@ -1,4 +1,5 @@
 use std::f64::consts::PI;
+use std::sync::Arc;
 
 use rand;
 use rand::Rng;
@@ -7,6 +8,8 @@ use crate::colour::Colour;
 use crate::geom;
 use crate::geom::{Collision, CollisionMetadata};
 use crate::model::Model;
+use crate::spectrum::Wavelengths;
+use crate::texture::Texture;
 use crate::vector::Vector3;
 
 
@@ -18,43 +21,99 @@ trait MaterialInterface {
 }
 
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub enum Material {
     Lambertian(LambertianMaterial),
+    OrenNayar(OrenNayarMaterial),
     Mirror(MirrorMaterial),
     Gloss(GlossMaterial),
     CookTorrance(CookTorranceMaterial),
-    FresnelCombination(FresnelCombinationMaterial),
-}
-
-#[derive(Clone, Copy, Debug)]
-pub enum BasicMaterial {
-    Lambertian(LambertianMaterial),
-    Mirror(MirrorMaterial),
-    Gloss(GlossMaterial),
-    CookTorrance(CookTorranceMaterial),
+    Dielectric(DielectricMaterial),
+    Mix(MixMaterial),
+    Null(NullMaterial),
 }
 
 impl Material {
-    pub fn resolve(self, collision: &Collision, model: &Model) -> Material {
+    pub fn resolve(&self, collision: &Collision, model: &Model) -> Material {
+        self.resolve_at(&SurfacePoint::new(collision, model))
+    }
+
+    pub fn resolve_at(&self, point: &SurfacePoint) -> Material {
         match self {
             Material::Lambertian(mat) => {
-                Material::Lambertian(mat.resolve(collision, model))
+                Material::Lambertian(mat.resolve_at(point))
+            },
+            Material::OrenNayar(mat) => {
+                Material::OrenNayar(mat.resolve_at(point))
             },
             Material::Gloss(mat) => {
-                Material::Gloss(mat.resolve(collision, model))
+                Material::Gloss(mat.resolve_at(point))
             },
+            Material::CookTorrance(mat) => {
+                Material::CookTorrance(mat.resolve_at(point))
+            },
+            Material::Mix(mat) => {
+                Material::Mix(mat.resolve_at(point))
+            },
+            _ => self.clone(),
+        }
+    }
+
+    // In spectral mode, swap all the colours in the material for their spectra at the given
+    // wavelengths.  Must be resolved first.
+    pub fn upsample(&self, wavelengths: &Wavelengths) -> Material {
+        match self {
+            Material::Lambertian(mat) => Material::Lambertian(mat.upsample(wavelengths)),
+            Material::OrenNayar(mat) => Material::OrenNayar(mat.upsample(wavelengths)),
+            Material::Gloss(mat) => Material::Gloss(mat.upsample(wavelengths)),
+            Material::CookTorrance(mat) => Material::CookTorrance(mat.upsample(wavelengths)),
+            Material::Dielectric(mat) => Material::Dielectric(mat.upsample(wavelengths)),
+            Material::Mix(mat) => Material::Mix(mat.upsample(wavelengths)),
+            _ => self.clone(),
+        }
+    }
+
+    // Which way round a surface is matters for refraction, so let the material know whether we
+    // hit the front or the back of it.
+    pub fn facing(self, front_face: bool) -> Material {
+        match self {
+            Material::Dielectric(mat) => Material::Dielectric(mat.facing(front_face)),
+            Material::Mix(mat) => Material::Mix(mat.facing(front_face)),
             _ => self,
         }
     }
 
-    pub fn to_basic(self) -> BasicMaterial {
+    pub fn is_null(&self) -> bool {
         match self {
-            Material::Lambertian(mat) => BasicMaterial::Lambertian(mat),
-            Material::Mirror(mat) => BasicMaterial::Mirror(mat),
-            Material::Gloss(mat) => BasicMaterial::Gloss(mat),
-            Material::CookTorrance(mat) => BasicMaterial::CookTorrance(mat),
-            Material::FresnelCombination(_) => panic!("FresnelCombination material cannot be downcast to BasicMaterial"),
+            Material::Null(_) => true,
+            _ => false,
+        }
+    }
+
+    // Whether the material only ever scatters in a single direction, so there is 0 chance of
+    // any particular direction being sampled.
+    pub fn is_specular(&self) -> bool {
+        match self {
+            Material::Mirror(_) | Material::Dielectric(_) | Material::Null(_) => true,
+            Material::Mix(mat) => mat.first.is_specular() && mat.second.is_specular(),
+            _ => false,
+        }
+    }
+
+    // Whether different wavelengths get scattered in different directions.
+    pub fn is_dispersive(&self) -> bool {
+        match self {
+            Material::Dielectric(mat) => mat.dispersion.is_some(),
+            Material::Mix(mat) => mat.first.is_dispersive() || mat.second.is_dispersive(),
+            _ => false,
+        }
+    }
+
+    // Whether any mix in the material is weighted by texture coordinates, which only meshes have.
+    pub fn has_textured_mix(&self) -> bool {
+        match self {
+            Material::Mix(mat) => mat.weight.is_textured() || mat.first.has_textured_mix() || mat.second.has_textured_mix(),
+            _ => false,
         }
     }
 
@@ -62,6 +121,10 @@ impl Material {
         Material::Lambertian(LambertianMaterial{ albedo, emittance })
     }
 
+    pub fn oren_nayar(albedo: MaterialColour, roughness: f64) -> Material {
+        Material::OrenNayar(OrenNayarMaterial::new(albedo, roughness))
+    }
+
     pub fn mirror() -> Material {
         Material::Mirror(MirrorMaterial{})
     }
@@ -70,106 +133,125 @@ impl Material {
         Material::Gloss(GlossMaterial::new(albedo, reflectance, metalness))
     }
 
-    pub fn cook_torrance(albedo: Colour, roughness: f64) -> Material {
+    pub fn cook_torrance(albedo: Colour, roughness: MaterialScalar) -> Material {
         Material::CookTorrance(CookTorranceMaterial { roughness,  albedo })
     }
 
-    pub fn fresnel_combination(diffuse: BasicMaterial, specular: BasicMaterial, refractive_index: f64) -> Material {
-        Material::FresnelCombination(FresnelCombinationMaterial::new(diffuse, specular, refractive_index))
+    pub fn dielectric(refractive_index: f64, tint: Colour) -> Material {
+        Material::Dielectric(DielectricMaterial::new(refractive_index, tint))
+    }
+
+    pub fn dispersive_dielectric(dispersion: Dispersion, tint: Colour) -> Material {
+        Material::Dielectric(DielectricMaterial::dispersive(dispersion, tint))
+    }
+
+    pub fn mix(first: Material, second: Material, weight: MixWeight) -> Material {
+        Material::Mix(MixMaterial::new(first, second, weight))
+    }
+
+    pub fn fresnel_combination(diffuse: Material, specular: Material, refractive_index: f64) -> Material {
+        Material::mix(diffuse, specular, MixWeight::fresnel(refractive_index))
+    }
+
+    pub fn null() -> Material {
+        Material::Null(NullMaterial{})
     }
 
     pub fn sample(&self, vec_out: Vector3, normal: Vector3) -> (Vector3, f64, Colour, bool) {
         match self {
             Material::Lambertian(mat) => mat.sample(vec_out, normal),
+            Material::OrenNayar(mat) => mat.sample(vec_out, normal),
             Material::Mirror(mat) => mat.sample(vec_out, normal),
             Material::Gloss(mat) => mat.sample(vec_out, normal),
-            _ => panic!("Not implemented"),
+            Material::Dielectric(mat) => mat.sample(vec_out, normal),
+            Material::Mix(mat) => mat.sample(vec_out, normal),
+            Material::Null(mat) => mat.sample(vec_out, normal),
+            Material::CookTorrance(_) => {
+                let direction = self.sample_pdf(vec_out, normal);
+                let pdf = self.weight_pdf(vec_out, direction * -1, normal);
+                let brdf = self.brdf(vec_out, direction * -1, normal);
+                (direction, pdf, brdf, false)
+            },
         }
     }
 
     pub fn weight_pdf(&self, vec_out: Vector3, vec_in: Vector3, normal: Vector3) -> f64 {
         match self {
             Material::Lambertian(mat) => mat.weight_pdf(vec_out, vec_in, normal),
+            Material::OrenNayar(mat) => mat.weight_pdf(vec_out, vec_in, normal),
             Material::Mirror(mat) => mat.weight_pdf(vec_out, vec_in, normal),
             Material::Gloss(mat) => mat.weight_pdf(vec_out, vec_in, normal),
             Material::CookTorrance(mat) => mat.weight_pdf(vec_out, vec_in, normal),
-            Material::FresnelCombination(mat) => mat.weight_pdf(vec_out, vec_in, normal),
+            Material::Dielectric(mat) => mat.weight_pdf(vec_out, vec_in, normal),
+            Material::Mix(mat) => mat.weight_pdf(vec_out, vec_in, normal),
+            Material::Null(mat) => mat.weight_pdf(vec_out, vec_in, normal),
         }
     }
 
     pub fn sample_pdf(&self, vec_out: Vector3, normal: Vector3) -> Vector3 {
         match self {
             Material::Lambertian(mat) => mat.sample_pdf(vec_out, normal),
+            Material::OrenNayar(mat) => mat.sample_pdf(vec_out, normal),
             Material::Mirror(mat) => mat.sample_pdf(vec_out, normal),
             Material::Gloss(mat) => mat.sample_pdf(vec_out, normal),
             Material::CookTorrance(mat) => mat.sample_pdf(vec_out, normal),
-            Material::FresnelCombination(mat) => mat.sample_pdf(vec_out, normal),
+            Material::Dielectric(mat) => mat.sample_pdf(vec_out, normal),
+            Material::Mix(mat) => mat.sample_pdf(vec_out, normal),
+            Material::Null(mat) => mat.sample_pdf(vec_out, normal),
         }
     }
 
     pub fn emittance(&self, vec_out: Vector3, cos_out: f64) -> Colour {
         match self {
             Material::Lambertian(mat) => mat.emittance(vec_out, cos_out),
+            Material::OrenNayar(mat) => mat.emittance(vec_out, cos_out),
             Material::Mirror(mat) => mat.emittance(vec_out, cos_out),
             Material::Gloss(mat) => mat.emittance(vec_out, cos_out),
             Material::CookTorrance(mat) => mat.emittance(vec_out, cos_out),
-            Material::FresnelCombination(mat) => mat.emittance(vec_out, cos_out),
+            Material::Dielectric(mat) => mat.emittance(vec_out, cos_out),
+            Material::Mix(mat) => mat.emittance(vec_out, cos_out),
+            Material::Null(mat) => mat.emittance(vec_out, cos_out),
         }
     }
 
     pub fn brdf(&self, vec_out: Vector3, vec_in: Vector3, normal: Vector3) -> Colour {
         match self {
             Material::Lambertian(mat) => mat.brdf(vec_out, vec_in, normal),
+            Material::OrenNayar(mat) => mat.brdf(vec_out, vec_in, normal),
             Material::Mirror(mat) => mat.brdf(vec_out, vec_in, normal),
             Material::Gloss(mat) => mat.brdf(vec_out, vec_in, normal),
             Material::CookTorrance(mat) => mat.brdf(vec_out, vec_in, normal),
-            Material::FresnelCombination(mat) => mat.brdf(vec_out, vec_in, normal),
+            Material::Dielectric(mat) => mat.brdf(vec_out, vec_in, normal),
+            Material::Mix(mat) => mat.brdf(vec_out, vec_in, normal),
+            Material::Null(mat) => mat.brdf(vec_out, vec_in, normal),
         }
     }
 }
 
-impl BasicMaterial {
-    fn weight_pdf(&self, vec_out: Vector3, vec_in: Vector3, normal: Vector3) -> f64 {
-        match self {
-            BasicMaterial::Lambertian(mat) => mat.weight_pdf(vec_out, vec_in, normal),
-            BasicMaterial::Mirror(mat) => mat.weight_pdf(vec_out, vec_in, normal),
-            BasicMaterial::Gloss(mat) => mat.weight_pdf(vec_out, vec_in, normal),
-            BasicMaterial::CookTorrance(mat) => mat.weight_pdf(vec_out, vec_in, normal),
-        }
-    }
-
-    fn sample_pdf(&self, vec_out: Vector3, normal: Vector3) -> Vector3 {
-        match self {
-            BasicMaterial::Lambertian(mat) => mat.sample_pdf(vec_out, normal),
-            BasicMaterial::Mirror(mat) => mat.sample_pdf(vec_out, normal),
-            BasicMaterial::Gloss(mat) => mat.sample_pdf(vec_out, normal),
-            BasicMaterial::CookTorrance(mat) => mat.sample_pdf(vec_out, normal),
-        }
-    }
-
-    fn emittance(&self, vec_out: Vector3, cos_out: f64) -> Colour {
-        match self {
-            BasicMaterial::Lambertian(mat) => mat.emittance(vec_out, cos_out),
-            BasicMaterial::Mirror(mat) => mat.emittance(vec_out, cos_out),
-            BasicMaterial::Gloss(mat) => mat.emittance(vec_out, cos_out),
-            BasicMaterial::CookTorrance(mat) => mat.emittance(vec_out, cos_out),
-        }
-    }
+// What materials need to know about where a mesh was hit.
+#[derive(Clone, Copy, Debug)]
+pub struct SurfacePoint {
+    texture_coords: (f64, f64),
+    colour: Option<Colour>,
+}
 
-    fn brdf(&self, vec_out: Vector3, vec_in: Vector3, normal: Vector3) -> Colour {
-        match self {
-            BasicMaterial::Lambertian(mat) => mat.brdf(vec_out, vec_in, normal),
-            BasicMaterial::Mirror(mat) => mat.brdf(vec_out, vec_in, normal),
-            BasicMaterial::Gloss(mat) => mat.brdf(vec_out, vec_in, normal),
-            BasicMaterial::CookTorrance(mat) => mat.brdf(vec_out, vec_in, normal),
+impl SurfacePoint {
+    pub fn new(collision: &Collision, model: &Model) -> SurfacePoint {
+        match collision.metadata {
+            CollisionMetadata::Mesh(face_ix, bx, by, bz) => SurfacePoint {
+                texture_coords: model.smooth_texture_coords(face_ix, bx, by, bz),
+                colour: model.vertex_colours.as_ref().map(|_| model.smooth_colour(face_ix, bx, by, bz)),
+            },
+            _ => panic!("Collision with mesh must have mesh metadata"),
         }
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub enum MaterialColour {
     Static(Colour),
     Vertex,
+    Texture(Arc<Texture>),
 }
 
 impl MaterialColour {
@@ -180,34 +262,71 @@ impl MaterialColour {
         }
     }
 
-    pub fn resolve(&self, collision: &Collision, model: &Model) -> MaterialColour {
+    pub fn resolve_at(&self, point: &SurfacePoint) -> MaterialColour {
         match self {
             MaterialColour::Static(c) => MaterialColour::Static(*c),
             MaterialColour::Vertex => {
-                match collision.metadata {
-                    CollisionMetadata::Mesh(face_ix, bx, by, bz) => {
-                        MaterialColour::Static(model.smooth_colour(face_ix, bx, by, bz))
-                    },
-                    _ => panic!("Collision with mesh must have mesh metadata"),
-                }
+                MaterialColour::Static(point.colour.expect("Model does not have vertex colours"))
+            },
+            MaterialColour::Texture(texture) => {
+                let (u, v) = point.texture_coords;
+                MaterialColour::Static(texture.colour(u, v))
             },
         }
     }
+
+    pub fn upsample(&self, wavelengths: &Wavelengths) -> MaterialColour {
+        MaterialColour::Static(wavelengths.upsample(self.colour()))
+    }
 }
 
-#[derive(Clone, Copy, Debug)]
+// A single number which can vary across a surface, like opacity or roughness.
+#[derive(Clone, Debug)]
+pub enum MaterialScalar {
+    Static(f64),
+    Texture(Arc<Texture>),
+}
+
+impl MaterialScalar {
+    pub fn value(&self, texture_coords: (f64, f64)) -> f64 {
+        match self {
+            MaterialScalar::Static(x) => *x,
+            MaterialScalar::Texture(texture) => texture.scalar(texture_coords.0, texture_coords.1),
+        }
+    }
+
+    pub fn scalar(&self) -> f64 {
+        match self {
+            MaterialScalar::Static(x) => *x,
+            _ => panic!("Material must be resolved before use"),
+        }
+    }
+
+    pub fn resolve_at(&self, point: &SurfacePoint) -> MaterialScalar {
+        MaterialScalar::Static(self.value(point.texture_coords))
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct LambertianMaterial {
     albedo: MaterialColour,
     emittance: Colour,
 }
 
 impl LambertianMaterial {
-    pub fn resolve(&self, collision: &Collision, model: &Model) -> LambertianMaterial {
+    pub fn resolve_at(&self, point: &SurfacePoint) -> LambertianMaterial {
         let mut resolved = self.clone();
-        resolved.albedo = self.albedo.resolve(collision, model);
+        resolved.albedo = self.albedo.resolve_at(point);
         resolved
     }
 
+    pub fn upsample(&self, wavelengths: &Wavelengths) -> LambertianMaterial {
+        LambertianMaterial {
+            albedo: self.albedo.upsample(wavelengths),
+            emittance: wavelengths.upsample(self.emittance),
+        }
+    }
+
     pub fn sample(&self, vec_out: Vector3, normal: Vector3) -> (Vector3, f64, Colour, bool) {
         let direction = self.sample_pdf(vec_out, normal);
         let pdf = self.weight_pdf(vec_out, direction * -1, normal);
@@ -218,7 +337,7 @@ impl LambertianMaterial {
 
 impl MaterialInterface for LambertianMaterial {
     fn weight_pdf(&self, _vec_out: Vector3, vec_in: Vector3, normal: Vector3) -> f64 {
-        normal.dot(vec_in * -1) / PI
+        f64::max(0.0, normal.dot(vec_in * -1)) / PI
     }
 
     fn sample_pdf(&self, _vec_out: Vector3, normal: Vector3) -> Vector3 {
@@ -235,7 +354,80 @@ impl MaterialInterface for LambertianMaterial {
     }
 
     fn brdf(&self, _vec_out: Vector3, vec_in: Vector3, normal: Vector3) -> Colour {
-        self.albedo.colour() * normal.dot(vec_in * -1) / PI
+        self.albedo.colour() * f64::max(0.0, normal.dot(vec_in * -1)) / PI
+    }
+}
+
+// Rough diffuse surfaces like clay, concrete and cloth.
+// Uses Fujii's improved Oren-Nayar model, which is cheaper than the original (no trig) and
+// reduces to Lambertian when roughness is 0.
+// See https://mimosa-pudica.net/improved-oren-nayar.html
+#[derive(Clone, Debug)]
+pub struct OrenNayarMaterial {
+    albedo: MaterialColour,
+    a: f64,
+    b: f64,
+}
+
+impl OrenNayarMaterial {
+    pub fn new(albedo: MaterialColour, roughness: f64) -> OrenNayarMaterial {
+        let sigma = f64::max(0.0, roughness);
+        let a = 1.0 / (PI + (PI / 2.0 - 2.0 / 3.0) * sigma);
+        let b = sigma * a;
+        OrenNayarMaterial { albedo, a, b }
+    }
+
+    pub fn resolve_at(&self, point: &SurfacePoint) -> OrenNayarMaterial {
+        let mut resolved = self.clone();
+        resolved.albedo = self.albedo.resolve_at(point);
+        resolved
+    }
+
+    pub fn upsample(&self, wavelengths: &Wavelengths) -> OrenNayarMaterial {
+        let mut upsampled = self.clone();
+        upsampled.albedo = self.albedo.upsample(wavelengths);
+        upsampled
+    }
+
+    pub fn sample(&self, vec_out: Vector3, normal: Vector3) -> (Vector3, f64, Colour, bool) {
+        let direction = self.sample_pdf(vec_out, normal);
+        let pdf = self.weight_pdf(vec_out, direction * -1, normal);
+        let brdf = self.brdf(vec_out, direction * -1, normal);
+        (direction, pdf, brdf, false)
+    }
+}
+
+impl MaterialInterface for OrenNayarMaterial {
+    fn weight_pdf(&self, _vec_out: Vector3, vec_in: Vector3, normal: Vector3) -> f64 {
+        // Cosine weighted, same as lambertian.
+        f64::max(0.0, normal.dot(vec_in * -1)) / PI
+    }
+
+    fn sample_pdf(&self, _vec_out: Vector3, normal: Vector3) -> Vector3 {
+        let random_direction = geom::cosine_sample_hemisphere();
+
+        let (i, j, k) = normal.form_basis();
+        let world_direction = geom::switch_basis(random_direction, i, j, k);
+
+        world_direction.normed()
+    }
+
+    fn emittance(&self, _vec_out: Vector3, _cos_out: f64) -> Colour {
+        Colour::BLACK
+    }
+
+    fn brdf(&self, vec_out: Vector3, vec_in: Vector3, normal: Vector3) -> Colour {
+        let vec_light = vec_in * -1;
+        let cos_in = normal.dot(vec_light);
+        let cos_out = normal.dot(vec_out);
+        if cos_in <= 0.0 || cos_out <= 0.0 {
+            return Colour::BLACK;
+        }
+
+        let s = vec_light.dot(vec_out) - cos_in * cos_out;
+        let t = if s <= 0.0 { 1.0 } else { f64::max(cos_in, cos_out) };
+
+        self.albedo.colour() * (self.a + self.b * s / t) * cos_in
     }
 }
 
@@ -271,7 +463,179 @@ impl MaterialInterface for MirrorMaterial {
     }
 }
 
+// An invisible surface, which lets rays carry straight on through.
+// Used to mark the boundary of a participating medium.
+#[derive(Clone, Copy, Debug)]
+pub struct NullMaterial {}
+
+impl NullMaterial {
+    pub fn sample(&self, vec_out: Vector3, normal: Vector3) -> (Vector3, f64, Colour, bool) {
+        (self.sample_pdf(vec_out, normal), 1.0, Colour::WHITE, true)
+    }
+}
+
+impl MaterialInterface for NullMaterial {
+    fn weight_pdf(&self, _vec_out: Vector3, _vec_in: Vector3, _normal: Vector3) -> f64 {
+        1.0
+    }
+
+    fn sample_pdf(&self, vec_out: Vector3, _normal: Vector3) -> Vector3 {
+        vec_out * -1
+    }
+
+    fn emittance(&self, _vec_out: Vector3, _cos_out: f64) -> Colour {
+        Colour::BLACK
+    }
+
+    fn brdf(&self, _vec_out: Vector3, _vec_in: Vector3, _normal: Vector3) -> Colour {
+        // 0 chance of any particular ray.
+        Colour::BLACK
+    }
+}
+
+// How the refractive index of a material varies with wavelength.
+// Wavelengths are in nanometres, but the coefficients are for micrometres, as is usual in
+// catalogues.
 #[derive(Clone, Copy, Debug)]
+pub enum Dispersion {
+    // n = a + b / λ²
+    Cauchy { a: f64, b: f64 },
+    // n² = 1 + Σ bᵢλ² / (λ² - cᵢ)
+    Sellmeier { b: [f64; 3], c: [f64; 3] },
+}
+
+impl Dispersion {
+    // Common crown glass, from the Schott catalogue.
+    pub const BK7: Dispersion = Dispersion::Sellmeier {
+        b: [1.039_612_12, 0.231_792_344, 1.010_469_45],
+        c: [0.006_000_698_67, 0.020_017_914_4, 103.560_653],
+    };
+
+    // Malitson, "Interspecimen Comparison of the Refractive Index of Fused Silica".
+    pub const FUSED_SILICA: Dispersion = Dispersion::Sellmeier {
+        b: [0.696_166_3, 0.407_942_6, 0.897_479_4],
+        c: [0.004_679_148, 0.013_512_063, 97.934_002_5],
+    };
+
+    // Peter, "Über Brechungsindizes und Absorptionskonstanten des Diamanten".
+    pub const DIAMOND: Dispersion = Dispersion::Sellmeier {
+        b: [0.3306, 4.3356, 0.0],
+        c: [0.030_625, 0.011_236, 0.0],
+    };
+
+    // The yellow helium line, which refractive indices are usually quoted at.
+    pub const D_LINE: f64 = 587.6;
+
+    pub fn refractive_index(&self, wavelength: f64) -> f64 {
+        let l2 = (wavelength / 1000.0) * (wavelength / 1000.0);
+        match self {
+            Dispersion::Cauchy { a, b } => a + b / l2,
+            Dispersion::Sellmeier { b, c } => {
+                let n2 = 1.0 + (0 .. 3).map(|ix| b[ix] * l2 / (l2 - c[ix])).sum::<f64>();
+                n2.sqrt()
+            },
+        }
+    }
+}
+
+// Smooth glass, water and the like.
+// Light is either reflected or refracted, with the chance of each given by the fresnel equations.
+#[derive(Clone, Copy, Debug)]
+pub struct DielectricMaterial {
+    refractive_index: f64,
+    // Colour picked up by light passing through the surface.
+    tint: Colour,
+    // Whether we're on the outside of the surface, looking in.
+    entering: bool,
+    // Only has an effect in spectral mode, otherwise the refractive index at the D line is used.
+    dispersion: Option<Dispersion>,
+}
+
+impl DielectricMaterial {
+    pub fn new(refractive_index: f64, tint: Colour) -> DielectricMaterial {
+        DielectricMaterial { refractive_index, tint, entering: true, dispersion: None }
+    }
+
+    pub fn dispersive(dispersion: Dispersion, tint: Colour) -> DielectricMaterial {
+        DielectricMaterial {
+            refractive_index: dispersion.refractive_index(Dispersion::D_LINE),
+            tint,
+            entering: true,
+            dispersion: Some(dispersion),
+        }
+    }
+
+    pub fn facing(self, front_face: bool) -> DielectricMaterial {
+        DielectricMaterial { entering: front_face, ..self }
+    }
+
+    pub fn upsample(&self, wavelengths: &Wavelengths) -> DielectricMaterial {
+        // The path follows the hero wavelength if the wavelengths get split up.
+        let refractive_index = match self.dispersion {
+            Some(dispersion) => dispersion.refractive_index(wavelengths.hero()),
+            None => self.refractive_index,
+        };
+        DielectricMaterial { refractive_index, tint: wavelengths.upsample(self.tint), ..*self }
+    }
+
+    // Ratio of refractive indices across the surface, in the direction the light is going.
+    fn eta(&self) -> f64 {
+        if self.entering { 1.0 / self.refractive_index } else { self.refractive_index }
+    }
+
+    // Returns the fraction of light reflected, and the direction of refraction if there is one.
+    fn scatter(&self, vec_out: Vector3, normal: Vector3) -> (f64, Option<Vector3>) {
+        let eta = self.eta();
+        let cos_i = f64::min(1.0, vec_out.dot(normal));
+        let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+        if sin2_t >= 1.0 {
+            // Total internal reflection.
+            return (1.0, None);
+        }
+
+        let cos_t = (1.0 - sin2_t).sqrt();
+        let r_parallel = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+        let r_perpendicular = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+        let reflectance = (r_parallel * r_parallel + r_perpendicular * r_perpendicular) / 2.0;
+
+        let refracted = (vec_out * -eta + normal * (eta * cos_i - cos_t)).normed();
+        (reflectance, Some(refracted))
+    }
+
+    // Note we don't scale radiance by eta^2 on refraction, since it cancels out for light which
+    // enters and then leaves an object again.
+    pub fn sample(&self, vec_out: Vector3, normal: Vector3) -> (Vector3, f64, Colour, bool) {
+        match self.scatter(vec_out, normal) {
+            (reflectance, Some(refracted)) if rand::thread_rng().gen::<f64>() >= reflectance => {
+                (refracted, 1.0 - reflectance, self.tint * (1.0 - reflectance), true)
+            },
+            (reflectance, _) => {
+                (MirrorMaterial::reflect(vec_out, normal), reflectance, Colour::WHITE * reflectance, true)
+            },
+        }
+    }
+}
+
+impl MaterialInterface for DielectricMaterial {
+    fn weight_pdf(&self, _vec_out: Vector3, _vec_in: Vector3, _normal: Vector3) -> f64 {
+        1.0
+    }
+
+    fn sample_pdf(&self, vec_out: Vector3, normal: Vector3) -> Vector3 {
+        self.sample(vec_out, normal).0
+    }
+
+    fn emittance(&self, _vec_out: Vector3, _cos_out: f64) -> Colour {
+        Colour::BLACK
+    }
+
+    fn brdf(&self, _vec_out: Vector3, _vec_in: Vector3, _normal: Vector3) -> Colour {
+        // 0 chance of any particular ray.
+        Colour::BLACK
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct GlossMaterial {
     lambertian: LambertianMaterial,
     mirror: MirrorMaterial,
@@ -292,21 +656,46 @@ impl GlossMaterial {
         }
     }
 
-    pub fn resolve(&self, collision: &Collision, model: &Model) -> GlossMaterial {
+    pub fn resolve_at(&self, point: &SurfacePoint) -> GlossMaterial {
         let mut resolved = self.clone();
-        resolved.lambertian = self.lambertian.resolve(collision, model);
+        resolved.lambertian = self.lambertian.resolve_at(point);
         resolved
     }
 
-    // Returns (direction, pdf, brdf, is_specular)
-    pub fn sample(&self, vec_out: Vector3, normal: Vector3) -> (Vector3, f64, Colour, bool) {
-        let cos_theta = vec_out.dot(normal);
+    pub fn upsample(&self, wavelengths: &Wavelengths) -> GlossMaterial {
+        let mut upsampled = self.clone();
+        upsampled.lambertian = self.lambertian.upsample(wavelengths);
+        upsampled
+    }
+
+    fn fresnel(&self, vec_out: Vector3, normal: Vector3) -> f64 {
+        let cos_theta = f64::max(0.0, vec_out.dot(normal));
+        let r0 = self.fresnel_r0;
+        r0 + (1.0 - r0) * (1.0 - cos_theta).powf(5.0)
+    }
+
+    // The diffuse lobe gets whatever the specular one doesn't reflect, on the way in and on the
+    // way out, so it's the same both ways round.  Dividing by what's left over on average
+    // (Schlick's fresnel averaged over the hemisphere) makes up for the light lost to the second
+    // fresnel factor, so no energy goes missing.
+    fn diffuse_factor(&self, vec_out: Vector3, vec_in: Vector3, normal: Vector3) -> f64 {
         let r0 = self.fresnel_r0;
-        let r = r0 + (1.0 - r0) * (1.0 - cos_theta).powf(5.0);
+        let average = r0 + (1.0 - r0) / 21.0;
+        let r_out = self.fresnel(vec_out, normal);
+        let r_in = self.fresnel(vec_in * -1.0, normal);
+        (1.0 - r_out) * (1.0 - r_in) / (1.0 - average)
+    }
+
+    // For very reflective materials (e.g. metals) sample relative to reflectivity.
+    // For less reflective materials (e.g. plastics) sample at 0.5 to capture highlights.
+    fn specular_chance(&self, vec_out: Vector3, normal: Vector3) -> f64 {
+        if self.fresnel_r0 > 0.5 { self.fresnel(vec_out, normal) } else { 0.5 }
+    }
 
-        // For very reflective materials (e.g. metals) sample relative to reflectivity.
-        // For less reflective materials (e.g. plastics) sample at 0.5 to capture highlights.
-        let specular_chance = if r0 > 0.5 { r } else { 0.5 };
+    // Returns (direction, pdf, brdf, is_specular)
+    pub fn sample(&self, vec_out: Vector3, normal: Vector3) -> (Vector3, f64, Colour, bool) {
+        let r = self.fresnel(vec_out, normal);
+        let specular_chance = self.specular_chance(vec_out, normal);
         let is_specular = rand::thread_rng().gen::<f64>() <= specular_chance;
 
         if is_specular {
@@ -320,37 +709,22 @@ impl GlossMaterial {
             let vec_in = direction * -1.0;
             let pdf = self.lambertian.weight_pdf(vec_out, vec_in, normal);
             let brdf = self.lambertian.brdf(vec_out, vec_in, normal) * (1.0 - self.metalness);
-            (direction, pdf * (1.0 - specular_chance), brdf * (1.0 - r), is_specular)
+            let diffuse = self.diffuse_factor(vec_out, vec_in, normal);
+            (direction, pdf * (1.0 - specular_chance), brdf * diffuse, is_specular)
         }
     }
 }
 
 impl MaterialInterface for GlossMaterial {
+    // The specular lobe has 0 chance of producing any particular ray, so only the diffuse lobe
+    // contributes.
     fn weight_pdf(&self, vec_out: Vector3, vec_in: Vector3, normal: Vector3) -> f64 {
-        let cos_theta = vec_out.dot(normal);
-
-        let r0 = self.fresnel_r0;
-        let r = r0 + (1.0 - r0) * (1.0 - cos_theta).powf(5.0);
-
-        // Probability of this ray given diffuse scattering.
-        let diffuse = self.lambertian.weight_pdf(vec_out, vec_in, normal);
-
-        // Probability of this ray given specular reflection.
-        let specular = self.mirror.weight_pdf(vec_out, vec_in, normal);
-
-        diffuse * (1.0 - r) * specular * r
+        let specular_chance = self.specular_chance(vec_out, normal);
+        self.lambertian.weight_pdf(vec_out, vec_in, normal) * (1.0 - specular_chance)
     }
 
     fn sample_pdf(&self, vec_out: Vector3, normal: Vector3) -> Vector3 {
-        let cos_theta = vec_out.dot(normal);
-        let r0 = self.fresnel_r0;
-        let r = r0 + (1.0 - r0) * (1.0 - cos_theta).powf(5.0);
-
-        if rand::thread_rng().gen::<f64>() > r {
-            self.lambertian.sample_pdf(vec_out, normal)
-        } else {
-            self.mirror.sample_pdf(vec_out, normal)
-        }
+        self.sample(vec_out, normal).0
     }
 
     fn emittance(&self, _vec_out: Vector3, _cos_out: f64) -> Colour {
@@ -358,108 +732,221 @@ impl MaterialInterface for GlossMaterial {
     }
 
     fn brdf(&self, vec_out: Vector3, vec_in: Vector3, normal: Vector3) -> Colour {
-        let cos_theta = vec_out.dot(normal);
-
-        let r0 = self.fresnel_r0;
-        let r = r0 + (1.0 - r0) * (1.0 - cos_theta).powf(5.0);
+        let r = self.fresnel(vec_out, normal);
 
         let diffuse = self.lambertian.brdf(vec_out, vec_in, normal) * (1.0 - self.metalness);
         let specular = self.mirror.brdf(vec_out, vec_in, normal);
 
-        diffuse * (1.0 - r) + specular * r
+        diffuse * self.diffuse_factor(vec_out, vec_in, normal) + specular * r
     }
 }
 
-#[derive(Clone, Copy, Debug)]
-pub struct FresnelCombinationMaterial {
-    diffuse: BasicMaterial,
-    specular: BasicMaterial,
-    fresnel_r0: f64,
+// How much of the second material to use in a mix.
+#[derive(Clone, Debug)]
+pub enum MixWeight {
+    Constant(f64),
+    // Schlick's approximation of the fresnel factor, given the reflectance at normal incidence.
+    Fresnel(f64),
+    Texture(Arc<Texture>),
+    // Alternating squares of 0 and 1, with the given number of squares per unit of texture
+    // coordinates.
+    Checker(f64),
 }
 
-impl FresnelCombinationMaterial {
-    pub fn new(diffuse: BasicMaterial, specular: BasicMaterial, refractive_index: f64) -> FresnelCombinationMaterial {
-        // Schlick's approximation for the fresnel factor.
+impl MixWeight {
+    pub fn fresnel(refractive_index: f64) -> MixWeight {
         let n1: f64 = 1.0;  // Air
         let n2: f64 = refractive_index;
-        let fresnel_r0 = ((n1 - n2) / (n1 + n2)).powf(2.0);
+        MixWeight::Fresnel(((n1 - n2) / (n1 + n2)).powf(2.0))
+    }
 
-        FresnelCombinationMaterial { diffuse, specular, fresnel_r0 }
+    pub fn is_textured(&self) -> bool {
+        matches!(self, MixWeight::Texture(_) | MixWeight::Checker(_))
     }
 
-    fn fresnel_weight(&self, vec_out: Vector3, normal: Vector3) -> f64 {
-        let cos_theta = vec_out.dot(normal);
-        let r0 = self.fresnel_r0;
-        r0 + (1.0 - r0) * (1.0 - cos_theta).powf(5.0)
+    pub fn value(&self, cos_theta: f64) -> f64 {
+        match self {
+            MixWeight::Constant(w) => w.clamp(0.0, 1.0),
+            MixWeight::Fresnel(r0) => r0 + (1.0 - r0) * (1.0 - cos_theta.max(0.0)).powf(5.0),
+            _ => panic!("Material must be resolved before use"),
+        }
+    }
+
+    pub fn resolve_at(&self, point: &SurfacePoint) -> MixWeight {
+        match self {
+            MixWeight::Texture(texture) => {
+                let (u, v) = point.texture_coords;
+                MixWeight::Constant(texture.scalar(u, v))
+            },
+            MixWeight::Checker(scale) => {
+                let (u, v) = point.texture_coords;
+                let parity = ((u * scale).floor() + (v * scale).floor()) as i64;
+                MixWeight::Constant(parity.rem_euclid(2) as f64)
+            },
+            _ => self.clone(),
+        }
+    }
+}
+
+// A blend of two materials, which can themselves be any material, including other mixes.
+#[derive(Clone, Debug)]
+pub struct MixMaterial {
+    first: Arc<Material>,
+    second: Arc<Material>,
+    weight: MixWeight,
+    // The two materials are shared between every hit, so instead of rebuilding them each time,
+    // remember what was hit and apply it to whichever one gets used.
+    point: Option<SurfacePoint>,
+    front_face: Option<bool>,
+    wavelengths: Option<Wavelengths>,
+}
+
+impl MixMaterial {
+    pub fn new(first: Material, second: Material, weight: MixWeight) -> MixMaterial {
+        MixMaterial {
+            first: Arc::new(first),
+            second: Arc::new(second),
+            weight,
+            point: None,
+            front_face: None,
+            wavelengths: None,
+        }
+    }
+
+    pub fn resolve_at(&self, point: &SurfacePoint) -> MixMaterial {
+        MixMaterial { weight: self.weight.resolve_at(point), point: Some(*point), ..self.clone() }
+    }
+
+    pub fn upsample(&self, wavelengths: &Wavelengths) -> MixMaterial {
+        MixMaterial { wavelengths: Some(*wavelengths), ..self.clone() }
+    }
+
+    pub fn facing(self, front_face: bool) -> MixMaterial {
+        MixMaterial { front_face: Some(front_face), ..self }
+    }
+
+    // One of the two materials, as it is at this particular hit.
+    fn lobe(&self, material: &Material) -> Material {
+        let mut lobe = match self.point {
+            Some(ref point) => material.resolve_at(point),
+            None => material.clone(),
+        };
+        if let Some(front_face) = self.front_face {
+            lobe = lobe.facing(front_face);
+        }
+        if let Some(ref wavelengths) = self.wavelengths {
+            lobe = lobe.upsample(wavelengths);
+        }
+        lobe
+    }
+
+    // Pick one of the materials in proportion to its weight and sample it.
+    // Specular samples can only have come from the chosen material, but anything else could have
+    // been produced by either, so the pdf and brdf are combined over both.
+    pub fn sample(&self, vec_out: Vector3, normal: Vector3) -> (Vector3, f64, Colour, bool) {
+        let w = self.weight.value(vec_out.dot(normal));
+        let use_second = rand::thread_rng().gen::<f64>() < w;
+        let (chosen, chance) = if use_second { (&self.second, w) } else { (&self.first, 1.0 - w) };
+
+        let (direction, pdf, brdf, is_specular) = self.lobe(chosen).sample(vec_out, normal);
+        if is_specular {
+            return (direction, pdf * chance, brdf * chance, true);
+        }
+
+        let vec_in = direction * -1;
+        (direction, self.weight_pdf(vec_out, vec_in, normal), self.brdf(vec_out, vec_in, normal), false)
+    }
+
+    fn lobe_pdf(material: &Material, vec_out: Vector3, vec_in: Vector3, normal: Vector3) -> f64 {
+        if material.is_specular() {
+            0.0
+        } else {
+            material.weight_pdf(vec_out, vec_in, normal)
+        }
     }
 }
 
-impl MaterialInterface for FresnelCombinationMaterial {
+impl MaterialInterface for MixMaterial {
     fn weight_pdf(&self, vec_out: Vector3, vec_in: Vector3, normal: Vector3) -> f64 {
-        let r = self.fresnel_weight(vec_out, normal);
-        let diffuse_weight = self.diffuse.weight_pdf(vec_out, vec_in, normal);
-        let specular_weight = self.specular.weight_pdf(vec_out, vec_in, normal);
-        diffuse_weight * (1.0 - r) + specular_weight * r
+        let w = self.weight.value(vec_out.dot(normal));
+        let first = MixMaterial::lobe_pdf(&self.lobe(&self.first), vec_out, vec_in, normal);
+        let second = MixMaterial::lobe_pdf(&self.lobe(&self.second), vec_out, vec_in, normal);
+        first * (1.0 - w) + second * w
     }
 
     fn sample_pdf(&self, vec_out: Vector3, normal: Vector3) -> Vector3 {
-        let r = self.fresnel_weight(vec_out, normal);
+        let w = self.weight.value(vec_out.dot(normal));
 
-        if rand::thread_rng().gen::<f64>() > r {
-            self.diffuse.sample_pdf(vec_out, normal)
+        if rand::thread_rng().gen::<f64>() < w {
+            self.lobe(&self.second).sample_pdf(vec_out, normal)
         } else {
-            self.specular.sample_pdf(vec_out, normal)
+            self.lobe(&self.first).sample_pdf(vec_out, normal)
         }
     }
 
     fn emittance(&self, vec_out: Vector3, cos_out: f64) -> Colour {
-        // Specular emittance doesn't make sense?
-        self.diffuse.emittance(vec_out, cos_out)
+        let w = self.weight.value(cos_out);
+        let first = self.lobe(&self.first).emittance(vec_out, cos_out);
+        let second = self.lobe(&self.second).emittance(vec_out, cos_out);
+        first * (1.0 - w) + second * w
     }
 
     fn brdf(&self, vec_out: Vector3, vec_in: Vector3, normal: Vector3) -> Colour {
-        let r = self.fresnel_weight(vec_out, normal);
-
-        let diffuse_brdf = self.diffuse.brdf(vec_out, vec_in, normal);
-        let specular_brdf = self.specular.brdf(vec_out, vec_in, normal);
-
-        diffuse_brdf * (1.0 - r) + specular_brdf * r
+        let w = self.weight.value(vec_out.dot(normal));
+        let first = self.lobe(&self.first).brdf(vec_out, vec_in, normal);
+        let second = self.lobe(&self.second).brdf(vec_out, vec_in, normal);
+        first * (1.0 - w) + second * w
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub struct CookTorranceMaterial {
-    roughness: f64,
+    roughness: MaterialScalar,
     albedo: Colour,
 }
 
 impl CookTorranceMaterial {
+    pub fn resolve_at(&self, point: &SurfacePoint) -> CookTorranceMaterial {
+        let mut resolved = self.clone();
+        resolved.roughness = self.roughness.resolve_at(point);
+        resolved
+    }
+
+    pub fn upsample(&self, wavelengths: &Wavelengths) -> CookTorranceMaterial {
+        CookTorranceMaterial {
+            roughness: self.roughness.clone(),
+            albedo: wavelengths.upsample(self.albedo),
+        }
+    }
+
     fn ndf(&self, n: Vector3, h: Vector3) -> f64 {
         // Beckmann NDF.
-        let alpha = h.dot(n).acos();
-        let cos_alpha = alpha.cos();
-        let tan_alpha = alpha.tan();
-        let m = self.roughness;
+        let cos_alpha = h.dot(n);
+        if cos_alpha <= 0.0 {
+            return 0.0;
+        }
 
-        let exp = (-1.0 * (tan_alpha * tan_alpha) / (m * m)).exp();
-        let d0 = exp / (PI * m * m * cos_alpha.powf(4.0));
-        0f64.max(d0 * n.dot(h))
+        let cos2_alpha = cos_alpha * cos_alpha;
+        let tan2_alpha = (1.0 - cos2_alpha) / cos2_alpha;
+        let m = self.roughness.scalar();
+
+        let exp = (-1.0 * tan2_alpha / (m * m)).exp();
+        exp / (PI * m * m * cos2_alpha * cos2_alpha)
     }
 }
 
 impl MaterialInterface for CookTorranceMaterial {
     fn weight_pdf(&self, vec_out: Vector3, vec_in: Vector3, normal: Vector3) -> f64 {
+        // We sample microfacet normals in proportion to D(h) * cos(h), and then need to account
+        // for the change of variables from the microfacet normal to the reflected direction.
         let h = (vec_out - vec_in).normed();
         let d = self.ndf(normal, h);
-        let p = (d * normal.dot(h).abs()) / (4.0 * vec_out.dot(h).abs());
-
-        if p < 0.0 {
-            println!("Negative PDF! d={:.1}, p={:.1}", d, p);
-            panic!();
+        let vdh = vec_out.dot(h).abs();
+        if d <= 0.0 || vdh <= 0.0 {
+            return 0.0;
         }
 
-        p
-
+        (d * normal.dot(h)) / (4.0 * vdh)
     }
 
     fn sample_pdf(&self, vec_out: Vector3, normal: Vector3) -> Vector3 {
@@ -468,7 +955,7 @@ impl MaterialInterface for CookTorranceMaterial {
         // derivation.
         let mut rng = rand::thread_rng();
         let e = rng.gen::<f64>();
-        let a = self.roughness;
+        let a = self.roughness.scalar();
         let theta = (a.powf(2.0) * (1.0 - e).ln() * -1.0).sqrt().atan();
         let phi = rng.gen::<f64>()  * 2.0 * PI;
 
@@ -509,17 +996,389 @@ impl MaterialInterface for CookTorranceMaterial {
         //   phi = angle of incidence with microfacet normal
         let h = (vec_out - vec_in).normed();
 
-        let d = self.ndf(normal, h);
-
         // Geometric term.
         let ndl = normal.dot(vec_in * -1.0);
         let vdh = vec_out.dot(h);
         let ndh = normal.dot(h);
         let ndv = normal.dot(vec_out);
+        if ndl <= 0.0 || ndv <= 0.0 || vdh <= 0.0 {
+            return Colour::BLACK;
+        }
+
+        let d = self.ndf(normal, h);
         let g = 0f64.max(1f64.min(((2.0 * ndh * ndv) / vdh).min((2.0 * ndh * ndl) / vdh)));
 
-        // Specular component.
-        self.albedo * (d * g) / (4.0 * ndv * ndl)
+        // Specular component, times the cosine of the incoming light like everything else.
+        self.albedo * (d * g) / (4.0 * ndv)
     }
 }
 
+
+#[cfg(test)]
+mod test {
+    use crate::colour::Colour;
+    use crate::geom::{Collision, CollisionMetadata};
+    use crate::material::*;
+    use crate::model::Model;
+    use crate::vector::Vector3;
+
+    #[test]
+    fn test_mix_material() {
+        let red = Material::lambertian(MaterialColour::Static(Colour::rgb(1.0, 0.0, 0.0)), Colour::BLACK);
+        let blue = Material::lambertian(MaterialColour::Static(Colour::rgb(0.0, 0.0, 1.0)), Colour::BLACK);
+        let mix = Material::mix(red, Material::mirror(), MixWeight::Constant(0.5));
+        let nested = Material::mix(mix, blue, MixWeight::Constant(0.5));
+
+        let normal = Vector3::new(0.0, 1.0, 0.0);
+        let vec_out = Vector3::new(0.0, 1.0, 0.0);
+        let vec_in = Vector3::new(0.0, -1.0, 0.0);
+
+        // Mirror contributes nothing in any particular direction.
+        let brdf = nested.brdf(vec_out, vec_in, normal);
+        assert!((brdf.r - 0.25 / PI).abs() < 1e-9);
+        assert_eq!(brdf.g, 0.0);
+        assert!((brdf.b - 0.5 / PI).abs() < 1e-9);
+        assert!((nested.weight_pdf(vec_out, vec_in, normal) - 0.75 / PI).abs() < 1e-9);
+
+        // Sampled weights should average out to the mix, with the mirror reflecting a quarter of
+        // the light back along the normal.
+        let n = 100_000;
+        let total = (0 .. n).fold(Colour::BLACK, |acc, _| {
+            let (_, pdf, brdf, _) = nested.sample(vec_out, normal);
+            acc + brdf / pdf
+        }) / n as f64;
+        assert!((total.r - 0.5).abs() < 0.02);
+        assert!((total.g - 0.25).abs() < 0.02);
+        assert!((total.b - 0.75).abs() < 0.02);
+    }
+
+    #[test]
+    fn test_mix_resolved_in_place() {
+        let mut model = Model::new(
+            vec![Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 1.0)],
+            vec![(0, 1, 2)]);
+        model.vertex_colours = Some(vec![Colour::rgb(0.0, 0.0, 0.0), Colour::rgb(0.3, 0.0, 0.0), Colour::rgb(0.0, 0.0, 0.6)]);
+        let collision = Collision {
+            distance: 1.0,
+            location: Vector3::new(0.25, 0.0, 0.5),
+            normal: Vector3::new(0.0, 1.0, 0.0),
+            metadata: CollisionMetadata::Mesh(0, 0.25, 0.25, 0.5),
+            front_face: false,
+        };
+
+        let vertex = Material::lambertian(MaterialColour::Vertex, Colour::BLACK);
+        let mix = Material::mix(vertex, Material::dielectric(1.5, Colour::WHITE), MixWeight::Constant(0.5));
+        let resolved = mix.resolve(&collision, &model).facing(false);
+
+        // The two materials are still shared rather than copied for the hit.
+        match (&mix, &resolved) {
+            (Material::Mix(before), Material::Mix(after)) => {
+                assert!(Arc::ptr_eq(&before.first, &after.first));
+                assert!(Arc::ptr_eq(&before.second, &after.second));
+            },
+            _ => panic!("Expected a mix"),
+        }
+
+        let normal = Vector3::new(0.0, 1.0, 0.0);
+        let vec_out = Vector3::new(0.0, 1.0, 0.0);
+        let brdf = resolved.brdf(vec_out, normal * -1, normal);
+        assert!((brdf.r - 0.5 * 0.075 / PI).abs() < 1e-9);
+        assert!((brdf.b - 0.5 * 0.3 / PI).abs() < 1e-9);
+
+        // The glass knows it was hit from the inside, so reflects everything at a grazing angle.
+        let vec_out = Vector3::new(0.9, 0.1, 0.0).normed();
+        for _ in 0 .. 100 {
+            let (direction, _, _, is_specular) = resolved.sample(vec_out, normal);
+            if is_specular {
+                assert!(direction.y > 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_dielectric() {
+        let glass = Material::dielectric(1.5, Colour::WHITE);
+        let normal = Vector3::new(0.0, 1.0, 0.0);
+        let vec_out = Vector3::new(0.0, 1.0, 0.0);
+
+        // Head on, 4% is reflected and the rest carries straight on through.
+        let n = 10_000;
+        let reflected = (0 .. n).filter(|_| {
+            let (direction, pdf, brdf, is_specular) = glass.sample(vec_out, normal);
+            assert!(is_specular);
+            assert!((brdf.r / pdf - 1.0).abs() < 1e-9);
+            direction.y > 0.0
+        }).count();
+        assert!((reflected as f64 / n as f64 - 0.04).abs() < 0.01);
+
+        // At a grazing angle from the inside, everything is reflected.
+        let inside = glass.facing(false);
+        let vec_out = Vector3::new(0.9, 0.1, 0.0).normed();
+        for _ in 0 .. 100 {
+            let (direction, _, _, _) = inside.sample(vec_out, normal);
+            assert!(direction.y > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_dispersion() {
+        let check = |dispersion: Dispersion, expected: f64| {
+            let n = dispersion.refractive_index(Dispersion::D_LINE);
+            assert!((n - expected).abs() < 1e-3, "Expected {}, got {}", expected, n);
+        };
+        check(Dispersion::BK7, 1.5168);
+        check(Dispersion::FUSED_SILICA, 1.4585);
+        check(Dispersion::DIAMOND, 2.4175);
+        check(Dispersion::Cauchy { a: 1.5046, b: 0.004_20 }, 1.5168);
+
+        // Blue bends more than red.
+        let blue = Dispersion::BK7.refractive_index(450.0);
+        let red = Dispersion::BK7.refractive_index(650.0);
+        assert!(blue > red);
+
+        let glass = Material::dispersive_dielectric(Dispersion::BK7, Colour::WHITE);
+        assert!(glass.is_dispersive());
+        assert!(!Material::dielectric(1.5, Colour::WHITE).is_dispersive());
+        match glass.upsample(&Wavelengths::from_hero(450.0)) {
+            Material::Dielectric(mat) => assert_eq!(mat.refractive_index, blue),
+            _ => panic!("Upsampling changed the type of material"),
+        }
+    }
+
+    // Statistical checks that each material's `sample`, `weight_pdf` and `brdf` agree with each
+    // other, and that materials don't create energy.
+    // All checks are done with the normal pointing straight up.
+    mod bsdf {
+        use std::f64::consts::PI;
+
+        use crate::colour::Colour;
+        use crate::material::*;
+        use crate::vector::Vector3;
+
+        const NUM_SAMPLES: usize = 200_000;
+        const THETA_BINS: usize = 10;
+        const PHI_BINS: usize = 20;
+        const SUBDIVISIONS: usize = 24;
+
+        fn normal() -> Vector3 {
+            Vector3::new(0.0, 1.0, 0.0)
+        }
+
+        // Outgoing directions to test with, from near the normal to near grazing.
+        fn outgoing_directions() -> Vec<Vector3> {
+            [0.9, 0.5, 0.2].iter()
+                .map(|&cos_theta: &f64| Vector3::new((1.0 - cos_theta * cos_theta).sqrt(), cos_theta, 0.0))
+                .collect()
+        }
+
+        fn direction(cos_theta: f64, phi: f64) -> Vector3 {
+            let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+            Vector3::new(sin_theta * phi.cos(), cos_theta, sin_theta * phi.sin())
+        }
+
+        // Bins are evenly spaced in cos(theta) and phi, so they all cover the same solid angle.
+        fn bin_index(direction: Vector3) -> Option<usize> {
+            if direction.y <= 0.0 {
+                return None;
+            }
+            let phi = direction.z.atan2(direction.x).rem_euclid(2.0 * PI);
+            let theta_bin = usize::min(THETA_BINS - 1, (direction.y * THETA_BINS as f64) as usize);
+            let phi_bin = usize::min(PHI_BINS - 1, (phi / (2.0 * PI) * PHI_BINS as f64) as usize);
+            Some(theta_bin * PHI_BINS + phi_bin)
+        }
+
+        // Integrates `f` over each bin using the midpoint rule.
+        fn integrate_bins<F: Fn(Vector3) -> f64>(f: F) -> Vec<f64> {
+            let d_cos = 1.0 / (THETA_BINS * SUBDIVISIONS) as f64;
+            let d_phi = 2.0 * PI / (PHI_BINS * SUBDIVISIONS) as f64;
+            let mut integrals = vec![0.0; THETA_BINS * PHI_BINS];
+            for t in 0 .. THETA_BINS * SUBDIVISIONS {
+                for p in 0 .. PHI_BINS * SUBDIVISIONS {
+                    let dir = direction((t as f64 + 0.5) * d_cos, (p as f64 + 0.5) * d_phi);
+                    let ix = (t / SUBDIVISIONS) * PHI_BINS + p / SUBDIVISIONS;
+                    integrals[ix] += f(dir) * d_cos * d_phi;
+                }
+            }
+            integrals
+        }
+
+        // Upper bound for a chi-square statistic with `dof` degrees of freedom, at a significance
+        // level of around 1e-5, using the Wilson-Hilferty approximation.
+        fn chi_square_threshold(dof: usize) -> f64 {
+            let k = dof as f64;
+            let z = 4.0;
+            k * (1.0 - 2.0 / (9.0 * k) + z * (2.0 / (9.0 * k)).sqrt()).powf(3.0)
+        }
+
+        // Every continuous sample should come with the same pdf and brdf that we'd get by asking
+        // for them directly.
+        pub fn check_sample_consistency(material: &Material) {
+            for vec_out in outgoing_directions() {
+                for _ in 0 .. 10_000 {
+                    let (dir, pdf, brdf, is_specular) = material.sample(vec_out, normal());
+                    assert!(!dir.is_nan() && pdf >= 0.0, "Bad sample {:?} with pdf {}", dir, pdf);
+                    if is_specular || pdf == 0.0 {
+                        continue;
+                    }
+
+                    let expected_pdf = material.weight_pdf(vec_out, dir * -1, normal());
+                    let expected_brdf = material.brdf(vec_out, dir * -1, normal());
+                    assert!((pdf - expected_pdf).abs() <= 1e-6 * expected_pdf.max(1.0),
+                        "Sampled pdf {} doesn't match weight_pdf {} for {:?}", pdf, expected_pdf, dir);
+                    assert!((brdf - expected_brdf).max().abs() <= 1e-6 && (expected_brdf - brdf).max().abs() <= 1e-6,
+                        "Sampled brdf {:?} doesn't match brdf {:?} for {:?}", brdf, expected_brdf, dir);
+                }
+            }
+        }
+
+        // Sampled directions should be distributed according to `weight_pdf`.
+        // Specular samples and anything below the surface go into one extra bin, which should get
+        // whatever probability is left over.
+        pub fn check_chi_square(material: &Material) {
+            for vec_out in outgoing_directions() {
+                let mut observed = vec![0.0; THETA_BINS * PHI_BINS + 1];
+                for _ in 0 .. NUM_SAMPLES {
+                    let (dir, pdf, _, is_specular) = material.sample(vec_out, normal());
+                    let ix = if is_specular || pdf == 0.0 { None } else { bin_index(dir) };
+                    observed[ix.unwrap_or(THETA_BINS * PHI_BINS)] += 1.0;
+                }
+
+                // Specular materials have 0 chance of any particular direction.
+                let pdf = |dir: Vector3| if material.is_specular() { 0.0 } else { material.weight_pdf(vec_out, dir * -1, normal()) };
+                let mut expected: Vec<f64> = integrate_bins(pdf)
+                    .iter()
+                    .map(|p| p * NUM_SAMPLES as f64)
+                    .collect();
+                let leftover = NUM_SAMPLES as f64 - expected.iter().sum::<f64>();
+                assert!(leftover > -0.01 * NUM_SAMPLES as f64, "weight_pdf integrates to more than 1");
+                expected.push(f64::max(0.0, leftover));
+
+                // Pool together bins which are too small for the test to be reliable.
+                let mut chi_square = 0.0;
+                let mut dof = 0;
+                let (mut pooled_observed, mut pooled_expected) = (0.0, 0.0);
+                for (o, e) in observed.iter().zip(expected.iter()) {
+                    if *e < 5.0 {
+                        pooled_observed += o;
+                        pooled_expected += e;
+                    } else {
+                        chi_square += (o - e) * (o - e) / e;
+                        dof += 1;
+                    }
+                }
+                if pooled_expected >= 5.0 {
+                    chi_square += (pooled_observed - pooled_expected) * (pooled_observed - pooled_expected) / pooled_expected;
+                    dof += 1;
+                } else {
+                    assert!(pooled_observed < 5.0 + 5.0 * pooled_expected,
+                        "Sampled {} directions where weight_pdf expects {}", pooled_observed, pooled_expected);
+                }
+
+                let threshold = chi_square_threshold(usize::max(1, dof - 1));
+                assert!(chi_square < threshold,
+                    "Chi-square {:.1} exceeds {:.1} for vec_out={:?}", chi_square, threshold, vec_out);
+            }
+        }
+
+        // Under uniform white light, a white material can reflect at most all of it.
+        // Also checks the importance sampled estimate agrees with integrating the brdf directly.
+        pub fn check_white_furnace(material: &Material, expected_albedo: Option<f64>) {
+            for vec_out in outgoing_directions() {
+                let mut total = Colour::BLACK;
+                let mut continuous = Colour::BLACK;
+                for _ in 0 .. NUM_SAMPLES {
+                    let (_, pdf, brdf, is_specular) = material.sample(vec_out, normal());
+                    if pdf > 0.0 {
+                        total += brdf / pdf;
+                        if !is_specular {
+                            continuous += brdf / pdf;
+                        }
+                    }
+                }
+                let albedo = total / NUM_SAMPLES as f64;
+                let continuous = continuous / NUM_SAMPLES as f64;
+
+                assert!(albedo.max() <= 1.01, "Albedo {:?} is more than 1 for vec_out={:?}", albedo, vec_out);
+                if let Some(expected) = expected_albedo {
+                    assert!((albedo.min() - expected).abs() < 0.01 && (albedo.max() - expected).abs() < 0.01,
+                        "Albedo {:?} should be {} for vec_out={:?}", albedo, expected, vec_out);
+                }
+
+                let integral: f64 = integrate_bins(|dir| material.brdf(vec_out, dir * -1, normal()).mean()).iter().sum();
+                assert!((continuous.mean() - integral).abs() < 0.01,
+                    "Sampled albedo {} doesn't match integrated brdf {} for vec_out={:?}", continuous.mean(), integral, vec_out);
+            }
+        }
+
+        // Light should be reflected the same amount either way round.
+        // brdf includes the cosine of the incoming light, so divide that out first.
+        pub fn check_reciprocity(material: &Material) {
+            let dirs: Vec<Vector3> = (0 .. 20)
+                .map(|ix| direction(0.05 + 0.9 * (ix as f64 / 20.0), ix as f64 * 2.4))
+                .collect();
+            for &a in dirs.iter() {
+                for &b in dirs.iter() {
+                    let forward = material.brdf(a, b * -1, normal()) / b.y;
+                    let backward = material.brdf(b, a * -1, normal()) / a.y;
+                    let difference = (forward - backward).max().abs().max((backward - forward).max().abs());
+                    assert!(difference <= 1e-9 * forward.max().max(1.0),
+                        "brdf isn't reciprocal: {:?} vs {:?} for {:?} and {:?}", forward, backward, a, b);
+                }
+            }
+        }
+
+        macro_rules! test_bsdf {
+            ($name:ident: $material:expr, albedo: $albedo:expr, reciprocal: $reciprocal:expr) => {
+                mod $name {
+                    use super::*;
+
+                    #[test]
+                    fn sample_consistency() {
+                        check_sample_consistency(&$material);
+                    }
+
+                    #[test]
+                    fn chi_square() {
+                        check_chi_square(&$material);
+                    }
+
+                    #[test]
+                    fn white_furnace() {
+                        check_white_furnace(&$material, $albedo);
+                    }
+
+                    #[test]
+                    fn reciprocity() {
+                        if $reciprocal {
+                            check_reciprocity(&$material);
+                        }
+                    }
+                }
+            }
+        }
+
+        fn white() -> MaterialColour {
+            MaterialColour::Static(Colour::WHITE)
+        }
+
+        test_bsdf!(lambertian: Material::lambertian(white(), Colour::BLACK), albedo: Some(1.0), reciprocal: true);
+        test_bsdf!(oren_nayar: Material::oren_nayar(white(), 0.8), albedo: None, reciprocal: true);
+        test_bsdf!(mirror: Material::mirror(), albedo: Some(1.0), reciprocal: true);
+        test_bsdf!(plastic: Material::gloss(white(), 0.04, 0.0), albedo: Some(1.0), reciprocal: true);
+        test_bsdf!(metal: Material::gloss(white(), 0.9, 1.0), albedo: None, reciprocal: true);
+        test_bsdf!(cook_torrance_smooth: Material::cook_torrance(Colour::WHITE, MaterialScalar::Static(0.2)), albedo: None, reciprocal: true);
+        test_bsdf!(cook_torrance_rough: Material::cook_torrance(Colour::WHITE, MaterialScalar::Static(0.6)), albedo: None, reciprocal: true);
+        test_bsdf!(dielectric: Material::dielectric(1.5, Colour::WHITE), albedo: Some(1.0), reciprocal: true);
+        test_bsdf!(null: Material::null(), albedo: Some(1.0), reciprocal: true);
+        test_bsdf!(fresnel: Material::fresnel_combination(
+            Material::lambertian(white(), Colour::BLACK),
+            Material::cook_torrance(Colour::WHITE, MaterialScalar::Static(0.3)),
+            1.5,
+        ), albedo: None, reciprocal: false);
+        test_bsdf!(mix: Material::mix(
+            Material::oren_nayar(white(), 0.5),
+            Material::mix(Material::mirror(), Material::cook_torrance(Colour::WHITE, MaterialScalar::Static(0.4)), MixWeight::Constant(0.5)),
+            MixWeight::Constant(0.3),
+        ), albedo: None, reciprocal: true);
+    }
+}
@@ -19,6 +19,17 @@ impl Matrix3 {
     fn set(&mut self, r: usize, c: usize, v: f64) {
         self.components[r * 3 + c] = v;
     }
+
+    // For rotation matrices this is also the inverse.
+    pub fn transpose(&self) -> Matrix3 {
+        let mut out = Matrix3::zero();
+        for r in 0 .. 3 {
+            for c in 0 .. 3 {
+                out.set(c, r, self.get(r, c));
+            }
+        }
+        out
+    }
 }
 
 // Constructors.
@@ -27,6 +38,10 @@ impl Matrix3 {
         Matrix3{ components: [0.0; 9] }
     }
 
+    pub fn identity() -> Matrix3 {
+        Matrix3{ components: [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0] }
+    }
+
     pub fn rotation(yaw: f64, pitch: f64, roll: f64) -> Matrix3 {
         let m_pitch = Matrix3::rotation_x(pitch);
         let m_yaw = Matrix3::rotation_y(yaw);
@@ -0,0 +1,365 @@
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+use rand;
+use rand::Rng;
+
+use crate::colour::Colour;
+use crate::geom;
+use crate::geom::{AABB, Ray};
+use crate::matrix::Matrix3;
+use crate::spectrum::Wavelengths;
+use crate::vector::Vector3;
+use crate::volume::DensityGrid;
+
+#[derive(Clone, Debug)]
+pub enum Medium {
+    Homogeneous(HomogeneousMedium),
+    Grid(GridMedium),
+}
+
+// The result of sampling a distance along a ray travelling through a medium.
+#[derive(Clone, Copy, Debug)]
+pub enum MediumSample {
+    // The ray scattered inside the medium before reaching the next surface.
+    Scatter { distance: f64, weight: Colour },
+    // The ray made it through to the next surface (or off to infinity).
+    Pass { weight: Colour },
+}
+
+impl Medium {
+    pub fn homogeneous(absorption: Colour, scattering: Colour, anisotropy: f64) -> Medium {
+        Medium::Homogeneous(HomogeneousMedium::new(absorption, scattering, anisotropy))
+    }
+
+    pub fn sample_distance(&self, ray: Ray, max_distance: f64) -> MediumSample {
+        match self {
+            Medium::Homogeneous(m) => m.sample_distance(ray, max_distance),
+            Medium::Grid(m) => m.sample_distance(ray, max_distance),
+        }
+    }
+
+    pub fn transmittance(&self, ray: Ray, distance: f64) -> Colour {
+        match self {
+            Medium::Homogeneous(m) => m.transmittance(ray, distance),
+            Medium::Grid(m) => m.transmittance(ray, distance),
+        }
+    }
+
+    // In spectral mode, the coefficients at the given wavelengths.
+    pub fn upsample(&self, wavelengths: &Wavelengths) -> Medium {
+        match self {
+            Medium::Homogeneous(m) => Medium::Homogeneous(m.upsample(wavelengths)),
+            Medium::Grid(m) => Medium::Grid(m.upsample(wavelengths)),
+        }
+    }
+
+    pub fn phase(&self) -> &HenyeyGreenstein {
+        match self {
+            Medium::Homogeneous(m) => &m.phase,
+            Medium::Grid(m) => &m.phase,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HomogeneousMedium {
+    sigma_s: Colour,
+    sigma_t: Colour,
+    phase: HenyeyGreenstein,
+}
+
+impl HomogeneousMedium {
+    pub fn new(absorption: Colour, scattering: Colour, anisotropy: f64) -> HomogeneousMedium {
+        HomogeneousMedium {
+            sigma_s: scattering,
+            sigma_t: absorption + scattering,
+            phase: HenyeyGreenstein::new(anisotropy),
+        }
+    }
+
+    fn upsample(&self, wavelengths: &Wavelengths) -> HomogeneousMedium {
+        let sigma_s = wavelengths.upsample(self.sigma_s);
+        let sigma_a = wavelengths.upsample(self.sigma_t - self.sigma_s);
+        HomogeneousMedium { sigma_s, sigma_t: sigma_a + sigma_s, phase: self.phase }
+    }
+
+    fn sample_distance(&self, ray: Ray, max_distance: f64) -> MediumSample {
+        // Pick a channel at random and sample a distance proportional to its transmittance.
+        // The pdf is then the average over all three channels, so that channels with very
+        // different extinction coefficients don't blow up the variance.
+        let mut rng = rand::thread_rng();
+        let sigma = match rng.gen_range(0, 3) {
+            0 => self.sigma_t.r,
+            1 => self.sigma_t.g,
+            _ => self.sigma_t.b,
+        };
+
+        let u: f64 = rng.gen();
+        let distance = -(1.0 - u).ln() / sigma;
+
+        if distance < max_distance {
+            let tr = self.transmittance(ray, distance);
+            let density = self.sigma_t * tr;
+            let pdf = density.mean();
+            if pdf <= 0.0 {
+                return MediumSample::Pass { weight: Colour::BLACK };
+            }
+            MediumSample::Scatter { distance, weight: tr * self.sigma_s / pdf }
+        } else {
+            let tr = self.transmittance(ray, max_distance);
+            let pdf = tr.mean();
+            if pdf <= 0.0 {
+                return MediumSample::Pass { weight: Colour::BLACK };
+            }
+            MediumSample::Pass { weight: tr / pdf }
+        }
+    }
+
+    fn transmittance(&self, _ray: Ray, distance: f64) -> Colour {
+        beer_lambert(self.sigma_t, distance)
+    }
+}
+
+// A medium whose density varies through space according to a voxel grid.
+// The grid is centred on `translation`, and its longest side is `scale` long.
+#[derive(Clone, Debug)]
+pub struct GridMedium {
+    grid: Arc<DensityGrid>,
+    sigma_a: Colour,
+    sigma_s: Colour,
+    phase: HenyeyGreenstein,
+    translation: Vector3,
+    rotation: Matrix3,
+    inv_rotation: Matrix3,
+    voxels_per_unit: f64,
+    majorant: f64,
+}
+
+impl GridMedium {
+    pub fn new(
+        grid: Arc<DensityGrid>,
+        absorption: Colour,
+        scattering: Colour,
+        anisotropy: f64,
+        translation: Vector3,
+        rotation: Matrix3,
+        scale: f64,
+    ) -> GridMedium {
+        let longest_side = usize::max(grid.width, usize::max(grid.height, grid.depth)) as f64;
+        let majorant = grid.max_density * (absorption + scattering).max();
+        GridMedium {
+            grid,
+            sigma_a: absorption,
+            sigma_s: scattering,
+            phase: HenyeyGreenstein::new(anisotropy),
+            translation,
+            rotation,
+            inv_rotation: rotation.transpose(),
+            voxels_per_unit: longest_side / scale,
+            majorant,
+        }
+    }
+
+    fn upsample(&self, wavelengths: &Wavelengths) -> GridMedium {
+        let sigma_a = wavelengths.upsample(self.sigma_a);
+        let sigma_s = wavelengths.upsample(self.sigma_s);
+        GridMedium {
+            grid: self.grid.clone(),
+            sigma_a,
+            sigma_s,
+            majorant: self.grid.max_density * (sigma_a + sigma_s).max(),
+            ..*self
+        }
+    }
+
+    // World-space bounding box of the grid.
+    pub fn bounds(&self) -> AABB {
+        let half_size = Vector3::new(
+            self.grid.width as f64,
+            self.grid.height as f64,
+            self.grid.depth as f64,
+        ) / (2.0 * self.voxels_per_unit);
+
+        let mut min = Vector3::new(std::f64::MAX, std::f64::MAX, std::f64::MAX);
+        let mut max = Vector3::new(std::f64::MIN, std::f64::MIN, std::f64::MIN);
+        for &sx in &[-1.0, 1.0] {
+            for &sy in &[-1.0, 1.0] {
+                for &sz in &[-1.0, 1.0] {
+                    let corner = self.rotation * (half_size * Vector3::new(sx, sy, sz)) + self.translation;
+                    min = Vector3::componentwise_min(min, corner);
+                    max = Vector3::componentwise_max(max, corner);
+                }
+            }
+        }
+        AABB::new(min, max)
+    }
+
+    fn density(&self, p: Vector3) -> f64 {
+        let local = self.inv_rotation * (p - self.translation) * self.voxels_per_unit;
+        self.grid.density(
+            local.x + self.grid.width as f64 / 2.0,
+            local.y + self.grid.height as f64 / 2.0,
+            local.z + self.grid.depth as f64 / 2.0,
+        )
+    }
+
+    // Delta tracking against a constant majorant.
+    // At each tentative collision we pick absorption, scattering or a null collision with
+    // probability proportional to the (channel-averaged) coefficients, and weight by the
+    // per-channel ratio so that chromatic media stay unbiased.
+    fn sample_distance(&self, ray: Ray, max_distance: f64) -> MediumSample {
+        if self.majorant <= 0.0 {
+            return MediumSample::Pass { weight: Colour::WHITE };
+        }
+
+        let mut rng = rand::thread_rng();
+        let mut weight = Colour::WHITE;
+        let mut distance = 0.0;
+        loop {
+            distance -= (1.0 - rng.gen::<f64>()).ln() / self.majorant;
+            if distance >= max_distance {
+                return MediumSample::Pass { weight };
+            }
+
+            let density = self.density(ray.origin + ray.direction * distance);
+            let sigma_a = self.sigma_a * density;
+            let sigma_s = self.sigma_s * density;
+            let sigma_n = Colour::WHITE * self.majorant - sigma_a - sigma_s;
+
+            let p_absorb = sigma_a.mean() / self.majorant;
+            let p_scatter = sigma_s.mean() / self.majorant;
+
+            let xi: f64 = rng.gen();
+            if xi < p_absorb {
+                // Absorbed, nothing makes it back along this path.
+                return MediumSample::Pass { weight: Colour::BLACK };
+            } else if xi < p_absorb + p_scatter {
+                weight = weight * sigma_s / (self.majorant * p_scatter);
+                return MediumSample::Scatter { distance, weight };
+            } else {
+                let p_null = 1.0 - p_absorb - p_scatter;
+                weight = weight * sigma_n / (self.majorant * p_null);
+            }
+        }
+    }
+
+    // Ratio tracking.
+    fn transmittance(&self, ray: Ray, max_distance: f64) -> Colour {
+        if self.majorant <= 0.0 {
+            return Colour::WHITE;
+        }
+
+        let mut rng = rand::thread_rng();
+        let mut transmittance = Colour::WHITE;
+        let mut distance = 0.0;
+        loop {
+            distance -= (1.0 - rng.gen::<f64>()).ln() / self.majorant;
+            if distance >= max_distance {
+                return transmittance;
+            }
+
+            let sigma_t = (self.sigma_a + self.sigma_s) * self.density(ray.origin + ray.direction * distance);
+            transmittance = transmittance * (Colour::WHITE - sigma_t / self.majorant);
+
+            if transmittance.max() <= 0.0 {
+                return Colour::BLACK;
+            }
+        }
+    }
+}
+
+pub fn beer_lambert(sigma_t: Colour, distance: f64) -> Colour {
+    // Careful to avoid 0 * inf = NaN for channels which don't attenuate at all.
+    let tr = |sigma: f64| if sigma <= 0.0 { 1.0 } else { (-sigma * distance).exp() };
+    Colour::rgb(tr(sigma_t.r), tr(sigma_t.g), tr(sigma_t.b))
+}
+
+// The Henyey-Greenstein phase function.
+// g > 0 scatters forwards, g < 0 scatters backwards, and g = 0 is isotropic.
+#[derive(Clone, Copy, Debug)]
+pub struct HenyeyGreenstein {
+    g: f64,
+}
+
+impl HenyeyGreenstein {
+    pub fn new(g: f64) -> HenyeyGreenstein {
+        HenyeyGreenstein { g: f64::max(-0.99, f64::min(0.99, g)) }
+    }
+
+    // Directions here are both directions of travel, so cos_theta = 1 means the ray carries
+    // straight on.
+    pub fn evaluate(&self, dir_in: Vector3, dir_out: Vector3) -> f64 {
+        let g = self.g;
+        let cos_theta = dir_in.dot(dir_out);
+        let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+        (1.0 - g * g) / (4.0 * PI * denom * denom.sqrt())
+    }
+
+    // Sampled exactly proportional to the phase function, so the weight is always 1.
+    pub fn sample(&self, dir_in: Vector3) -> Vector3 {
+        let mut rng = rand::thread_rng();
+        let u: f64 = rng.gen();
+        let v: f64 = rng.gen();
+        let g = self.g;
+
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * u
+        } else {
+            let sqr = (1.0 - g * g) / (1.0 - g + 2.0 * g * u);
+            (1.0 + g * g - sqr * sqr) / (2.0 * g)
+        };
+        let sin_theta = f64::max(0.0, 1.0 - cos_theta * cos_theta).sqrt();
+        let phi = 2.0 * PI * v;
+
+        // y is up.
+        let local = Vector3::new(sin_theta * phi.cos(), cos_theta, sin_theta * phi.sin());
+        let (i, j, k) = dir_in.form_basis();
+        geom::switch_basis(local, i, j, k).normed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::medium::*;
+
+    #[test]
+    fn test_henyey_greenstein_normalized() {
+        // Integrate the phase function over the sphere numerically, it should come out to 1.
+        for &g in &[-0.7, 0.0, 0.3, 0.9] {
+            let hg = HenyeyGreenstein::new(g);
+            let dir_in = Vector3::new(0.0, 0.0, 1.0);
+            let n = 20000;
+            let mut total = 0.0;
+            for ix in 0 .. n {
+                let cos_theta = -1.0 + 2.0 * ((ix as f64) + 0.5) / (n as f64);
+                let dir_out = Vector3::new((1.0 - cos_theta * cos_theta).sqrt(), 0.0, cos_theta);
+                total += hg.evaluate(dir_in, dir_out) * 2.0 * PI * (2.0 / (n as f64));
+            }
+            assert!((total - 1.0).abs() < 1e-3, "g={} integrated to {}", g, total);
+        }
+    }
+
+    #[test]
+    fn test_henyey_greenstein_sample_mean_cosine() {
+        // The mean cosine of the scattering angle is exactly g.
+        let g = 0.6;
+        let hg = HenyeyGreenstein::new(g);
+        let dir_in = Vector3::new(0.0, 1.0, 0.0);
+        let n = 100_000;
+        let mean = (0 .. n).map(|_| hg.sample(dir_in).dot(dir_in)).sum::<f64>() / (n as f64);
+        assert!((mean - g).abs() < 0.01, "mean cosine {}", mean);
+    }
+
+    #[test]
+    fn test_beer_lambert() {
+        let tr = beer_lambert(Colour::rgb(0.0, 1.0, 2.0), 1.0);
+        assert_eq!(tr.r, 1.0);
+        assert!((tr.g - (-1f64).exp()).abs() < 1e-12);
+        assert!((tr.b - (-2f64).exp()).abs() < 1e-12);
+
+        let tr = beer_lambert(Colour::rgb(0.0, 1.0, 2.0), f64::INFINITY);
+        assert_eq!(tr.r, 1.0);
+        assert_eq!(tr.g, 0.0);
+    }
+}
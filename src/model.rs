@@ -1,10 +1,12 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use crate::colour::Colour;
 use crate::geom::Primitive;
-use crate::material::Material;
+use crate::material::{Material, MaterialScalar};
 use crate::obj;
 use crate::ply;
+use crate::texture::Texture;
 use crate::vector::Vector3;
 
 pub struct ModelLibrary {
@@ -102,7 +104,18 @@ impl ModelLibrary {
     }
 }
 
+// Fine surface detail, which tilts the shading normal without changing the geometry.
+#[derive(Clone, Debug)]
+pub enum BumpMap {
+    // Greyscale heights, with a multiplier for how bumpy the surface is.
+    Height(Arc<Texture>, f64),
+    // Tangent space normals, encoded as RGB.
+    Normal(Arc<Texture>),
+}
+
 pub struct Model {
+    // e.g. the object name in an OBJ file.
+    pub name: Option<String>,
     pub vertices: Vec<Vector3>,
     pub faces: Vec<(usize, usize, usize)>,
     pub face_normals: Vec<Vector3>,
@@ -110,6 +123,8 @@ pub struct Model {
     pub vertex_colours: Option<Vec<Colour>>,
     pub texture_coords: Option<Vec<(f64, f64)>>,
     pub material: Option<Material>,
+    pub opacity: Option<MaterialScalar>,
+    pub bump: Option<BumpMap>,
 }
 
 impl Model {
@@ -117,6 +132,7 @@ impl Model {
         let face_normals = Model::compute_face_normals(&vertices, &faces);
 
         Model{ 
+            name: None,
             vertices,
             faces,
             face_normals,
@@ -124,6 +140,8 @@ impl Model {
             vertex_colours: None,
             texture_coords: None,
             material: None,
+            opacity: None,
+            bump: None,
         }
     }
 
@@ -139,6 +157,36 @@ impl Model {
         self.material = Some(material);
     }
 
+    // Makes parts of the model transparent, e.g. the gaps between leaves.
+    pub fn attach_opacity(&mut self, opacity: MaterialScalar) {
+        self.opacity = Some(opacity);
+    }
+
+    pub fn attach_bump(&mut self, bump: BumpMap) {
+        self.bump = Some(bump);
+    }
+
+    pub fn opacity(&self, face_ix: usize, bx: f64, by: f64, bz: f64) -> f64 {
+        match self.opacity {
+            Some(ref opacity) => opacity.value(self.smooth_texture_coords(face_ix, bx, by, bz)),
+            None => 1.0,
+        }
+    }
+
+    pub fn smooth_texture_coords(&self, face_ix: usize, bx: f64, by: f64, bz: f64) -> (f64, f64) {
+        match self.texture_coords {
+            Some(ref texture_coords) => {
+                let (a, b, c) = self.faces[face_ix];
+                let (au, av) = texture_coords[a];
+                let (bu, bv) = texture_coords[b];
+                let (cu, cv) = texture_coords[c];
+
+                (au * bx + bu * by + cu * bz, av * bx + bv * by + cv * bz)
+            },
+            None => (0.0, 0.0),
+        }
+    }
+
     pub fn smooth_normal(&self, face_ix: usize, bx: f64, by: f64, bz: f64) -> Vector3 {
         match self.vertex_normals {
             Some(ref vertex_normals) => {
@@ -155,6 +203,75 @@ impl Model {
         }
     }
 
+    // Tilts the given shading normal according to the bump map, if there is one.
+    pub fn bump_normal(&self, face_ix: usize, bx: f64, by: f64, bz: f64, normal: Vector3) -> Vector3 {
+        let bump = match self.bump {
+            Some(ref bump) => bump,
+            None => return normal,
+        };
+
+        let (tangent, bitangent) = match self.tangent_frame(face_ix, normal) {
+            Some(frame) => frame,
+            None => return normal,
+        };
+
+        let (u, v) = self.smooth_texture_coords(face_ix, bx, by, bz);
+        let bumped = match bump {
+            BumpMap::Normal(texture) => {
+                let c = texture.colour(u, v);
+                tangent * (c.r * 2.0 - 1.0) + bitangent * (c.g * 2.0 - 1.0) + normal * (c.b * 2.0 - 1.0)
+            },
+            BumpMap::Height(texture, multiplier) => {
+                // Slope of the height field, measured over one texel in each direction.
+                let du = 1.0 / texture.width as f64;
+                let dv = 1.0 / texture.height as f64;
+                let h = texture.scalar(u, v);
+                let dh_du = (texture.scalar(u + du, v) - h) * multiplier;
+                let dh_dv = (texture.scalar(u, v + dv) - h) * multiplier;
+                normal - tangent * dh_du - bitangent * dh_dv
+            },
+        };
+
+        if bumped.is_nan() || bumped.dot(normal) <= 0.0 {
+            normal
+        } else {
+            bumped.normed()
+        }
+    }
+
+    // Unit vectors along the surface in the direction of increasing u and v, made perpendicular
+    // to the normal.
+    fn tangent_frame(&self, face_ix: usize, normal: Vector3) -> Option<(Vector3, Vector3)> {
+        let texture_coords = self.texture_coords.as_ref()?;
+        let (a, b, c) = self.faces[face_ix];
+
+        let e1 = self.vertices[b] - self.vertices[a];
+        let e2 = self.vertices[c] - self.vertices[a];
+        let (du1, dv1) = (texture_coords[b].0 - texture_coords[a].0, texture_coords[b].1 - texture_coords[a].1);
+        let (du2, dv2) = (texture_coords[c].0 - texture_coords[a].0, texture_coords[c].1 - texture_coords[a].1);
+
+        let det = du1 * dv2 - du2 * dv1;
+        if det.abs() < 1e-12 {
+            return None;
+        }
+
+        let tangent = (e1 * dv2 - e2 * dv1) / det;
+        let bitangent = (e2 * du1 - e1 * du2) / det;
+
+        // Gram-Schmidt, keeping the handedness of the texture mapping.
+        let tangent = (tangent - normal * normal.dot(tangent)).normed();
+        let mut orthogonal_bitangent = normal.cross(tangent);
+        if orthogonal_bitangent.dot(bitangent) < 0.0 {
+            orthogonal_bitangent = orthogonal_bitangent * -1;
+        }
+
+        if tangent.is_nan() || orthogonal_bitangent.is_nan() {
+            None
+        } else {
+            Some((tangent, orthogonal_bitangent))
+        }
+    }
+
     pub fn smooth_colour(&self, face_ix: usize, bx: f64, by: f64, bz: f64) -> Colour {
         match self.vertex_colours {
             Some(ref vertex_colours) => {
@@ -1,15 +1,26 @@
+use std::path::Path;
+use std::sync::Arc;
+
 use tobj;
 
 use crate::colour::Colour;
-use crate::material::{Material, MaterialColour};
-use crate::model::Model;
+use crate::material::{Material, MaterialColour, MaterialScalar, MixWeight};
+use crate::model::{BumpMap, Model};
+use crate::texture::Texture;
 use crate::vector::Vector3;
 
 pub fn load_obj_file(filename: &str) -> Vec<Model> {
     let (obj_models, obj_materials) = tobj::load_obj(filename, true).expect("Failed to load obj file");
 
-    let materials: Vec<Material> = obj_materials.iter()
-        .map(|m| convert_material(m))
+    // Texture paths in the MTL file are relative to the OBJ file.
+    let base_dir = Path::new(filename).parent().unwrap_or(Path::new("."));
+
+    let materials: Vec<ObjMaterial> = obj_materials.iter()
+        .map(|m| ObjMaterial {
+            material: convert_material(m, base_dir),
+            opacity: convert_opacity(m, base_dir),
+            bump: convert_bump(m, base_dir),
+        })
         .collect();
 
     println!("Loaded {} materials", materials.len());
@@ -21,12 +32,165 @@ pub fn load_obj_file(filename: &str) -> Vec<Model> {
     models
 }
 
-fn convert_material(obj_material: &tobj::Material) -> Material {
-    // TODO: Flesh out.
-    Material::lambertian(MaterialColour::Static(array_to_colour(obj_material.diffuse)), Colour::BLACK)
+// Everything we take from a material in the MTL file.
+struct ObjMaterial {
+    material: Material,
+    opacity: Option<MaterialScalar>,
+    bump: Option<BumpMap>,
+}
+
+// See http://paulbourke.net/dataformats/mtl/ for what everything means.
+// Since everything is ray traced anyway, the illumination models boil down to:
+//  - 0, 1 and 10: diffuse only.
+//  - 2, 3, 5 and 8: diffuse with a specular layer on top.
+//  - 4, 6, 7 and 9: glass.
+// Without an illumination model we guess from the other properties.
+fn convert_material(obj_material: &tobj::Material, base_dir: &Path) -> Material {
+    let specular = array_to_colour(obj_material.specular);
+    let has_specular = specular.max() > 0.0;
+
+    match obj_material.illumination_model {
+        Some(4) | Some(6) | Some(7) | Some(9) => {
+            convert_dielectric(obj_material)
+        },
+        Some(0) | Some(1) | Some(10) => {
+            convert_diffuse(obj_material, base_dir)
+        },
+        Some(_) | None if has_specular => {
+            // The specular layer sits on top of the diffuse, and reflects more at grazing angles.
+            let refractive_index = if obj_material.optical_density > 1.0 {
+                obj_material.optical_density as f64
+            } else {
+                1.5
+            };
+
+            Material::mix(
+                convert_diffuse(obj_material, base_dir),
+                Material::cook_torrance(specular, convert_roughness(obj_material, base_dir)),
+                MixWeight::fresnel(refractive_index),
+            )
+        },
+        _ => convert_diffuse(obj_material, base_dir),
+    }
+}
+
+// From `Kd`, `map_Kd` and `Ke`.
+fn convert_diffuse(obj_material: &tobj::Material, base_dir: &Path) -> Material {
+    let albedo = if !obj_material.diffuse_texture.is_empty() {
+        let texture = Texture::load(&texture_path(&obj_material.diffuse_texture, base_dir).0);
+        MaterialColour::Texture(Arc::new(texture))
+    } else {
+        MaterialColour::Static(array_to_colour(obj_material.diffuse))
+    };
+
+    let emittance = obj_material.unknown_param.get("Ke")
+        .map(|ke| parse_colour(ke))
+        .unwrap_or(Colour::BLACK);
+
+    Material::lambertian(albedo, emittance)
+}
+
+// From `Ni` and `Tf`.
+fn convert_dielectric(obj_material: &tobj::Material) -> Material {
+    let refractive_index = if obj_material.optical_density > 1.0 {
+        obj_material.optical_density as f64
+    } else {
+        println!("[WARN] Glass material '{}' has no refractive index, assuming 1.5", obj_material.name);
+        1.5
+    };
+
+    let tint = obj_material.unknown_param.get("Tf")
+        .map(|tf| parse_colour(tf))
+        .unwrap_or(Colour::WHITE);
+
+    Material::dielectric(refractive_index, tint)
+}
+
+// From `Ns` and `map_Ns`.
+// `Ns` is a Phong exponent from 0 to 1000, which we convert to Beckmann roughness.
+// `map_Ns` scales `Ns` across the surface.
+fn convert_roughness(obj_material: &tobj::Material, base_dir: &Path) -> MaterialScalar {
+    let shininess = obj_material.shininess as f64;
+    let roughness = move |ns: f64| (2.0 / (f64::max(0.0, ns) + 2.0)).sqrt();
+
+    if !obj_material.shininess_texture.is_empty() {
+        let texture = Texture::load_linear(&texture_path(&obj_material.shininess_texture, base_dir).0);
+        MaterialScalar::Texture(Arc::new(texture.map(|x| roughness(x * shininess))))
+    } else {
+        MaterialScalar::Static(roughness(shininess))
+    }
+}
+
+// From `d` and `map_d`.
+// Glass uses `d` to mean how see-through it is, which is handled by refraction instead.
+fn convert_opacity(obj_material: &tobj::Material, base_dir: &Path) -> Option<MaterialScalar> {
+    let is_glass = matches!(obj_material.illumination_model, Some(4) | Some(6) | Some(7) | Some(9));
+
+    if !obj_material.dissolve_texture.is_empty() {
+        let texture = Texture::load(&texture_path(&obj_material.dissolve_texture, base_dir).0);
+        Some(MaterialScalar::Texture(Arc::new(texture)))
+    } else if obj_material.dissolve < 1.0 && !is_glass {
+        Some(MaterialScalar::Static(obj_material.dissolve as f64))
+    } else {
+        None
+    }
+}
+
+// From `map_Bump` or `bump`.
+// These are meant to be height maps, but normal maps often end up here too.  Normal maps are
+// mostly blue, so we can tell them apart.
+fn convert_bump(obj_material: &tobj::Material, base_dir: &Path) -> Option<BumpMap> {
+    if obj_material.normal_texture.is_empty() {
+        return None;
+    }
+
+    let (path, multiplier) = texture_path(&obj_material.normal_texture, base_dir);
+    let texture = Texture::load_linear(&path);
+    let mean = texture.mean_colour();
+    if mean.b > 0.6 && (mean.r - 0.5).abs() < 0.15 && (mean.g - 0.5).abs() < 0.15 {
+        println!("Treating '{}' as a normal map", path);
+        Some(BumpMap::Normal(Arc::new(texture)))
+    } else {
+        Some(BumpMap::Height(Arc::new(texture), multiplier))
+    }
+}
+
+// Texture statements can have options before the filename, e.g. `map_Bump -bm 0.5 bump.png`.
+// Returns the path to the texture relative to `base_dir`, and the bump multiplier.
+fn texture_path(statement: &str, base_dir: &Path) -> (String, f64) {
+    let mut tokens = statement.split_whitespace().peekable();
+    let mut multiplier = 1.0;
+
+    while let Some(option) = tokens.peek().filter(|t| t.starts_with('-')).map(|t| t.to_string()) {
+        tokens.next();
+        let num_args = match option.as_str() {
+            "-o" | "-s" | "-t" => 3,
+            "-mm" => 2,
+            _ => 1,
+        };
+
+        for ix in 0 .. num_args {
+            // Numeric arguments after the first are optional.
+            let is_arg = match tokens.peek() {
+                Some(t) => ix == 0 || t.parse::<f64>().is_ok(),
+                None => false,
+            };
+            if !is_arg {
+                break;
+            }
+            let arg = tokens.next().expect("Peeked");
+            if option == "-bm" {
+                multiplier = arg.parse().unwrap_or(1.0);
+            }
+        }
+    }
+
+    let filename = tokens.collect::<Vec<&str>>().join(" ");
+    let path = base_dir.join(filename);
+    (path.to_str().expect("Texture path is valid unicode").to_string(), multiplier)
 }
 
-fn convert_model(obj_model: &tobj::Model, materials: &Vec<Material>) -> Model {
+fn convert_model(obj_model: &tobj::Model, materials: &Vec<ObjMaterial>) -> Model {
     let vertices: Vec<Vector3> = obj_model.mesh.positions
         .chunks_exact(3)
         .map(|coords| {
@@ -39,9 +203,10 @@ fn convert_model(obj_model: &tobj::Model, materials: &Vec<Material>) -> Model {
             (indices[0] as usize, indices[1] as usize, indices[2] as usize)
         }).collect();
 
-    println!("Loaded model with {} vertices and {} faces", vertices.len(), faces.len());
+    println!("Loaded model '{}' with {} vertices and {} faces", obj_model.name, vertices.len(), faces.len());
 
     let mut model = Model::new(vertices, faces);
+    model.name = Some(obj_model.name.clone());
 
     let texcoords = &obj_model.mesh.texcoords;
     if texcoords.len() > 0 {
@@ -58,7 +223,24 @@ fn convert_model(obj_model: &tobj::Model, materials: &Vec<Material>) -> Model {
     match obj_model.mesh.material_id {
         Some(mat) => {
             println!("Model has associated material");
-            model.attach_material(materials[mat]);
+            let obj_material = &materials[mat];
+            model.attach_material(obj_material.material.clone());
+
+            match obj_material.opacity {
+                Some(MaterialScalar::Texture(_)) if model.texture_coords.is_none() => {
+                    println!("[WARN] Ignoring opacity map on model without texture coordinates");
+                },
+                Some(ref opacity) => model.attach_opacity(opacity.clone()),
+                None => (),
+            }
+
+            match obj_material.bump {
+                Some(_) if model.texture_coords.is_none() => {
+                    println!("[WARN] Ignoring bump map on model without texture coordinates");
+                },
+                Some(ref bump) => model.attach_bump(bump.clone()),
+                None => (),
+            }
         },
         None => (),
     }
@@ -69,3 +251,31 @@ fn convert_model(obj_model: &tobj::Model, materials: &Vec<Material>) -> Model {
 fn array_to_colour(rgb: [f32; 3]) -> Colour {
     Colour::rgb(rgb[0] as f64, rgb[1] as f64, rgb[2] as f64)
 }
+
+// e.g. "1.0 0.5 0.5".  A single value means grey.
+fn parse_colour(s: &str) -> Colour {
+    let values: Vec<f64> = s.split_whitespace().filter_map(|x| x.parse().ok()).collect();
+    match values.as_slice() {
+        [r, g, b, ..] => Colour::rgb(*r, *g, *b),
+        [x] => Colour::rgb(*x, *x, *x),
+        _ => {
+            println!("[WARN] Could not parse colour '{}'", s);
+            Colour::BLACK
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::obj::texture_path;
+
+    #[test]
+    fn test_texture_path() {
+        let base_dir = Path::new("models");
+        assert_eq!(texture_path("wood.png", base_dir), ("models/wood.png".to_string(), 1.0));
+        assert_eq!(texture_path("-bm 0.5 bump.png", base_dir), ("models/bump.png".to_string(), 0.5));
+        assert_eq!(texture_path("-s 2 2 -clamp on my texture.png", base_dir), ("models/my texture.png".to_string(), 1.0));
+    }
+}
@@ -2,6 +2,7 @@ use std::ops;
 
 use crate::camera::Image;
 use crate::colour::Colour;
+use crate::spectrum;
 
 struct MeanVec<T> {
     sums: Vec<T>,
@@ -34,15 +35,24 @@ pub struct Estimator {
     width: usize,
     height: usize,
     preview_grid_size: usize,
+    // In spectral mode samples are XYZ, and are only converted to RGB when rendering.
+    spectral: bool,
     means: MeanVec<Colour>,
+    // Light traced straight to the camera lands on any pixel, so is summed separately, and
+    // averaged over every light path traced rather than each pixel's own samples.
+    splats: Vec<Colour>,
+    light_paths: u64,
 }
 
 impl Estimator {
-    pub fn new(width: usize, height: usize, preview_grid_size: usize) -> Estimator {
+    pub fn new(width: usize, height: usize, preview_grid_size: usize, spectral: bool) -> Estimator {
         Estimator {
             width, height,
             preview_grid_size,
+            spectral,
             means: MeanVec::new(width * height, Colour::BLACK),
+            splats: vec![Colour::BLACK; width * height],
+            light_paths: 0,
         }
     }
 
@@ -50,6 +60,29 @@ impl Estimator {
         self.means.update(x + y * self.width, colour);
     }
 
+    pub fn add_splat(&mut self, x: usize, y: usize, colour: Colour) {
+        self.splats[x + y * self.width] += colour;
+    }
+
+    // Light paths traced, whether or not they reached the camera.
+    pub fn add_light_paths(&mut self, count: u64) {
+        self.light_paths += count;
+    }
+
+    // The pixel at `ix`, using the samples from `mean_ix`.
+    fn get(&self, ix: usize, mean_ix: usize) -> Colour {
+        let mut value = self.means.get(mean_ix);
+        if self.light_paths > 0 {
+            value += self.splats[ix] / self.light_paths as f64;
+        }
+
+        if self.spectral {
+            spectrum::xyz_to_rgb(value)
+        } else {
+            value
+        }
+    }
+
     pub fn render(&self) -> Image {
         let mut buffer = Vec::with_capacity(self.width * self.height);
         for ix in 0 .. self.width * self.height {
@@ -60,15 +93,15 @@ impl Estimator {
                 let grid_size = self.preview_grid_size;
 
                 if x % grid_size == 0 && y % grid_size == 0 {
-                    buffer.push(self.means.get(ix));
+                    buffer.push(self.get(ix, ix));
                 } else {
                     let grid_x = x - (x % grid_size);
                     let grid_y = y - (y % grid_size);
                     let grid_ix = grid_x + grid_y * self.width;
-                    buffer.push(self.means.get(grid_ix));
+                    buffer.push(self.get(ix, grid_ix));
                 }
             } else {
-                buffer.push(self.means.get(ix));
+                buffer.push(self.get(ix, ix));
             }
         }
         Image {
@@ -78,3 +111,26 @@ impl Estimator {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::colour::Colour;
+    use crate::pixels::*;
+
+    #[test]
+    fn test_splats_averaged_over_light_paths() {
+        let mut estimator = Estimator::new(2, 1, 1, false);
+        estimator.update_pixel(0, 0, Colour::rgb(1.0, 1.0, 1.0));
+        estimator.update_pixel(0, 0, Colour::rgb(3.0, 3.0, 3.0));
+        estimator.update_pixel(1, 0, Colour::BLACK);
+
+        // Light paths can land anywhere, so splats are over all the paths, not the pixel's samples.
+        estimator.add_splat(1, 0, Colour::rgb(2.0, 2.0, 2.0));
+        estimator.add_splat(1, 0, Colour::rgb(2.0, 2.0, 2.0));
+        estimator.add_light_paths(8);
+
+        let image = estimator.render();
+        assert_eq!(image.pixels[0].g, 2.0);
+        assert_eq!(image.pixels[1].g, 0.5);
+    }
+}
@@ -15,6 +15,7 @@ const PREVIEW_GRID_SIZE: usize = 6;
 pub struct Renderer {
     width: u32,
     height: u32,
+    spectral: bool,
     estimator: Estimator,
     epoch: u64,
     pool: ThreadPool,
@@ -32,7 +33,7 @@ pub struct Renderer {
 
 impl Renderer {
     pub fn new(camera: Camera, scene: Arc<Scene>, num_workers: usize) -> Renderer {
-        let estimator = Estimator::new(camera.width as usize, camera.height as usize, PREVIEW_GRID_SIZE);
+        let estimator = Estimator::new(camera.width as usize, camera.height as usize, PREVIEW_GRID_SIZE, scene.spectral);
         let pool = ThreadPool::new(num_workers);
 
         let (request_tx, request_rx) = channel::bounded::<worker::RenderRequest>(200);
@@ -56,6 +57,7 @@ impl Renderer {
         Renderer{
             width: camera.width,
             height: camera.height,
+            spectral: scene.spectral,
             estimator,
             epoch: 0,
             pool,
@@ -102,6 +104,10 @@ impl Renderer {
             result.samples.iter().for_each(|(x, y, colour)| {
                 self.estimator.update_pixel(*x as usize, *y as usize, *colour);
             });
+            result.splats.iter().for_each(|(x, y, colour)| {
+                self.estimator.add_splat(*x as usize, *y as usize, *colour);
+            });
+            self.estimator.add_light_paths(result.light_paths);
         });
 
         if self.pool.panic_count() > 0 {
@@ -144,7 +150,7 @@ impl Renderer {
         self.block_num = 0;
         self.num_rays_cast = 0;
         self.quick_render = true;
-        self.estimator = Estimator::new(self.width as usize, self.height as usize, PREVIEW_GRID_SIZE);
+        self.estimator = Estimator::new(self.width as usize, self.height as usize, PREVIEW_GRID_SIZE, self.spectral);
         self.epoch += 1;
         self.epoch
     }
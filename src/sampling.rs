@@ -264,6 +264,171 @@ impl DiskSampler for CorrelatedMultiJitteredSampler {
     }
 }
 
+// Piecewise constant distribution over [0, 1), with one bucket per value of `func`.
+#[derive(Clone, Debug)]
+pub struct Distribution1D {
+    func: Vec<f64>,
+    cdf: Vec<f64>,
+    integral: f64,
+}
+
+impl Distribution1D {
+    pub fn new(func: Vec<f64>) -> Distribution1D {
+        let n = func.len();
+        let mut cdf = vec![0.0; n + 1];
+        for ix in 0 .. n {
+            cdf[ix + 1] = cdf[ix] + func[ix] / n as f64;
+        }
+        let integral = cdf[n];
+
+        // Nothing to go on, so fall back to uniform.
+        if integral > 0.0 {
+            for c in cdf.iter_mut() {
+                *c /= integral;
+            }
+        } else {
+            for (ix, c) in cdf.iter_mut().enumerate() {
+                *c = ix as f64 / n as f64;
+            }
+        }
+
+        Distribution1D { func, cdf, integral }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    pub fn integral(&self) -> f64 {
+        self.integral
+    }
+
+    // Returns (x, pdf, bucket index) for a uniform sample `u`.
+    pub fn sample(&self, u: f64) -> (f64, f64, usize) {
+        // Last bucket whose cdf is <= u.
+        let n = self.count();
+        let ix = match self.cdf.binary_search_by(|c| c.partial_cmp(&u).unwrap()) {
+            Ok(ix) => ix,
+            Err(ix) => ix - 1,
+        }.min(n - 1);
+
+        let width = self.cdf[ix + 1] - self.cdf[ix];
+        let offset = if width > 0.0 { (u - self.cdf[ix]) / width } else { 0.0 };
+        let x = (ix as f64 + offset) / n as f64;
+        (x, self.pdf(ix), ix)
+    }
+
+    // As `sample`, but only landing in the buckets from `from` to `to` inclusive.  Returns
+    // (x, bucket index).
+    pub fn sample_range(&self, u: f64, from: usize, to: usize) -> (f64, usize) {
+        let target = self.cdf[from] + u * (self.cdf[to + 1] - self.cdf[from]);
+        let ix = from + match self.cdf[from ..= to].binary_search_by(|c| c.partial_cmp(&target).unwrap()) {
+            Ok(ix) => ix,
+            Err(ix) => ix - 1,
+        };
+
+        let width = self.cdf[ix + 1] - self.cdf[ix];
+        let offset = if width > 0.0 { (target - self.cdf[ix]) / width } else { 0.0 };
+        ((ix as f64 + offset) / self.count() as f64, ix)
+    }
+
+    // Integral over the buckets from `from` to `to` inclusive.
+    pub fn range_integral(&self, from: usize, to: usize) -> f64 {
+        (self.cdf[to + 1] - self.cdf[from]) * self.integral
+    }
+
+    // Chance of `sample` landing in the given bucket.
+    pub fn probability(&self, ix: usize) -> f64 {
+        self.pdf(ix) / self.count() as f64
+    }
+
+    // Density in [0, 1) of the given bucket.
+    pub fn pdf(&self, ix: usize) -> f64 {
+        if self.integral > 0.0 {
+            self.func[ix] / self.integral
+        } else {
+            1.0
+        }
+    }
+}
+
+// Piecewise constant distribution over [0, 1)^2, from a row-major grid of values.
+// Picks a row from the marginal distribution, then a column from that row.
+#[derive(Clone, Debug)]
+pub struct Distribution2D {
+    rows: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    pub fn new(func: &[f64], width: usize, height: usize) -> Distribution2D {
+        let rows: Vec<Distribution1D> = func.chunks(width)
+            .take(height)
+            .map(|row| Distribution1D::new(row.to_vec()))
+            .collect();
+        let marginal = Distribution1D::new(rows.iter().map(|r| r.integral()).collect());
+        Distribution2D { rows, marginal }
+    }
+
+    // Returns ((x, y), pdf).
+    pub fn sample(&self, u: f64, v: f64) -> ((f64, f64), f64) {
+        let (y, pdf_y, row) = self.marginal.sample(v);
+        let (x, pdf_x, _) = self.rows[row].sample(u);
+        ((x, y), pdf_x * pdf_y)
+    }
+
+    pub fn pdf(&self, x: f64, y: f64) -> f64 {
+        let (ix, iy) = self.cell(x, y);
+        self.marginal.pdf(iy) * self.rows[iy].pdf(ix)
+    }
+
+    // Number of (columns, rows).
+    pub fn size(&self) -> (usize, usize) {
+        (self.rows[0].count(), self.rows.len())
+    }
+
+    fn cell(&self, x: f64, y: f64) -> (usize, usize) {
+        let (width, height) = self.size();
+        (((x * width as f64) as usize).min(width - 1), ((y * height as f64) as usize).min(height - 1))
+    }
+
+    // As `sample`, but only within some of the cells, given as inclusive ranges of
+    // (column, row, last column, last row) which don't overlap.  None if they're all 0.
+    pub fn sample_within(&self, cells: &[(usize, usize, usize, usize)], u: f64, v: f64) -> Option<((f64, f64), f64)> {
+        // Pick a row from any of the ranges, then a column from that row.
+        let mut remaining = v * self.integral_within(cells) * self.rows.len() as f64;
+        for &(x0, y0, x1, y1) in cells {
+            for iy in y0 ..= y1 {
+                let weight = self.rows[iy].range_integral(x0, x1);
+                if remaining < weight {
+                    let y = (iy as f64 + remaining / weight) / self.rows.len() as f64;
+                    let (x, _) = self.rows[iy].sample_range(u, x0, x1);
+                    return Some(((x, y), self.pdf_within(cells, x, y)));
+                }
+                remaining -= weight;
+            }
+        }
+        None
+    }
+
+    pub fn pdf_within(&self, cells: &[(usize, usize, usize, usize)], x: f64, y: f64) -> f64 {
+        let (ix, iy) = self.cell(x, y);
+        let inside = cells.iter().any(|&(x0, y0, x1, y1)| x0 <= ix && ix <= x1 && y0 <= iy && iy <= y1);
+        let integral = self.integral_within(cells);
+        if !inside || integral <= 0.0 {
+            return 0.0;
+        }
+        self.pdf(x, y) * self.marginal.integral() / integral
+    }
+
+    fn integral_within(&self, cells: &[(usize, usize, usize, usize)]) -> f64 {
+        let total: f64 = cells.iter()
+            .map(|&(x0, y0, x1, y1)| (y0 ..= y1).map(|iy| self.rows[iy].range_integral(x0, x1)).sum::<f64>())
+            .sum();
+        total / self.rows.len() as f64
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::sampling::*;
@@ -306,4 +471,41 @@ mod test {
     test_sampler!(test_uniform_square, UniformSampler, Square, is_in_unit_square);
     test_sampler!(test_cmj_disk, CorrelatedMultiJitteredSampler, Disk, is_in_unit_disk);
     test_sampler!(test_cmj_square, CorrelatedMultiJitteredSampler, Square, is_in_unit_square);
+
+    #[test]
+    fn test_distribution_2d() {
+        let func = vec![
+            0.0, 1.0, 2.0,
+            3.0, 0.0, 6.0,
+        ];
+        let distribution = Distribution2D::new(&func, 3, 2);
+
+        // Densities average to 1 over the square.
+        let total: f64 = (0 .. 6).map(|ix| distribution.pdf((ix % 3) as f64 / 3.0, (ix / 3) as f64 / 2.0)).sum();
+        assert!((total / 6.0 - 1.0).abs() < 1e-9);
+        assert!((distribution.pdf(0.9, 0.9) - 6.0 / 2.0).abs() < 1e-9);
+
+        // Samples land in proportion to the function, and agree with the pdf.
+        let mut counts = [0; 6];
+        for (u, v) in CorrelatedMultiJitteredSampler::new(7, 100, 100).pattern::<Square>() {
+            let ((x, y), pdf) = distribution.sample(u, v);
+            assert!(is_in_unit_square(x, y));
+            assert!((pdf - distribution.pdf(x, y)).abs() < 1e-9);
+            counts[(y * 2.0) as usize * 3 + (x * 3.0) as usize] += 1;
+        }
+        for (count, f) in counts.iter().zip(func.iter()) {
+            assert!((*count as f64 / 10000.0 - f / 12.0).abs() < 0.01, "{:?}", counts);
+        }
+
+        // Just the last two columns, which hold 3 / 4 of the function.
+        let cells = [(1, 0, 2, 1)];
+        assert_eq!(distribution.pdf_within(&cells, 0.1, 0.9), 0.0);
+        assert!((distribution.pdf_within(&cells, 0.9, 0.9) - 6.0 / 2.0 * 4.0 / 3.0).abs() < 1e-9);
+        for (u, v) in CorrelatedMultiJitteredSampler::new(7, 10, 10).pattern::<Square>() {
+            let ((x, y), pdf) = distribution.sample_within(&cells, u, v).unwrap();
+            assert!(x >= 1.0 / 3.0 && is_in_unit_square(x, y));
+            assert!((pdf - distribution.pdf_within(&cells, x, y)).abs() < 1e-9);
+        }
+        assert!(distribution.sample_within(&[(0, 0, 0, 0)], 0.5, 0.5).is_none());
+    }
 }
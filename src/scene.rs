@@ -1,13 +1,23 @@
+use std::collections::HashSet;
 use std::f64::consts::PI;
+use std::sync::Arc;
 
 use rand;
 use rand::Rng;
 
 use crate::bvh::{construct_bvh_aac, BVH};
 use crate::colour::Colour;
-use crate::geom::{Collision, CollisionMetadata, Geometry, Primitive, Ray};
+use crate::geom;
+use crate::geom::{AABB, BoundedVolume, Collision, CollisionMetadata, Geometry, Primitive, Ray};
+use crate::ies::IesProfile;
+use crate::light_tree::{LightBounds, LightTree};
 use crate::material::Material;
+use crate::matrix::Matrix3;
+use crate::medium::Medium;
 use crate::model::ModelLibrary;
+use crate::sampling::{Distribution1D, Distribution2D};
+use crate::sky::PreethamSky;
+use crate::texture::Texture;
 use crate::vector::Vector3;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -23,7 +33,7 @@ pub enum Entity {
 }
 
 impl Entity {
-    pub fn id(self) -> EntityID {
+    pub fn id(&self) -> EntityID {
         match self {
             Entity::Object(o) => EntityID::Object(o.id),
             Entity::Light(l) => EntityID::Light(l.id),
@@ -36,6 +46,8 @@ pub struct Object {
     pub id: usize,
     pub geometry: Geometry,
     pub material: Material,
+    // The medium filling the inside of the object, if it is closed.
+    pub medium: Option<Medium>,
 }
 
 #[derive(Clone, Debug)]
@@ -44,6 +56,10 @@ pub struct Light {
     pub geometry: LightGeometry,
     pub colour: Colour,
     pub intensity: f64,
+    // How the intensity varies with direction.  Only point and spot lights can have one.
+    pub profile: Option<LightProfile>,
+    // Which objects the light lights and is blocked by, if not all of them.
+    pub linking: Option<Arc<LightLinking>>,
 }
 
 impl Light {
@@ -51,10 +67,292 @@ impl Light {
         EntityID::Light(self.id)
     }
 
-    pub fn sample(&self, from: Vector3) -> (Vector3, f64) {
+    // Returns (direction from the light to `from`, distance, inv_pdf)
+    // For spot lights and lights with a profile, inv_pdf also includes how much the light falls
+    // off in that direction.
+    pub fn sample(&self, from: Vector3) -> (Vector3, f64, f64) {
         match self.geometry {
-            LightGeometry::Point(v) => (v, 1.0),
-            LightGeometry::Area(p) => p.sample(from),
+            LightGeometry::Point(v) => {
+                let out_vec = from - v;
+                let distance_sq = out_vec.magnitude();
+                let direction = out_vec.normed();
+                (direction, distance_sq.sqrt(), self.profile_factor(direction) / distance_sq)
+            },
+            LightGeometry::Spot(spot) => {
+                let out_vec = from - spot.position;
+                let distance_sq = out_vec.magnitude();
+                let direction = out_vec.normed();
+                let falloff = spot.falloff(direction) * self.profile_factor(direction);
+                (direction, distance_sq.sqrt(), falloff / distance_sq)
+            },
+            LightGeometry::Distant(distant) => {
+                let to_light = distant.sample_direction();
+                (to_light * -1, f64::INFINITY, distant.solid_angle())
+            },
+            LightGeometry::Area(area) => area.sample(from),
+        }
+    }
+
+    fn profile_factor(&self, direction: Vector3) -> f64 {
+        match &self.profile {
+            Some(profile) => profile.evaluate(direction),
+            None => 1.0,
+        }
+    }
+
+    // Roughly how much light it gives off in total, for deciding how often to sample it.
+    // Distant lights would light the whole scene, which fits in a sphere of `scene_radius`.
+    pub fn power(&self, scene_radius: f64) -> f64 {
+        self.colour.luminance() * self.intensity * self.power_per_intensity(scene_radius)
+    }
+
+    // Total power given off with an intensity of 1.
+    pub fn power_per_intensity(&self, scene_radius: f64) -> f64 {
+        match (&self.geometry, &self.profile) {
+            // Over the whole sphere of directions.
+            (LightGeometry::Point(_), None) => 4.0 * PI,
+            (LightGeometry::Point(_), Some(profile)) => profile.integrate(|_| 1.0),
+            (LightGeometry::Spot(spot), None) => spot.solid_angle(),
+            (LightGeometry::Spot(spot), Some(profile)) => profile.integrate(|direction| spot.falloff(direction)),
+            (LightGeometry::Distant(distant), _) => distant.solid_angle() * PI * scene_radius * scene_radius,
+            // A lambertian emitter gives off π times its radiance per unit area.
+            (LightGeometry::Area(area), _) => PI * area.shape.area() * if area.two_sided { 2.0 } else { 1.0 },
+        }
+    }
+
+    // Where the light is and which way it shines, for the light tree.
+    // Distant lights are everywhere, so have no bounds.
+    pub fn bounds(&self) -> Option<LightBounds> {
+        let up = Vector3::new(0.0, 1.0, 0.0);
+        let power = self.power(0.0);
+        match self.geometry {
+            LightGeometry::Point(v) => Some(LightBounds::new(AABB::new(v, v), power, up, PI, PI / 2.0)),
+            LightGeometry::Spot(spot) => {
+                let p = spot.position;
+                Some(LightBounds::new(AABB::new(p, p), power, spot.direction, 0.0, spot.cos_outer.acos()))
+            },
+            LightGeometry::Distant(_) => None,
+            LightGeometry::Area(area) => {
+                let aabb = area.shape.aabb();
+                match area.normal() {
+                    Some(normal) if !area.two_sided => Some(LightBounds::new(aabb, power, normal, 0.0, PI / 2.0)),
+                    _ => Some(LightBounds::new(aabb, power, up, PI, PI / 2.0)),
+                }
+            },
+        }
+    }
+
+    // Whether the light lights the given object.  Anything else, like fog, is always lit.
+    pub fn illuminates(&self, object: Option<usize>) -> bool {
+        match (&self.linking, object) {
+            (Some(linking), Some(object)) => {
+                let included = match &linking.include {
+                    Some(include) => include.contains(&object),
+                    None => true,
+                };
+                included && !linking.exclude.contains(&object)
+            },
+            _ => true,
+        }
+    }
+
+    pub fn casts_shadows_from(&self, object: usize) -> bool {
+        match &self.linking {
+            Some(linking) => !linking.shadow_exclude.contains(&object),
+            None => true,
+        }
+    }
+
+    // Following a path to a linked light would still be blocked by objects which don't cast
+    // shadows from it, so they're only sampled directly.
+    pub fn is_linked(&self) -> bool {
+        self.linking.is_some()
+    }
+
+    // Point and spot lights can only be reached by sampling them directly.
+    pub fn is_delta(&self) -> bool {
+        matches!(self.geometry, LightGeometry::Point(_) | LightGeometry::Spot(_))
+    }
+
+    // Solid angle pdf of `sample` picking the part of the light seen looking from `from` in the
+    // given direction.
+    pub fn pdf(&self, from: Vector3, direction: Vector3) -> f64 {
+        match self.geometry {
+            LightGeometry::Point(_) | LightGeometry::Spot(_) => 0.0,
+            LightGeometry::Distant(distant) => {
+                if distant.covers(direction) { 1.0 / distant.solid_angle() } else { 0.0 }
+            },
+            LightGeometry::Area(area) => match area.shape.intersect(Ray::new(from, direction)) {
+                Some(col) if area.shines_towards(col.front_face) => area.shape.pdf(from, col.location, col.normal),
+                _ => 0.0,
+            },
+        }
+    }
+
+    // Whether light is given off from the side of the light a ray hit.
+    pub fn shines_towards(&self, front_face: bool) -> bool {
+        match self.geometry {
+            LightGeometry::Area(area) => area.shines_towards(front_face),
+            _ => true,
+        }
+    }
+
+    // Pick somewhere on the light and a direction for light to leave it in, for following light
+    // out into the scene.  Lights at infinity can't be sampled like this.
+    pub fn sample_emission(&self) -> Option<Emission> {
+        match self.geometry {
+            LightGeometry::Point(position) => {
+                let direction = sample_cone(Vector3::new(0.0, 1.0, 0.0), -1.0);
+                Some(Emission { origin: position, normal: None, direction })
+            },
+            LightGeometry::Spot(spot) => {
+                let direction = sample_cone(spot.direction, spot.cos_outer);
+                Some(Emission { origin: spot.position, normal: None, direction })
+            },
+            LightGeometry::Distant(_) => None,
+            LightGeometry::Area(area) => {
+                let (origin, front) = area.shape.sample_surface();
+                let normal = if area.shines_both_ways() && rand::thread_rng().gen::<bool>() { front * -1 } else { front };
+                let (i, j, k) = normal.form_basis();
+                let direction = geom::switch_basis(geom::cosine_sample_hemisphere(), i, j, k).normed();
+                Some(Emission { origin, normal: Some(normal), direction })
+            },
+        }
+    }
+
+    // Area pdf of `sample_emission` picking any particular point on the light.  Point and spot
+    // lights are always in the same place, so it's just 1.
+    pub fn emission_position_pdf(&self) -> f64 {
+        match self.geometry {
+            LightGeometry::Point(_) | LightGeometry::Spot(_) => 1.0,
+            LightGeometry::Distant(_) => 0.0,
+            LightGeometry::Area(area) => 1.0 / area.shape.area(),
+        }
+    }
+
+    // Solid angle pdf of `sample_emission` picking the given direction, once it has picked the
+    // point.  `normal` is as for `Emission`.
+    pub fn emission_direction_pdf(&self, normal: Option<Vector3>, direction: Vector3) -> f64 {
+        match self.geometry {
+            LightGeometry::Point(_) => 1.0 / (4.0 * PI),
+            LightGeometry::Spot(spot) => {
+                if direction.dot(spot.direction) >= spot.cos_outer {
+                    1.0 / (2.0 * PI * (1.0 - spot.cos_outer))
+                } else {
+                    0.0
+                }
+            },
+            LightGeometry::Distant(_) => 0.0,
+            LightGeometry::Area(area) => {
+                let cos_theta = match normal {
+                    Some(normal) => f64::max(0.0, normal.dot(direction)),
+                    None => 0.0,
+                };
+                let sides = if area.shines_both_ways() { 2.0 } else { 1.0 };
+                cos_theta / (PI * sides)
+            },
+        }
+    }
+
+    // The normal of an area light at a point on it, outwards for spheres.
+    pub fn surface_normal(&self, point: Vector3) -> Option<Vector3> {
+        match self.geometry {
+            LightGeometry::Area(AreaLight { shape: Primitive::Sphere(sphere), .. }) => Some((point - sphere.center).normed()),
+            LightGeometry::Area(area) => area.normal(),
+            _ => None,
+        }
+    }
+
+    // Fraction of `colour * intensity` given off in the given direction.  That's the radiance of
+    // area lights, and the radiant intensity of point and spot lights.
+    // `normal` is as for `Emission`.
+    pub fn emission(&self, normal: Option<Vector3>, direction: Vector3) -> f64 {
+        match self.geometry {
+            LightGeometry::Point(_) => self.profile_factor(direction),
+            LightGeometry::Spot(spot) => spot.falloff(direction) * self.profile_factor(direction),
+            LightGeometry::Distant(_) => 0.0,
+            LightGeometry::Area(_) => match normal {
+                Some(normal) if normal.dot(direction) > 0.0 => 1.0,
+                _ => 0.0,
+            },
+        }
+    }
+
+    pub fn is_at_infinity(&self) -> bool {
+        matches!(self.geometry, LightGeometry::Distant(_))
+    }
+}
+
+// Light leaving a light, as picked by `Light::sample_emission`.
+#[derive(Clone, Copy, Debug)]
+pub struct Emission {
+    pub origin: Vector3,
+    // Facing the side of the surface the light leaves from.  Point and spot lights have none.
+    pub normal: Option<Vector3>,
+    pub direction: Vector3,
+}
+
+// Uniformly over the directions within the cone around `axis`.
+fn sample_cone(axis: Vector3, cos_max: f64) -> Vector3 {
+    let mut rng = rand::thread_rng();
+    let cos_theta = 1.0 - rng.gen::<f64>() * (1.0 - cos_max);
+    let sin_theta = f64::max(0.0, 1.0 - cos_theta * cos_theta).sqrt();
+    let phi = 2.0 * PI * rng.gen::<f64>();
+
+    // y is up.
+    let local = Vector3::new(sin_theta * phi.cos(), cos_theta, sin_theta * phi.sin());
+    let (i, j, k) = axis.form_basis();
+    geom::switch_basis(local, i, j, k).normed()
+}
+
+// Objects are given by id.
+#[derive(Clone, Debug, Default)]
+pub struct LightLinking {
+    // Only these objects are lit, if given.
+    pub include: Option<HashSet<usize>>,
+    pub exclude: HashSet<usize>,
+    // These objects don't cast shadows from the light.
+    pub shadow_exclude: HashSet<usize>,
+}
+
+// A light covering the surface of a shape.
+// Flat shapes only shine from the front, unless they're two sided.  Spheres shine both outwards
+// and inwards.
+#[derive(Clone, Copy, Debug)]
+pub struct AreaLight {
+    pub shape: Primitive,
+    pub two_sided: bool,
+}
+
+impl AreaLight {
+    pub fn new(shape: Primitive, two_sided: bool) -> AreaLight {
+        AreaLight { shape, two_sided }
+    }
+
+    // Which way the front of a flat light faces.
+    fn normal(&self) -> Option<Vector3> {
+        match self.shape {
+            Primitive::Triangle(triangle) => Some(triangle.surface_normal),
+            Primitive::Rectangle(rect) => Some(rect.normal),
+            Primitive::Disk(disk) => Some(disk.normal),
+            Primitive::Sphere(_) | Primitive::Box(_) => None,
+        }
+    }
+
+    fn shines_towards(&self, front_face: bool) -> bool {
+        front_face || self.shines_both_ways()
+    }
+
+    fn shines_both_ways(&self) -> bool {
+        self.two_sided || self.normal().is_none()
+    }
+
+    fn sample(&self, from: Vector3) -> (Vector3, f64, f64) {
+        let (direction, distance, inv_pdf) = self.shape.sample(from);
+        match self.normal() {
+            // Behind a one sided light.
+            Some(normal) if !self.two_sided && direction.dot(normal) <= 0.0 => (direction, distance, 0.0),
+            _ => (direction, distance, inv_pdf),
         }
     }
 }
@@ -62,7 +360,124 @@ impl Light {
 #[derive(Clone, Debug)]
 pub enum LightGeometry {
     Point(Vector3),
-    Area(Primitive),
+    Spot(SpotLight),
+    Distant(DistantLight),
+    Area(AreaLight),
+}
+
+// A point light which only shines within a cone.
+// Full intensity inside the inner angle, fading smoothly to nothing at the outer angle.
+#[derive(Clone, Copy, Debug)]
+pub struct SpotLight {
+    pub position: Vector3,
+    direction: Vector3,
+    cos_inner: f64,
+    cos_outer: f64,
+}
+
+impl SpotLight {
+    // Angles are measured from the centre of the cone to its edge.
+    pub fn new(position: Vector3, direction: Vector3, inner_angle: f64, outer_angle: f64) -> SpotLight {
+        let outer_angle = outer_angle.clamp(0.0, PI);
+        let inner_angle = inner_angle.clamp(0.0, outer_angle);
+        SpotLight {
+            position,
+            direction: direction.normed(),
+            cos_inner: inner_angle.cos(),
+            cos_outer: outer_angle.cos(),
+        }
+    }
+
+    // Fraction of the full intensity given off in the given direction.
+    pub fn falloff(&self, direction: Vector3) -> f64 {
+        let cos_theta = direction.dot(self.direction);
+        if cos_theta >= self.cos_inner {
+            1.0
+        } else if cos_theta <= self.cos_outer {
+            0.0
+        } else {
+            let t = (cos_theta - self.cos_outer) / (self.cos_inner - self.cos_outer);
+            t * t * (3.0 - 2.0 * t)
+        }
+    }
+
+    // Solid angle the light covers, weighted by the falloff.
+    // The smoothstep is symmetric, so it averages 1/2 across the soft edge.
+    pub fn solid_angle(&self) -> f64 {
+        2.0 * PI * (1.0 - (self.cos_inner + self.cos_outer) / 2.0)
+    }
+}
+
+// An IES profile pointed somewhere in the scene.
+#[derive(Clone, Debug)]
+pub struct LightProfile {
+    ies: Arc<IesProfile>,
+    // Basis with j pointing at the profile's nadir, which is where its vertical angles start.
+    i: Vector3,
+    j: Vector3,
+    k: Vector3,
+}
+
+impl LightProfile {
+    pub fn new(ies: Arc<IesProfile>, nadir: Vector3) -> LightProfile {
+        let (i, j, k) = nadir.normed().form_basis();
+        LightProfile { ies, i, j, k }
+    }
+
+    // Fraction of the peak intensity given off in the given direction.
+    pub fn evaluate(&self, direction: Vector3) -> f64 {
+        let vertical = direction.dot(self.j).clamp(-1.0, 1.0).acos();
+        let horizontal = direction.dot(self.k).atan2(direction.dot(self.i));
+        self.ies.evaluate(vertical.to_degrees(), horizontal.to_degrees())
+    }
+
+    // Integral over all directions of the profile times `weight`.
+    pub fn integrate<F: Fn(Vector3) -> f64>(&self, weight: F) -> f64 {
+        let (steps_theta, steps_phi) = (180, 360);
+        let d_theta = PI / steps_theta as f64;
+        let d_phi = 2.0 * PI / steps_phi as f64;
+        let mut total = 0.0;
+        for t in 0 .. steps_theta {
+            let theta = (t as f64 + 0.5) * d_theta;
+            for p in 0 .. steps_phi {
+                let phi = (p as f64 + 0.5) * d_phi;
+                let direction = self.i * (theta.sin() * phi.cos()) + self.j * theta.cos() + self.k * (theta.sin() * phi.sin());
+                total += self.evaluate(direction) * weight(direction) * theta.sin() * d_theta * d_phi;
+            }
+        }
+        total
+    }
+}
+
+// A light infinitely far away, like the sun, covering a small disk in the sky.
+#[derive(Clone, Copy, Debug)]
+pub struct DistantLight {
+    // Towards the light.
+    direction: Vector3,
+    cos_max: f64,
+}
+
+impl DistantLight {
+    pub fn new(direction: Vector3, angular_diameter: f64) -> DistantLight {
+        DistantLight {
+            direction: direction.normed(),
+            cos_max: (angular_diameter / 2.0).clamp(0.0, PI).cos(),
+        }
+    }
+
+    // Uniformly over the disk.
+    pub fn sample_direction(&self) -> Vector3 {
+        sample_cone(self.direction, self.cos_max)
+    }
+
+    pub fn solid_angle(&self) -> f64 {
+        2.0 * PI * (1.0 - self.cos_max)
+    }
+
+    // Whether looking in this direction would see the light.
+    pub fn covers(&self, direction: Vector3) -> bool {
+        direction.dot(self.direction) >= self.cos_max
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -70,6 +485,7 @@ pub enum Skybox {
     Flat(FlatSky),
     Gradient(GradientSky),
     Hdri(HdriSky),
+    Sky(PreethamSky),
 }
 
 impl Skybox {
@@ -81,33 +497,86 @@ impl Skybox {
         Skybox::Gradient(GradientSky{ overhead_colour, horizon_colour })
     }
 
-    pub fn hdri(width: u32, height: u32, data: Vec<Colour>) -> Skybox {
-        Skybox::Hdri(HdriSky{ width, height, data })
+    pub fn hdri(width: u32, height: u32, data: Vec<Colour>, settings: HdriSettings) -> Skybox {
+        Skybox::Hdri(HdriSky::new(width, height, data, settings))
     }
 
-    pub fn ambient_light(&self, direction: Vector3) -> Colour {
+    pub fn sky(sun: Vector3, turbidity: f64) -> Skybox {
+        Skybox::Sky(PreethamSky::new(sun, turbidity))
+    }
+
+    // `direction` is the way the light is travelling, i.e. from the sky towards the viewer at
+    // `origin`.  Only ground projected HDRIs depend on where the viewer is.
+    pub fn ambient_light(&self, origin: Vector3, direction: Vector3) -> Colour {
         match self {
             Skybox::Flat(sky) => sky.colour,
             Skybox::Gradient(sky) => {
                 let cos_theta = direction.dot(Vector3::new(0.0, 1.0, 0.0));
                 sky.overhead_colour * cos_theta + sky.horizon_colour * (1.0 - cos_theta)
             },
-            Skybox::Hdri(sky) => {
-                // Equirectangular projection.
-                let lat = direction.y.acos();  // Spherical coords theta. [0, pi)
-                let long = direction.z.atan2(direction.x);  // Spherical coords phi. (-pi, pi]
+            Skybox::Hdri(sky) => sky.radiance_from(origin, direction),
+            Skybox::Sky(sky) => sky.radiance(direction * -1),
+        }
+    }
+
+    // Pick a direction to look for light from the sky, for skyboxes which can be importance
+    // sampled.  Returns the direction the light is travelling (as for `ambient_light`), and the
+    // pdf with respect to solid angle.
+    pub fn sample(&self) -> Option<(Vector3, f64)> {
+        match self {
+            Skybox::Hdri(sky) => Some(sky.sample()),
+            _ => None,
+        }
+    }
+
+    // Solid angle pdf of `sample` producing the given direction.
+    pub fn pdf(&self, direction: Vector3) -> f64 {
+        match self {
+            Skybox::Hdri(sky) => sky.pdf(direction),
+            _ => 0.0,
+        }
+    }
+
+    // As `sample`, but only looking through the portal from `point`.
+    pub fn sample_through(&self, point: Vector3, portal: &Primitive) -> Option<(Vector3, f64)> {
+        match self {
+            Skybox::Hdri(sky) => sky.sample_through(point, portal),
+            _ => None,
+        }
+    }
 
-                let w = sky.width as f64;
-                let h = sky.height as f64;
+    pub fn pdf_through(&self, point: Vector3, portal: &Primitive, direction: Vector3) -> f64 {
+        match self {
+            Skybox::Hdri(sky) => sky.pdf_through(point, portal, direction),
+            _ => 0.0,
+        }
+    }
 
-                // Adjust and scale.
-                let x = w / 2.0 * (long / PI) + w / 2.0;
-                let y = h * (1.0 - lat / PI);
+    pub fn is_importance_sampled(&self) -> bool {
+        matches!(self, Skybox::Hdri(_))
+    }
+}
 
-                let x_pix = u32::min(sky.width - 1, x as u32);
-                let y_pix = u32::min(sky.height - 1, y as u32);
-                let pix = y_pix * sky.width + x_pix;
-                sky.data[pix as usize]
+// What the camera sees behind everything instead of the skybox, which still lights the scene.
+// Positions are on the screen, from (0, 0) in the top left to (1, 1) in the bottom right.
+#[derive(Clone, Debug)]
+pub enum Background {
+    Flat(Colour),
+    Gradient { top: Colour, bottom: Colour },
+    // Stretched to fill the screen.
+    Image(Arc<Texture>),
+}
+
+impl Background {
+    pub fn colour(&self, screen: (f64, f64)) -> Colour {
+        let (x, y) = screen;
+        match self {
+            Background::Flat(colour) => *colour,
+            Background::Gradient { top, bottom } => *top * (1.0 - y) + *bottom * y,
+            Background::Image(image) => {
+                // Stop the edges blending with the opposite side as the texture wraps round.
+                let (half_x, half_y) = (0.5 / image.width as f64, 0.5 / image.height as f64);
+                image.colour(x.clamp(half_x, 1.0 - half_x), 1.0 - y.clamp(half_y, 1.0 - half_y))
             },
         }
     }
@@ -124,23 +593,480 @@ pub struct GradientSky {
     pub horizon_colour: Colour,
 }
 
+// How the pixels of an HDRI map to directions.  Apart from equirectangular images, which go
+// round with the direction light travels, they're laid out as seen looking out from the
+// middle, facing +z with +y up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HdriLayout {
+    // Longitude across and latitude down, covering the whole sphere.
+    Equirectangular,
+    // Six square faces in a cross four wide and three high.  The middle row faces +x, +z, -x
+    // and -z from left to right, with +y above +z and -y below it.
+    CubeMap,
+    // A light probe.  Straight ahead is in the middle of a circle filling the image, and
+    // straight behind is round its edge.
+    Angular,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HdriFilter {
+    Nearest,
+    Bilinear,
+    // Catmull-Rom, which is sharper than bilinear but can ring around very bright pixels.
+    Bicubic,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct HdriSettings {
+    pub layout: HdriLayout,
+    pub filter: HdriFilter,
+    // From the image's own orientation to the scene.
+    pub rotation: Matrix3,
+    // Intensity and tint.
+    pub scale: Colour,
+    pub ground: Option<GroundProjection>,
+}
+
+impl Default for HdriSettings {
+    fn default() -> HdriSettings {
+        HdriSettings {
+            layout: HdriLayout::Equirectangular,
+            filter: HdriFilter::Nearest,
+            rotation: Matrix3::rotation(0.0, 0.0, 0.0),
+            scale: Colour::WHITE,
+            ground: None,
+        }
+    }
+}
+
+// Puts the bottom half of an HDRI on the ground, instead of infinitely far away, so it stays
+// put as the camera moves.  The ground is a flat disk, under a dome which meets its edge and
+// has the rest of the HDRI on it.  Both are seen as if from where the HDRI was taken.
+#[derive(Clone, Copy, Debug)]
+pub struct GroundProjection {
+    // On the ground, under where the HDRI was taken.
+    center: Vector3,
+    // Of where the HDRI was taken, above the ground.
+    height: f64,
+    radius: f64,
+}
+
+impl GroundProjection {
+    pub fn new(center: Vector3, height: f64, radius: f64) -> GroundProjection {
+        if height <= 0.0 || radius <= 0.0 {
+            panic!("Ground projection needs a positive height and radius");
+        }
+        GroundProjection { center, height, radius }
+    }
+
+    // The direction to look in from where the HDRI was taken, to see what someone at `origin`
+    // looking the given way sees.
+    fn project(&self, origin: Vector3, looking: Vector3) -> Vector3 {
+        let taken_at = self.center + Vector3::new(0.0, self.height, 0.0);
+
+        if looking.y < 0.0 {
+            let t = (self.center.y - origin.y) / looking.y;
+            let on_ground = origin + looking * t;
+            if t >= 0.0 && (on_ground - self.center).magnitude() <= self.radius * self.radius {
+                return (on_ground - taken_at).normed();
+            }
+        }
+
+        // Seen on the inside of the dome.  From outside, it's all infinitely far away anyway.
+        let dome_radius_sq = self.radius * self.radius + self.height * self.height;
+        let to_origin = origin - taken_at;
+        let c = to_origin.magnitude() - dome_radius_sq;
+        if c > 0.0 {
+            return looking;
+        }
+        let b = to_origin.dot(looking);
+        let t = -b + (b * b - c).sqrt();
+        (origin + looking * t - taken_at).normed()
+    }
+}
+
+// Part of an image to look up pixels in.  Lookups past the edges are clamped, or wrap round
+// horizontally if `wrap` is set.
+#[derive(Clone, Copy, Debug)]
+struct ImageRegion {
+    x: i64,
+    y: i64,
+    width: i64,
+    height: i64,
+    wrap: bool,
+}
+
 #[derive(Clone, Debug)]
 pub struct HdriSky {
     pub width: u32,
     pub height: u32,
     pub data: Vec<Colour>,
+    settings: HdriSettings,
+    inv_rotation: Matrix3,
+    // Over an equirectangular grid, proportional to how much light comes from each cell.  This
+    // is the image itself for equirectangular images.
+    distribution: Distribution2D,
+}
+
+impl HdriSky {
+    pub fn new(width: u32, height: u32, data: Vec<Colour>, settings: HdriSettings) -> HdriSky {
+        match settings.layout {
+            HdriLayout::CubeMap if width * 3 != height * 4 => {
+                println!("[WARN] Cube map HDRI should be 4:3, but is {}x{}", width, height);
+            },
+            HdriLayout::Angular if width != height => {
+                println!("[WARN] Angular HDRI should be square, but is {}x{}", width, height);
+            },
+            _ => (),
+        }
+
+        let (grid_width, grid_height) = match settings.layout {
+            HdriLayout::Equirectangular => (width as usize, height as usize),
+            HdriLayout::CubeMap | HdriLayout::Angular => (width as usize, usize::max(1, width as usize / 2)),
+        };
+
+        let mut sky = HdriSky {
+            width,
+            height,
+            data,
+            settings,
+            inv_rotation: settings.rotation.transpose(),
+            distribution: Distribution2D::new(&[1.0], 1, 1),
+        };
+
+        // Cells near the poles cover less of the sphere.
+        let weights: Vec<f64> = (0 .. grid_width * grid_height)
+            .map(|ix| {
+                let x = ((ix % grid_width) as f64 + 0.5) / grid_width as f64;
+                let y = ((ix / grid_width) as f64 + 0.5) / grid_height as f64;
+                let lat = PI * (1.0 - y);
+                let radiance = sky.lookup(HdriSky::direction(x, y)) * settings.scale;
+                radiance.luminance().max(0.0) * lat.sin()
+            })
+            .collect();
+        sky.distribution = Distribution2D::new(&weights, grid_width, grid_height);
+        sky
+    }
+
+    // Equirectangular projection, to image coordinates in [0, 1).
+    fn image_coords(direction: Vector3) -> (f64, f64) {
+        let lat = direction.y.clamp(-1.0, 1.0).acos();  // Spherical coords theta. [0, pi]
+        let long = direction.z.atan2(direction.x);  // Spherical coords phi. (-pi, pi]
+        ((long / PI + 1.0) / 2.0, 1.0 - lat / PI)
+    }
+
+    fn direction(x: f64, y: f64) -> Vector3 {
+        let lat = PI * (1.0 - y);
+        let long = PI * (2.0 * x - 1.0);
+        Vector3::new(lat.sin() * long.cos(), lat.cos(), lat.sin() * long.sin())
+    }
+
+    pub fn radiance(&self, direction: Vector3) -> Colour {
+        self.lookup(self.inv_rotation * direction) * self.settings.scale
+    }
+
+    // As seen from `origin`, which only matters if the HDRI is projected onto the ground.
+    pub fn radiance_from(&self, origin: Vector3, direction: Vector3) -> Colour {
+        match self.settings.ground {
+            Some(ground) => self.radiance(ground.project(origin, direction * -1) * -1),
+            None => self.radiance(direction),
+        }
+    }
+
+    // Unscaled radiance travelling in the given direction, relative to the image.
+    fn lookup(&self, direction: Vector3) -> Colour {
+        let (width, height) = (self.width as i64, self.height as i64);
+        let full = ImageRegion { x: 0, y: 0, width, height, wrap: false };
+        let looking = direction * -1;
+
+        match self.settings.layout {
+            HdriLayout::Equirectangular => {
+                let (x, y) = HdriSky::image_coords(direction);
+                self.filtered(x * width as f64, y * height as f64, ImageRegion { wrap: true, ..full })
+            },
+            HdriLayout::CubeMap => {
+                // The faces are square, so an image of the wrong shape just leaves some unused.
+                let face = i64::max(1, i64::min(width / 4, height / 3));
+                let (col, row, u, v) = cube_face(looking);
+                let region = ImageRegion { x: col * face, y: row * face, width: face, height: face, wrap: false };
+                self.filtered((col as f64 + u) * face as f64, (row as f64 + v) * face as f64, region)
+            },
+            HdriLayout::Angular => {
+                // Distance from the middle is proportional to the angle from straight ahead.
+                let (right, up) = (-looking.x, looking.y);
+                let across = (right * right + up * up).sqrt();
+                let r = looking.z.clamp(-1.0, 1.0).acos() / PI;
+                let (u, v) = if across > 0.0 { (right * r / across, up * r / across) } else { (0.0, 0.0) };
+                self.filtered((0.5 + 0.5 * u) * width as f64, (0.5 - 0.5 * v) * height as f64, full)
+            },
+        }
+    }
+
+    // Filtered value at a position in pixels, where pixel (i, j) covers [i, i + 1) x [j, j + 1).
+    fn filtered(&self, x: f64, y: f64, region: ImageRegion) -> Colour {
+        let (x0, x_weights) = filter_taps(self.settings.filter, x - region.x as f64);
+        let (y0, y_weights) = filter_taps(self.settings.filter, y - region.y as f64);
+
+        let mut total = Colour::BLACK;
+        for (dy, wy) in y_weights.iter().enumerate().filter(|(_, w)| **w != 0.0) {
+            for (dx, wx) in x_weights.iter().enumerate().filter(|(_, w)| **w != 0.0) {
+                total += self.pixel(x0 + dx as i64, y0 + dy as i64, region) * (wx * wy);
+            }
+        }
+
+        // Bicubic filtering can overshoot below zero.
+        Colour::rgb(total.r.max(0.0), total.g.max(0.0), total.b.max(0.0))
+    }
+
+    // Pixel within the region.
+    fn pixel(&self, x: i64, y: i64, region: ImageRegion) -> Colour {
+        let x = if region.wrap { x.rem_euclid(region.width) } else { x.clamp(0, region.width - 1) };
+        let y = y.clamp(0, region.height - 1);
+        let ix = (region.y + y) * self.width as i64 + region.x + x;
+        self.data[ix as usize]
+    }
+
+    // Sampling ignores any ground projection, which would make the pdf depend on where the sky
+    // is seen from.  Light from the ground is still all found, since it's weighted against
+    // sampling the material by this same pdf, but the ground isn't importance sampled.
+    pub fn sample(&self) -> (Vector3, f64) {
+        let mut rng = rand::thread_rng();
+        let ((x, y), pdf) = self.distribution.sample(rng.gen(), rng.gen());
+        let direction = self.settings.rotation * HdriSky::direction(x, y);
+        (direction, HdriSky::to_solid_angle(pdf, y))
+    }
+
+    pub fn pdf(&self, direction: Vector3) -> f64 {
+        let (x, y) = HdriSky::image_coords(self.inv_rotation * direction);
+        HdriSky::to_solid_angle(self.distribution.pdf(x, y), y)
+    }
+
+    // As `sample`, but only where the portal could be seen from `point`.  The direction might
+    // still miss it, since this only knows which cells of the image it covers.
+    pub fn sample_through(&self, point: Vector3, portal: &Primitive) -> Option<(Vector3, f64)> {
+        let mut rng = rand::thread_rng();
+        let cells = self.portal_cells(point, portal);
+        let ((x, y), pdf) = self.distribution.sample_within(&cells, rng.gen(), rng.gen())?;
+        let direction = self.settings.rotation * HdriSky::direction(x, y);
+        Some((direction, HdriSky::to_solid_angle(pdf, y)))
+    }
+
+    pub fn pdf_through(&self, point: Vector3, portal: &Primitive, direction: Vector3) -> f64 {
+        let (x, y) = HdriSky::image_coords(self.inv_rotation * direction);
+        HdriSky::to_solid_angle(self.distribution.pdf_within(&self.portal_cells(point, portal), x, y), y)
+    }
+
+    // The cells of the distribution the portal covers, seen from `point`, as ranges for
+    // `Distribution2D::sample_within`.  Found from points around its edge, which is padded by a
+    // cell since it can bulge out a little between them.
+    fn portal_cells(&self, point: Vector3, portal: &Primitive) -> Vec<(usize, usize, usize, usize)> {
+        let (width, height) = self.distribution.size();
+        let rect = match portal {
+            Primitive::Rectangle(rect) => rect,
+            _ => return vec![(0, 0, width - 1, height - 1)],
+        };
+
+        let corners = [rect.corner, rect.corner + rect.edge_u, rect.corner + rect.edge_u + rect.edge_v, rect.corner + rect.edge_v];
+        let steps = 16;
+        let coords: Vec<(f64, f64)> = (0 .. 4 * steps)
+            .map(|ix| {
+                let (from, to) = (corners[ix / steps], corners[(ix / steps + 1) % 4]);
+                let on_edge = from + (to - from) * ((ix % steps) as f64 / steps as f64);
+                HdriSky::image_coords(self.inv_rotation * (point - on_edge).normed())
+            })
+            .collect();
+
+        // If the light coming straight down or up can come through, the portal goes all the way
+        // round, up to the top or bottom of the image.
+        let through = |travelling_y: f64| {
+            let looking = self.settings.rotation * Vector3::new(0.0, -travelling_y, 0.0);
+            portal.intersect(Ray::new(point, looking)).is_some()
+        };
+        let (to_top, to_bottom) = (through(1.0), through(-1.0));
+
+        let min_y = if to_bottom { 0.0 } else { coords.iter().fold(1.0, |acc, c| f64::min(acc, c.1)) };
+        let max_y = if to_top { 1.0 } else { coords.iter().fold(0.0, |acc, c| f64::max(acc, c.1)) };
+        let row = |y: f64| ((y * height as f64) as usize).min(height - 1);
+        let (y0, y1) = (row(min_y).saturating_sub(1), usize::min(row(max_y) + 1, height - 1));
+        if to_top || to_bottom {
+            return vec![(0, y0, width - 1, y1)];
+        }
+
+        // Otherwise it's the way round from the end of the biggest gap between the points to the
+        // start of it, which might cross the edge of the image.
+        let mut xs: Vec<f64> = coords.iter().map(|c| c.0).collect();
+        xs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let last = xs.len() - 1;
+        let (_, start) = (0 .. last)
+            .map(|ix| (xs[ix + 1] - xs[ix], ix + 1))
+            .fold((xs[0] + 1.0 - xs[last], 0), |best, gap| if gap.0 > best.0 { gap } else { best });
+        let column = |x: f64| ((x * width as f64) as usize).min(width - 1);
+        let x0 = column(xs[start]).saturating_sub(1);
+        let x1 = usize::min(column(xs[(start + last) % xs.len()]) + 1, width - 1);
+        if start == 0 {
+            vec![(x0, y0, x1, y1)]
+        } else if x1 < x0 {
+            vec![(x0, y0, width - 1, y1), (0, y0, x1, y1)]
+        } else {
+            vec![(0, y0, width - 1, y1)]
+        }
+    }
+
+    // The image covers 2pi by pi radians, squashed by sin(theta) away from the equator.
+    fn to_solid_angle(pdf: f64, y: f64) -> f64 {
+        let sin_theta = (PI * (1.0 - y)).sin();
+        if sin_theta <= 0.0 {
+            0.0
+        } else {
+            pdf / (2.0 * PI * PI * sin_theta)
+        }
+    }
+}
+
+// Which face of a cube map looking in the given direction sees, as (column, row, u, v) with u
+// and v in [0, 1] across and down the face.
+fn cube_face(looking: Vector3) -> (i64, i64, f64, f64) {
+    let (x, y, z) = (looking.x, looking.y, looking.z);
+    // Face, then the way across and the way down it.
+    let (col, row, forward, across, down) = if y.abs() >= x.abs() && y.abs() >= z.abs() {
+        if y > 0.0 { (1, 0, y, -x, z) } else { (1, 2, -y, -x, -z) }
+    } else if x.abs() >= z.abs() {
+        if x > 0.0 { (0, 1, x, z, -y) } else { (2, 1, -x, -z, -y) }
+    } else if z > 0.0 {
+        (1, 1, z, -x, -y)
+    } else {
+        (3, 1, -z, x, -y)
+    };
+
+    let u = (0.5 + 0.5 * across / forward).clamp(0.0, 1.0);
+    let v = (0.5 + 0.5 * down / forward).clamp(0.0, 1.0);
+    (col, row, u, v)
+}
+
+// The first pixel a filter covers around position `x`, and the weight of it and the ones after.
+fn filter_taps(filter: HdriFilter, x: f64) -> (i64, [f64; 4]) {
+    match filter {
+        HdriFilter::Nearest => (x.floor() as i64, [1.0, 0.0, 0.0, 0.0]),
+        HdriFilter::Bilinear => {
+            let x = x - 0.5;
+            let t = x - x.floor();
+            (x.floor() as i64, [1.0 - t, t, 0.0, 0.0])
+        },
+        HdriFilter::Bicubic => {
+            let x = x - 0.5;
+            let t = x - x.floor();
+            let (t2, t3) = (t * t, t * t * t);
+            let weights = [
+                0.5 * (-t3 + 2.0 * t2 - t),
+                0.5 * (3.0 * t3 - 5.0 * t2 + 2.0),
+                0.5 * (-3.0 * t3 + 4.0 * t2 + t),
+                0.5 * (t3 - t2),
+            ];
+            (x.floor() as i64 - 1, weights)
+        },
+    }
+}
+
+// Above this many lights, use the light tree.
+const LIGHT_TREE_THRESHOLD: usize = 16;
+
+// How to pick a light for NEE.
+enum LightSampler {
+    // In proportion to power.
+    Power(Distribution1D),
+    // Lights near the point being lit are more likely.  Distant lights aren't in the tree, so one
+    // of them is picked instead as often as the tree is used.
+    Tree { tree: LightTree, distant: Vec<usize> },
+}
+
+impl LightSampler {
+    fn new(lights: &[Light], scene_radius: f64) -> LightSampler {
+        if lights.len() <= LIGHT_TREE_THRESHOLD {
+            let power = lights.iter().map(|l| l.power(scene_radius)).collect();
+            LightSampler::Power(Distribution1D::new(power))
+        } else {
+            let bounded = lights.iter().filter_map(|l| l.bounds().map(|b| (l.id, b))).collect();
+            let distant = lights.iter().filter(|l| l.is_at_infinity()).map(|l| l.id).collect();
+            LightSampler::Tree { tree: LightTree::new(bounded), distant }
+        }
+    }
+
+    // Chance of picking a distant light rather than using the tree.
+    fn distant_chance(tree: &LightTree, distant: &[usize]) -> f64 {
+        let options = distant.len() + if tree.is_empty() { 0 } else { 1 };
+        distant.len() as f64 / options as f64
+    }
+
+    fn sample(&self, point: Vector3) -> Option<(usize, f64)> {
+        match self {
+            LightSampler::Power(distribution) => {
+                let (_, _, ix) = distribution.sample(rand::thread_rng().gen());
+                Some((ix, distribution.probability(ix)))
+            },
+            LightSampler::Tree { tree, distant } => {
+                let distant_chance = LightSampler::distant_chance(tree, distant);
+                let mut rng = rand::thread_rng();
+                if rng.gen::<f64>() < distant_chance {
+                    let ix = distant[rng.gen_range(0, distant.len())];
+                    Some((ix, distant_chance / distant.len() as f64))
+                } else {
+                    tree.sample(point).map(|(ix, p)| (ix, p * (1.0 - distant_chance)))
+                }
+            },
+        }
+    }
+
+    fn probability(&self, point: Vector3, light: &Light) -> f64 {
+        match self {
+            LightSampler::Power(distribution) => distribution.probability(light.id),
+            LightSampler::Tree { tree, distant } => {
+                let distant_chance = LightSampler::distant_chance(tree, distant);
+                if light.is_at_infinity() {
+                    distant_chance / distant.len() as f64
+                } else {
+                    tree.probability(point, light.id) * (1.0 - distant_chance)
+                }
+            },
+        }
+    }
 }
 
 pub struct Scene {
     pub skybox: Skybox,
+    // Seen by the camera instead of the skybox, if given.
+    pub background: Option<Background>,
+    // Openings, like windows, which light from the sky comes in through.  If there are any,
+    // NEE only looks for the sky through them.
+    pub portals: Vec<Primitive>,
     pub models: ModelLibrary,
+    // The medium filling all space outside of objects.
+    pub medium: Option<Medium>,
+    // Whether to trace paths with sampled wavelengths rather than RGB.
+    pub spectral: bool,
+    // Whether to render with the bidirectional path tracer rather than `trace_ray`.
+    pub bidirectional: bool,
     objects: Vec<Object>,
     lights: Vec<Light>,
+    light_sampler: LightSampler,
+    // For picking lights to follow light out from, in proportion to power.  Lights at infinity
+    // are never picked.
+    emitters: Distribution1D,
     bvh: BVH<EntityID>,
 }
 
 impl Scene {
-    pub fn new(mut models: ModelLibrary, objects: Vec<Object>, lights: Vec<Light>, skybox: Skybox) -> Scene {
+    pub fn new(
+        mut models: ModelLibrary,
+        objects: Vec<Object>,
+        lights: Vec<Light>,
+        skybox: Skybox,
+        medium: Option<Medium>,
+    ) -> Scene {
+        // Lights are looked up by id, for NEE and when they're hit.
+        if lights.iter().enumerate().any(|(ix, l)| l.id != ix) {
+            panic!("Light ids must match their position in the list of lights");
+        }
+
         let object_primitives = objects.iter()
             .map(|o| {
                 let id = o.id;
@@ -156,8 +1082,8 @@ impl Scene {
             .map(|l| {
                 let id = l.id;
                 let primitives = match l.geometry {
-                    LightGeometry::Point(_) => vec![],
-                    LightGeometry::Area(primitive) => std::iter::once(primitive).collect(),
+                    LightGeometry::Point(_) | LightGeometry::Spot(_) | LightGeometry::Distant(_) => vec![],
+                    LightGeometry::Area(area) => std::iter::once(area.shape).collect(),
                 };
                 primitives.into_iter().map(move|p| (p, EntityID::Light(id))).collect()
             })
@@ -166,11 +1092,21 @@ impl Scene {
         let primitive_geometry = object_primitives.chain(light_primitives).collect();
 
         let bvh = construct_bvh_aac(primitive_geometry);
-        Scene { skybox, models, objects, lights, bvh }
+        let scene_radius = (bvh.aabb().max - bvh.aabb().min).magnitude().sqrt() / 2.0;
+        let light_sampler = LightSampler::new(&lights, scene_radius);
+        let emitters = Distribution1D::new(lights.iter()
+            .map(|l| if l.is_at_infinity() { 0.0 } else { l.power(scene_radius) })
+            .collect());
+        Scene {
+            skybox, background: None, portals: vec![], models, medium,
+            spectral: false, bidirectional: false,
+            objects, lights, light_sampler, emitters, bvh,
+        }
     }
 
     pub fn find_intersection(&self, ray: Ray) -> Option<(Collision, Entity)> {
-        self.bvh.find_intersection(ray).map(|(mut col, entity)| {
+        let hit = self.bvh.find_intersection_filtered(ray, |col, entity| self.is_opaque_at(col, entity));
+        hit.map(|(mut col, entity)| {
             match entity {
                 EntityID::Object(id) => {
                     let obj = &self.objects[*id];
@@ -178,10 +1114,15 @@ impl Scene {
                         Geometry::Mesh(mesh) => {
                             match col.metadata {
                                 CollisionMetadata::Mesh(face_ix, bx, by, bz) => {
-                                    if mesh.smooth_normals {
-                                        let model = self.models.get(mesh.model);
-                                        let smooth_normal = model.smooth_normal(face_ix, bx, by, bz);
-                                        col.normal = mesh.rotate(smooth_normal);
+                                    let model = self.models.get(mesh.model);
+                                    if mesh.smooth_normals || model.bump.is_some() {
+                                        let normal = if mesh.smooth_normals {
+                                            model.smooth_normal(face_ix, bx, by, bz)
+                                        } else {
+                                            model.face_normals[face_ix]
+                                        };
+                                        let shading_normal = mesh.rotate(model.bump_normal(face_ix, bx, by, bz, normal));
+                                        col.normal = if col.front_face { shading_normal } else { shading_normal * -1 };
                                     }
                                     Some((col, Entity::Object(obj.clone())))
                                 },
@@ -196,12 +1137,418 @@ impl Scene {
         }).flatten()
     }
 
-    pub fn random_light(&self) -> Option<&Light> {
-        if self.lights.len() > 0 {
-            let id = rand::thread_rng().gen_range(0, self.lights.len());
-            Some(&self.lights[id])
+    // Transparent parts of a surface are treated as if they weren't there at all.
+    // Partially transparent surfaces let a random fraction of rays through.
+    fn is_opaque_at(&self, col: &Collision, entity: &EntityID) -> bool {
+        let obj = match entity {
+            EntityID::Object(id) => &self.objects[*id],
+            EntityID::Light(_) => return true,
+        };
+
+        match (&obj.geometry, col.metadata) {
+            (Geometry::Mesh(mesh), CollisionMetadata::Mesh(face_ix, bx, by, bz)) => {
+                let opacity = self.models.get(mesh.model).opacity(face_ix, bx, by, bz);
+                opacity >= 1.0 || rand::thread_rng().gen::<f64>() < opacity
+            },
+            _ => true,
+        }
+    }
+
+    // The medium a ray ends up in after passing through the surface of the given object.
+    pub fn medium_across(&self, object_id: usize, collision: &Collision) -> Option<&Medium> {
+        if collision.front_face {
+            self.objects[object_id].medium.as_ref()
+        } else {
+            self.medium.as_ref()
+        }
+    }
+
+    // Pick a light to light `point`, in proportion to how much it's likely to contribute.
+    // Returns the light along with the chance of picking it.
+    pub fn random_light(&self, point: Vector3) -> Option<(&Light, f64)> {
+        if self.lights.is_empty() {
+            return None;
+        }
+
+        self.light_sampler.sample(point)
+            .filter(|(_, p)| *p > 0.0)
+            .map(|(ix, p)| (&self.lights[ix], p))
+    }
+
+    // Chance of `random_light` picking the given light.
+    pub fn light_probability(&self, light: &Light, point: Vector3) -> f64 {
+        self.light_sampler.probability(point, light)
+    }
+
+    // Pick a light to follow light out from, in proportion to its power.  Returns the light
+    // along with the chance of picking it.
+    pub fn random_emitter(&self) -> Option<(&Light, f64)> {
+        if self.lights.is_empty() {
+            return None;
+        }
+
+        let (_, _, ix) = self.emitters.sample(rand::thread_rng().gen());
+        let probability = self.emitters.probability(ix);
+        if probability > 0.0 && !self.lights[ix].is_at_infinity() {
+            Some((&self.lights[ix], probability))
         } else {
             None
         }
     }
+
+    // Chance of `random_emitter` picking the given light.
+    pub fn emitter_probability(&self, light: &Light) -> f64 {
+        if light.is_at_infinity() { 0.0 } else { self.emitters.probability(light.id) }
+    }
+
+    // Pick a direction to look for light from the sky at `point`, if it can be sampled.  Returns
+    // the direction the light is travelling (as for `Skybox::ambient_light`), and the pdf with
+    // respect to solid angle.
+    pub fn sample_sky(&self, point: Vector3) -> Option<(Vector3, f64)> {
+        if self.portals.is_empty() {
+            return self.skybox.sample();
+        }
+
+        // Portals are picked evenly rather than by how much light comes through them.  Skies
+        // which can be importance sampled are, but only where the portal is, so small bright
+        // things like the sun can still be found.  Anything else is sampled evenly through it.
+        let portal = self.portals[rand::thread_rng().gen_range(0, self.portals.len())];
+        let direction = if self.skybox.is_importance_sampled() {
+            let (direction, _) = self.skybox.sample_through(point, &portal)?;
+            // Which might just miss the portal.
+            portal.intersect(Ray::new(point, direction * -1))?;
+            direction
+        } else {
+            let (direction, _, inv_pdf) = portal.sample(point);
+            if inv_pdf <= 0.0 {
+                return None;
+            }
+            direction
+        };
+        Some((direction, self.sky_pdf(point, direction)))
+    }
+
+    // Solid angle pdf of `sample_sky` producing the given direction.
+    pub fn sky_pdf(&self, point: Vector3, direction: Vector3) -> f64 {
+        if self.portals.is_empty() {
+            return self.skybox.pdf(direction);
+        }
+
+        let ray = Ray::new(point, direction * -1);
+        let total: f64 = self.portals.iter()
+            .filter_map(|portal| portal.intersect(ray).map(|col| if self.skybox.is_importance_sampled() {
+                self.skybox.pdf_through(point, portal, direction)
+            } else {
+                portal.pdf(point, col.location, col.normal)
+            }))
+            .sum();
+        total / self.portals.len() as f64
+    }
+
+    // Lights at infinity which would be seen looking in the given direction.
+    pub fn distant_lights(&self, direction: Vector3) -> impl Iterator<Item = &Light> {
+        self.lights.iter().filter(move |l| match l.geometry {
+            LightGeometry::Distant(distant) => distant.covers(direction),
+            _ => false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::material::MaterialColour;
+    use crate::scene::*;
+
+    #[test]
+    fn test_spot_light_falloff() {
+        let spot = SpotLight::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, -2.0, 0.0), 0.2, 0.4);
+        let at_angle = |theta: f64| spot.falloff(Vector3::new(theta.sin(), -theta.cos(), 0.0));
+
+        assert_eq!(at_angle(0.0), 1.0);
+        assert_eq!(at_angle(0.19), 1.0);
+        assert_eq!(at_angle(0.41), 0.0);
+        assert_eq!(spot.falloff(Vector3::new(0.0, 1.0, 0.0)), 0.0);
+
+        // Fades smoothly in between.
+        let mut last = 1.0;
+        for ix in 1 .. 20 {
+            let falloff = at_angle(0.2 + 0.2 * ix as f64 / 20.0);
+            assert!(falloff < last && falloff > 0.0);
+            last = falloff;
+        }
+
+        // Integrate the falloff over the sphere.
+        let n = 100_000;
+        let mut total = 0.0;
+        for ix in 0 .. n {
+            let theta = PI * (ix as f64 + 0.5) / n as f64;
+            total += at_angle(theta) * 2.0 * PI * theta.sin() * PI / n as f64;
+        }
+        assert!((total - spot.solid_angle()).abs() < 1e-3, "{} vs {}", total, spot.solid_angle());
+    }
+
+    #[test]
+    fn test_light_profile() {
+        // Full intensity in the lower hemisphere, half in the upper one.
+        let ies = IesProfile::parse("IESNA:LM-63-2002\nTILT=NONE\n1 -1 1.0 4 1 1 2 0 0 0\n1.0 1.0 0\n0 90 90 180\n0\n100 100 50 50\n");
+        let profile = LightProfile::new(Arc::new(ies), Vector3::new(0.0, -1.0, 0.0));
+        assert_eq!(profile.evaluate(Vector3::new(0.0, -1.0, 0.0)), 1.0);
+        assert_eq!(profile.evaluate(Vector3::new(0.6, -0.8, 0.0)), 1.0);
+        assert_eq!(profile.evaluate(Vector3::new(0.0, 0.8, -0.6)), 0.5);
+
+        let expected = 2.0 * PI * 1.5;
+        let total = profile.integrate(|_| 1.0);
+        assert!((total - expected).abs() < 1e-3, "{} vs {}", total, expected);
+
+        let light = Light {
+            id: 0,
+            geometry: LightGeometry::Point(Vector3::new(0.0, 0.0, 0.0)),
+            colour: Colour::WHITE,
+            intensity: 1.0,
+            profile: Some(profile),
+            linking: None,
+        };
+        assert_eq!(light.sample(Vector3::new(0.0, 2.0, 0.0)).2, 0.125);
+    }
+
+    #[test]
+    fn test_hdri_sampling() {
+        // Dim everywhere, with a bright patch.
+        let (width, height) = (16, 8);
+        let data: Vec<Colour> = (0 .. width * height)
+            .map(|ix| if ix == 2 * width + 5 { Colour::rgb(100.0, 100.0, 100.0) } else { Colour::rgb(0.1, 0.1, 0.1) })
+            .collect();
+        let sky = HdriSky::new(width, height, data, HdriSettings::default());
+
+        // Samples agree with the pdf, and estimate the total light from the sky.
+        let n = 100_000;
+        let mut total = 0.0;
+        for _ in 0 .. n {
+            let (direction, pdf) = sky.sample();
+            assert!((direction.magnitude() - 1.0).abs() < 1e-9);
+            assert!((pdf - sky.pdf(direction)).abs() < 1e-6 * pdf, "{} vs {}", pdf, sky.pdf(direction));
+            total += sky.radiance(direction).g / pdf / n as f64;
+        }
+
+        // The same thing by brute force.
+        let steps = 1000;
+        let mut expected = 0.0;
+        for iy in 0 .. steps {
+            for ix in 0 .. 2 * steps {
+                let theta = PI * (iy as f64 + 0.5) / steps as f64;
+                let phi = PI * (ix as f64 + 0.5) / steps as f64;
+                let direction = Vector3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin());
+                expected += sky.radiance(direction).g * theta.sin() * (PI / steps as f64).powi(2);
+            }
+        }
+
+        assert!((total - expected).abs() < 0.01 * expected, "{} vs {}", total, expected);
+    }
+
+    #[test]
+    fn test_hdri_layouts() {
+        // One pixel per face, each a different brightness.
+        let data = (0 .. 12).map(|ix| Colour::rgb(ix as f64, ix as f64, ix as f64)).collect();
+        let settings = HdriSettings { layout: HdriLayout::CubeMap, ..HdriSettings::default() };
+        let cube = HdriSky::new(4, 3, data, settings);
+        let looking = |sky: &HdriSky, x: f64, y: f64, z: f64| sky.radiance(Vector3::new(-x, -y, -z)).g;
+        assert_eq!(looking(&cube, 1.0, 0.0, 0.0), 4.0);
+        assert_eq!(looking(&cube, 0.0, 0.0, 1.0), 5.0);
+        assert_eq!(looking(&cube, -1.0, 0.0, 0.0), 6.0);
+        assert_eq!(looking(&cube, 0.0, 0.0, -1.0), 7.0);
+        assert_eq!(looking(&cube, 0.0, 1.0, 0.0), 1.0);
+        assert_eq!(looking(&cube, 0.0, -1.0, 0.0), 9.0);
+
+        // Images of the wrong shape only use the top left.
+        let data = (0 .. 32).map(|ix| Colour::rgb(ix as f64, ix as f64, ix as f64)).collect();
+        let wide = HdriSky::new(8, 4, data, settings);
+        assert_eq!(looking(&wide, 0.0, -1.0, 0.0), 17.0);
+
+        // Straight ahead in the middle, and right (-x) halfway to the edge.
+        let data = (0 .. 9).map(|ix| Colour::rgb(ix as f64, ix as f64, ix as f64)).collect();
+        let settings = HdriSettings { layout: HdriLayout::Angular, ..HdriSettings::default() };
+        let probe = HdriSky::new(3, 3, data, settings);
+        assert_eq!(looking(&probe, 0.0, 0.0, 1.0), 4.0);
+        assert_eq!(looking(&probe, -1.0, 0.0, 0.0), 5.0);
+        assert_eq!(looking(&probe, 0.0, 1.0, 0.0), 1.0);
+
+        // Bilinear filtering blends neighbouring pixels, wrapping round the sides.
+        let data = vec![Colour::rgb(0.0, 0.0, 0.0), Colour::rgb(1.0, 1.0, 1.0)];
+        let settings = HdriSettings { filter: HdriFilter::Bilinear, ..HdriSettings::default() };
+        let strip = HdriSky::new(2, 1, data, settings);
+        assert!((strip.radiance(Vector3::new(1.0, 0.0, 0.0)).g - 0.5).abs() < 1e-9);
+        assert!((strip.radiance(Vector3::new(-1.0, 0.0, 0.0)).g - 0.5).abs() < 1e-9);
+
+        // Rotated skies still sample in proportion to their pdf, and turn their light with them.
+        let (width, height) = (16, 8);
+        let data: Vec<Colour> = (0 .. width * height)
+            .map(|ix| if ix == 2 * width + 5 { Colour::rgb(100.0, 100.0, 100.0) } else { Colour::rgb(0.1, 0.1, 0.1) })
+            .collect();
+        let rotation = Matrix3::rotation(0.7, 0.3, 0.0);
+        let settings = HdriSettings { filter: HdriFilter::Bicubic, rotation, ..HdriSettings::default() };
+        let plain = HdriSky::new(width, height, data.clone(), HdriSettings { filter: HdriFilter::Bicubic, ..HdriSettings::default() });
+        let rotated = HdriSky::new(width, height, data, settings);
+        for _ in 0 .. 1000 {
+            let (direction, pdf) = rotated.sample();
+            assert!((pdf - rotated.pdf(direction)).abs() < 1e-6 * pdf);
+            assert!((pdf - plain.pdf(rotation.transpose() * direction)).abs() < 1e-6 * pdf);
+            let expected = plain.radiance(rotation.transpose() * direction).g;
+            assert!((rotated.radiance(direction).g - expected).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_background() {
+        let gradient = Background::Gradient { top: Colour::WHITE, bottom: Colour::BLACK };
+        assert_eq!(gradient.colour((0.3, 0.0)).g, 1.0);
+        assert_eq!(gradient.colour((0.3, 0.75)).g, 0.25);
+
+        // Black on the left and white on the right, without blending round the edges.
+        let image = Background::Image(Arc::new(Texture::new(2, 1, vec![Colour::BLACK, Colour::WHITE])));
+        assert_eq!(image.colour((0.0, 0.5)).g, 0.0);
+        assert_eq!(image.colour((0.5, 0.5)).g, 0.5);
+        assert_eq!(image.colour((1.0, 0.5)).g, 1.0);
+    }
+
+    #[test]
+    fn test_ground_projection() {
+        let ground = GroundProjection::new(Vector3::new(0.0, 0.0, 0.0), 1.0, 10.0);
+        let taken_at = Vector3::new(0.0, 1.0, 0.0);
+        let same = |a: Vector3, b: Vector3| (a - b).magnitude() < 1e-18;
+
+        // From where it was taken, it looks the same as at infinity.
+        for looking in [Vector3::new(0.0, -1.0, 0.0), Vector3::new(0.6, 0.0, 0.8), Vector3::new(0.0, 0.6, -0.8)].iter() {
+            assert!(same(ground.project(taken_at, *looking), *looking));
+        }
+
+        // Elsewhere the ground stays put.
+        let looking_down = ground.project(Vector3::new(1.0, 0.5, 0.0), Vector3::new(0.0, -1.0, 0.0));
+        assert!(same(looking_down, Vector3::new(1.0, -1.0, 0.0).normed()));
+
+        // Past the edge of the ground is the dome.
+        let looking_out = ground.project(Vector3::new(5.0, 1.0, 0.0), Vector3::new(1.0, 0.0, 0.0));
+        assert!(looking_out.x > 0.99 && looking_out.y == 0.0);
+
+        // And outside that everything is at infinity.
+        let outside = Vector3::new(100.0, 1.0, 0.0);
+        assert!(same(ground.project(outside, Vector3::new(0.0, -1.0, 0.0)), Vector3::new(0.0, -1.0, 0.0)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Light ids must match")]
+    fn test_light_ids() {
+        let light = Light {
+            id: 1,
+            geometry: LightGeometry::Point(Vector3::new(0.0, 1.0, 0.0)),
+            colour: Colour::WHITE,
+            intensity: 1.0,
+            profile: None,
+            linking: None,
+        };
+        Scene::new(ModelLibrary::new(), vec![], vec![light], Skybox::flat(Colour::WHITE), None);
+    }
+
+    #[test]
+    fn test_portals() {
+        let ball = Object {
+            id: 0,
+            geometry: Geometry::Primitive(Primitive::sphere(Vector3::new(0.0, -10.0, 0.0), 1.0)),
+            material: Material::lambertian(MaterialColour::Static(Colour::WHITE), Colour::BLACK),
+            medium: None,
+        };
+        let mut scene = Scene::new(ModelLibrary::new(), vec![ball], vec![], Skybox::flat(Colour::WHITE), None);
+        let window = |x: f64| Primitive::rectangle(Vector3::new(x, 1.0, 2.0), Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 1.0, 0.0));
+        scene.portals = vec![window(-2.0), window(1.0)];
+
+        // Samples only go through the portals, with the pdf of either one picking them.
+        let point = Vector3::new(0.0, 1.5, 0.0);
+        for _ in 0 .. 1000 {
+            let (direction, pdf) = scene.sample_sky(point).unwrap();
+            let towards = direction * -1;
+            let t = 2.0 / towards.z;
+            let hit = point + towards * t;
+            assert!(hit.y >= 1.0 - 1e-9 && hit.y <= 2.0 + 1e-9);
+            assert!((pdf - scene.sky_pdf(point, direction)).abs() < 1e-9 * pdf);
+        }
+        assert_eq!(scene.sky_pdf(point, Vector3::new(0.0, 0.0, 1.0)), 0.0);
+
+        // Which together cover all directions through them.
+        let n = 100_000;
+        let mut solid_angle = 0.0;
+        for _ in 0 .. n {
+            let (_, pdf) = scene.sample_sky(point).unwrap();
+            solid_angle += 1.0 / pdf / n as f64;
+        }
+        // Solid angle of the rectangle [x1, x2] x [y1, y2] on the plane 2 away.
+        let f = |x: f64, y: f64| (x * y / (2.0 * (x * x + y * y + 4.0).sqrt())).atan();
+        let rect = |x1: f64, x2: f64| f(x2, 0.5) - f(x1, 0.5) - f(x2, -0.5) + f(x1, -0.5);
+        let expected = rect(-2.0, -1.0) + rect(1.0, 2.0);
+        assert!((solid_angle - expected).abs() < 0.01 * expected, "{} vs {}", solid_angle, expected);
+    }
+
+    #[test]
+    fn test_portals_find_the_sun() {
+        // A sun and the sky seen through a window, with the rest of the sky hidden by the walls
+        // and ceiling.
+        let (width, height) = (128, 64);
+        let looking = Vector3::new(0.3, 0.4, 2.0).normed();
+        let (sun_x, sun_y) = HdriSky::image_coords(looking * -1);
+        let sun = (sun_y * height as f64) as u32 * width + (sun_x * width as f64) as u32;
+        let data = (0 .. width * height)
+            .map(|ix| if ix == sun {
+                Colour::rgb(1000.0, 1000.0, 1000.0)
+            } else if ix < height / 2 * width {
+                Colour::rgb(1.0, 1.0, 1.0)
+            } else {
+                Colour::rgb(0.01, 0.01, 0.01)
+            })
+            .collect();
+        let sky = Skybox::hdri(width, height, data, HdriSettings::default());
+
+        let ball = Object {
+            id: 0,
+            geometry: Geometry::Primitive(Primitive::sphere(Vector3::new(0.0, -10.0, 0.0), 1.0)),
+            material: Material::lambertian(MaterialColour::Static(Colour::WHITE), Colour::BLACK),
+            medium: None,
+        };
+        let without_portals = Scene::new(ModelLibrary::new(), vec![ball.clone()], vec![], sky.clone(), None);
+        let mut with_portals = Scene::new(ModelLibrary::new(), vec![ball], vec![], sky, None);
+
+        // Mean and variance of NEE's estimate of the light coming in through a portal.
+        let point = Vector3::new(0.0, 0.0, 0.0);
+        let estimate = |scene: &Scene, portal: Primitive| {
+            let n = 20_000;
+            let samples: Vec<f64> = (0 .. n)
+                .map(|_| match scene.sample_sky(point) {
+                    Some((direction, pdf)) if portal.intersect(Ray::new(point, direction * -1)).is_some() => {
+                        assert!((pdf - scene.sky_pdf(point, direction)).abs() < 1e-6 * pdf);
+                        scene.skybox.ambient_light(point, direction).g / pdf
+                    },
+                    _ => 0.0,
+                })
+                .collect();
+            let mean = samples.iter().sum::<f64>() / n as f64;
+            let variance = samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n as f64;
+            (mean, variance)
+        };
+
+        let window = Primitive::rectangle(Vector3::new(-1.0, 0.0, 2.0), Vector3::new(2.0, 0.0, 0.0), Vector3::new(0.0, 1.0, 0.0));
+        with_portals.portals = vec![window];
+        let (mean, variance) = estimate(&with_portals, window);
+        let (expected, variance_without) = estimate(&without_portals, window);
+        assert!((mean - expected).abs() < 0.05 * expected, "{} vs {}", mean, expected);
+        assert!(variance < variance_without, "{} vs {}", variance, variance_without);
+
+        // Portals across the edge of the image and over the top of it still let in all of the
+        // sky they cover, which is the same as their solid angle.  The one 2 away across the
+        // edge covers [-1, 1] x [0, 1] and the skylight [-1, 1] x [-1, 1].
+        let f = |x: f64, y: f64| (x * y / (2.0 * (x * x + y * y + 4.0).sqrt())).atan();
+        let across_edge = Primitive::rectangle(Vector3::new(2.0, 0.0, -1.0), Vector3::new(0.0, 0.0, 2.0), Vector3::new(0.0, 1.0, 0.0));
+        let skylight = Primitive::rectangle(Vector3::new(-1.0, 2.0, -1.0), Vector3::new(2.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 2.0));
+        for (portal, expected) in [(across_edge, 2.0 * f(1.0, 1.0)), (skylight, 4.0 * f(1.0, 1.0))].iter() {
+            with_portals.portals = vec![*portal];
+            let (mean, _) = estimate(&with_portals, *portal);
+            assert!((mean - expected).abs() < 0.02 * expected, "{} vs {}", mean, expected);
+        }
+    }
 }
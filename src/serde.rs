@@ -1,6 +1,7 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::BufReader;
+use std::sync::Arc;
 
 use image::hdr;
 
@@ -9,9 +10,16 @@ use crate::colour::Colour;
 use crate::matrix::Matrix3;
 use crate::vector::Vector3;
 use crate::geom;
-use crate::material::{BasicMaterial, Material, MaterialColour};
+use crate::ies;
+use crate::material::{Dispersion, Material, MaterialColour, MaterialScalar, MixWeight};
+use crate::medium::{GridMedium, Medium};
 use crate::model;
 use crate::scene;
+use crate::sky;
+use crate::spectrum;
+use crate::spectrum::LUMENS_PER_WATT;
+use crate::texture::Texture;
+use crate::volume;
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct VectorDescription {
@@ -55,7 +63,7 @@ impl MaterialColourDescription {
     }
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
 pub struct RotationDescription {
     pub pitch: f64,
     pub yaw: f64,
@@ -69,8 +77,31 @@ pub struct SceneDescription {
     pub lights: Vec<LightDescription>,
     pub skybox: SkyboxDescription,
 
+    // Shown behind everything instead of the skybox, which still lights the scene.  Unlike the
+    // skybox, it isn't affected by the camera's exposure.
+    #[serde(default)]
+    pub background: Option<BackgroundDescription>,
+
+    // Openings the sky is seen through, e.g. the windows of a room.
+    #[serde(default)]
+    pub portals: Vec<PortalDescription>,
+
     #[serde(default)]
     pub models: HashMap<String, ModelDescription>,
+
+    // Materials which objects can refer to by name.
+    #[serde(default)]
+    pub materials: HashMap<String, MaterialDescription>,
+
+    // Fills all the space outside of objects.  e.g. fog.
+    #[serde(default)]
+    pub medium: Option<MediumDescription>,
+
+    #[serde(default)]
+    pub volumes: Vec<VolumeDescription>,
+
+    #[serde(default)]
+    pub settings: SettingsDescription,
 }
 
 impl SceneDescription {
@@ -88,17 +119,27 @@ impl SceneDescription {
             model_library.declare(name.clone(), desc.file.clone());
         });
 
+        let material_library = build_material_library(&self.materials);
+
+        // The scene objects each description turned into, for lights to refer to.
+        let mut object_names: HashMap<&str, Vec<usize>> = HashMap::new();
+
         self.objects.iter().for_each(|o| {
+            let first_ix = objects.len();
             match o.shape {
                 ShapeDescription::Sphere(ref shp) => {
                     let obj_ix = objects.len();
                     let geometry = geom::Geometry::Primitive(geom::Primitive::sphere(shp.center.to_vector(), shp.radius));
-                    let material: Material = (&o.material).into();
+                    let material = o.material.to_material(&material_library);
+                    if material.has_textured_mix() {
+                        panic!("Texture and checker mix weights need texture coordinates, which only meshes have");
+                    }
 
                     objects.push(scene::Object{
                         id: obj_ix,
                         geometry,
                         material,
+                        medium: o.medium.as_ref().map(|m| m.to_medium()),
                     });
                 },
                 ShapeDescription::Mesh(ref shp) => {
@@ -109,6 +150,10 @@ impl SceneDescription {
                     // Ensure model is loaded.
                     let model_indices = model_library.load(&shp.model);
 
+                    shp.materials.keys()
+                        .filter(|name| !model_indices.iter().any(|ix| model_library.get(*ix).name.as_ref() == Some(name)))
+                        .for_each(|name| println!("[WARN] Model '{}' has no sub-mesh called '{}'", shp.model, name));
+
                     model_indices.iter().for_each(|ix| {
                         let obj_ix = objects.len();
 
@@ -123,37 +168,89 @@ impl SceneDescription {
                             geom::Mesh::new(*ix, translation, rotation, shp.scale, shp.smooth_normals)
                         );
 
-                        let material: Material = match o.material {
-                            MaterialDescription::Auto => model_library.get(*ix).material.unwrap_or(
+                        // Sub-meshes can be given their own materials.
+                        let model = model_library.get(*ix);
+                        let material_description = model.name.as_ref()
+                            .and_then(|name| shp.materials.get(name))
+                            .unwrap_or(&o.material);
+
+                        let material: Material = match material_description {
+                            MaterialDescription::Auto => model.material.clone().unwrap_or(
                                 Material::lambertian(MaterialColour::Static(Colour::WHITE), Colour::BLACK)
                            ),
-                            _ => (&o.material).into(),
+                            _ => material_description.to_material(&material_library),
                         };
 
                         objects.push(scene::Object{
                             id: obj_ix,
                             geometry,
                             material,
+                            medium: o.medium.as_ref().map(|m| m.to_medium()),
                         });
                     });
                 },
             };
 
+            if let Some(name) = &o.name {
+                object_names.entry(name).or_default().extend(first_ix .. objects.len());
+            }
         });
 
-        self.lights.iter().enumerate().for_each(|(ix, l)| {
-            lights.push(scene::Light{
-                id: ix,
-                geometry: l.geometry.to_light_geometry(),
-                colour: l.colour.to_colour(),
-                intensity: l.intensity,
+        self.volumes.iter().for_each(|v| {
+            let medium = v.to_grid_medium();
+
+            // The volume is bounded by an invisible box, so that rays know when they enter it.
+            let geometry = geom::Geometry::Primitive(geom::Primitive::aabb_box(medium.bounds()));
+
+            objects.push(scene::Object{
+                id: objects.len(),
+                geometry,
+                material: Material::null(),
+                medium: Some(Medium::Grid(medium)),
             });
         });
 
-        scene::Scene::new(model_library, objects, lights, self.skybox.to_skybox())
+        self.lights.iter().enumerate().for_each(|(ix, l)| {
+            lights.push(l.to_light(ix, &object_names));
+        });
+
+        if let Some(sun) = self.skybox.to_sun(lights.len()) {
+            lights.push(sun);
+        }
+
+        let medium = self.medium.as_ref().map(|m| m.to_medium());
+
+        let bidirectional = self.settings.bidirectional && {
+            let has_media = medium.is_some() || objects.iter().any(|o| o.medium.is_some());
+            let has_linking = lights.iter().any(|l| l.is_linked());
+            if has_media || has_linking {
+                println!("[WARN] Media and light linking need the path tracer, so not rendering bidirectionally");
+            }
+            !has_media && !has_linking
+        };
+
+        let mut scene = scene::Scene::new(model_library, objects, lights, self.skybox.to_skybox(), medium);
+        scene.spectral = self.settings.spectral;
+        scene.bidirectional = bidirectional;
+        scene.background = self.background.as_ref().map(|b| b.to_background());
+        scene.portals = self.portals.iter().map(|p| rectangle(p.center, p.edge_u, p.edge_v)).collect();
+        scene
     }
 }
 
+// How to render the scene, as opposed to what's in it.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct SettingsDescription {
+    // Trace sampled wavelengths instead of RGB.
+    #[serde(default)]
+    pub spectral: bool,
+
+    // Trace paths from the lights as well as the camera, and join them up.  Much better at
+    // caustics and scenes lit indirectly, but slower per sample.
+    #[serde(default)]
+    pub bidirectional: bool,
+}
+
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct CameraDescription {
     pub image_width: u32,
@@ -167,6 +264,10 @@ pub struct CameraDescription {
     pub focal_length: f64,
     pub focus_distance: f64,
     pub aperture: f64,
+
+    // Exposure compensation in stops.  Each stop doubles the brightness.
+    #[serde(default)]
+    pub exposure: f64,
 }
 
 impl CameraDescription {
@@ -181,6 +282,7 @@ impl CameraDescription {
         camera.sensor_height = self.sensor_height;
         camera.focal_length = self.focal_length;
         camera.aperture = self.aperture;
+        camera.exposure = 2f64.powf(self.exposure);
 
         camera.distance_from_lens = (self.focal_length * self.focus_distance) / (self.focus_distance - self.focal_length);
         camera
@@ -196,33 +298,257 @@ pub struct ModelDescription {
 pub struct ObjectDescription {
     pub shape: ShapeDescription,
     pub material: MaterialDescription,
+
+    // Fills the inside of the object.  Only makes sense for closed shapes.
+    #[serde(default)]
+    pub medium: Option<MediumDescription>,
+
+    // For lights to refer to.  Doesn't need to be unique.
+    #[serde(default)]
+    pub name: Option<String>,
 }
 
+// The brightness can be given as a plain `intensity`, which is the radiance of area lights, or
+// the radiant intensity of point lights.  Or it can be given as the total power of the light in
+// `watts` or `lumens`.
+// Watts here are watts of visible light at the peak efficacy of 683 lumens per watt.  Real
+// fixtures are far less efficient than that, so take the figure on the box in lumens.
+// Point and spot lights can take an `ies` profile from the manufacturer.  With no brightness
+// given, they use the candela figures in the file.
+// Lights can be linked to objects by name.  They only light the objects in `include` if it's
+// given, don't light any in `exclude`, and aren't blocked by any in `shadow_exclude`.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct LightDescription {
     pub geometry: LightGeometryDescription,
+    // Tints the blackbody colour if `temperature` is given too.
+    #[serde(default = "default_tint")]
     pub colour: ColourDescription,
-    pub intensity: f64,
+    // In Kelvin.
+    #[serde(default)]
+    pub temperature: Option<f64>,
+    #[serde(default)]
+    pub intensity: Option<f64>,
+    #[serde(default)]
+    pub watts: Option<f64>,
+    #[serde(default)]
+    pub lumens: Option<f64>,
+    #[serde(default)]
+    pub ies: Option<IesDescription>,
+    #[serde(default)]
+    pub include: Option<Vec<String>>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub shadow_exclude: Vec<String>,
+}
+
+impl LightDescription {
+    pub fn to_light(&self, id: usize, object_names: &HashMap<&str, Vec<usize>>) -> scene::Light {
+        let power = match (self.watts, self.lumens) {
+            (Some(watts), None) => Some(watts),
+            (None, Some(lumens)) => Some(lumens / LUMENS_PER_WATT),
+            (None, None) => None,
+            (Some(_), Some(_)) => panic!("Light can't have both watts and lumens"),
+        };
+
+        let ies = self.ies.as_ref().map(|desc| (desc, Arc::new(ies::load_ies_file(&desc.file))));
+        let profile = ies.as_ref().map(|(desc, ies)| {
+            let nadir = match (desc.direction, &self.geometry) {
+                (Some(direction), _) => direction.to_vector(),
+                (None, LightGeometryDescription::Spot(spot)) => spot.direction.to_vector(),
+                (None, LightGeometryDescription::Point(_)) => Vector3::new(0.0, -1.0, 0.0),
+                (None, _) => panic!("Only point and spot lights can have an IES profile"),
+            };
+            scene::LightProfile::new(ies.clone(), nadir)
+        });
+
+        let mut light = scene::Light {
+            id,
+            geometry: self.geometry.to_light_geometry(),
+            colour: emission_colour(self.colour, self.temperature),
+            intensity: 0.0,
+            profile,
+            linking: self.to_light_linking(object_names),
+        };
+
+        light.intensity = match (self.intensity, power, &ies) {
+            (Some(intensity), None, _) => intensity,
+            (None, Some(_), _) if matches!(self.geometry, LightGeometryDescription::Distant(_)) => {
+                panic!("Distant lights need an intensity rather than a power")
+            },
+            (None, Some(power), _) => power / light.power_per_intensity(0.0),
+            (None, None, Some((_, ies))) => ies.peak_candela() / LUMENS_PER_WATT,
+            (None, None, None) => panic!("Light needs an intensity, watts or lumens"),
+            (Some(_), Some(_), _) => panic!("Light can't have both an intensity and a power"),
+        };
+        light
+    }
+
+    fn to_light_linking(&self, object_names: &HashMap<&str, Vec<usize>>) -> Option<Arc<scene::LightLinking>> {
+        if self.include.is_none() && self.exclude.is_empty() && self.shadow_exclude.is_empty() {
+            return None;
+        }
+
+        let find = |names: &Vec<String>| -> HashSet<usize> {
+            names.iter()
+                .flat_map(|name| match object_names.get(name.as_str()) {
+                    Some(ids) => ids.clone(),
+                    None => {
+                        println!("[WARN] Light refers to an object called '{}' which doesn't exist", name);
+                        vec![]
+                    },
+                })
+                .collect()
+        };
+
+        Some(Arc::new(scene::LightLinking {
+            include: self.include.as_ref().map(find),
+            exclude: find(&self.exclude),
+            shadow_exclude: find(&self.shadow_exclude),
+        }))
+    }
+}
+
+// Direction is where the profile's vertical angles start from.  It defaults to straight down for
+// point lights, and the direction of spot lights.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IesDescription {
+    pub file: String,
+    #[serde(default)]
+    pub direction: Option<VectorDescription>,
+}
+
+// Blackbody colours are normalised to a luminance of 1, so the RGB colour alone sets the
+// brightness.
+fn emission_colour(colour: ColourDescription, temperature: Option<f64>) -> Colour {
+    match temperature {
+        Some(t) => colour.to_colour() * spectrum::blackbody(t),
+        None => colour.to_colour(),
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum LightGeometryDescription {
     Point(VectorDescription),
+    Spot(SpotLightDescription),
+    Distant(DistantLightDescription),
     Sphere(SphereDescription),
+    Rectangle(RectangleLightDescription),
+    Disk(DiskLightDescription),
+    Triangle(TriangleLightDescription),
 }
 
 impl LightGeometryDescription {
     pub fn to_light_geometry(&self) -> scene::LightGeometry {
         match self {
             LightGeometryDescription::Point(v) => scene::LightGeometry::Point(v.to_vector()),
+            LightGeometryDescription::Spot(s) => scene::LightGeometry::Spot(s.to_spot_light()),
+            LightGeometryDescription::Distant(d) => scene::LightGeometry::Distant(
+                scene::DistantLight::new(d.direction.to_vector(), d.angular_diameter)
+            ),
             LightGeometryDescription::Sphere(s) => scene::LightGeometry::Area(
-                geom::Primitive::sphere(s.center.to_vector(), s.radius)
+                scene::AreaLight::new(geom::Primitive::sphere(s.center.to_vector(), s.radius), false)
+            ),
+            LightGeometryDescription::Rectangle(r) => scene::LightGeometry::Area(
+                scene::AreaLight::new(r.to_primitive(), r.two_sided)
+            ),
+            LightGeometryDescription::Disk(d) => scene::LightGeometry::Area(
+                scene::AreaLight::new(geom::Primitive::disk(d.center.to_vector(), d.normal.to_vector(), d.radius), d.two_sided)
+            ),
+            LightGeometryDescription::Triangle(t) => scene::LightGeometry::Area(
+                scene::AreaLight::new(t.to_primitive(), t.two_sided)
             ),
         }
     }
 }
 
+// Flat lights shine from the front, which is the side `edge_u` x `edge_v` (or the normal, or
+// going anticlockwise round the vertices) points to.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct RectangleLightDescription {
+    pub center: VectorDescription,
+    // Full length sides, which should be at right angles.
+    pub edge_u: VectorDescription,
+    pub edge_v: VectorDescription,
+    #[serde(default)]
+    pub two_sided: bool,
+}
+
+impl RectangleLightDescription {
+    fn to_primitive(self) -> geom::Primitive {
+        rectangle(self.center, self.edge_u, self.edge_v)
+    }
+}
+
+fn rectangle(center: VectorDescription, edge_u: VectorDescription, edge_v: VectorDescription) -> geom::Primitive {
+    let edge_u = edge_u.to_vector();
+    let edge_v = edge_v.to_vector();
+    let corner = center.to_vector() - edge_u * 0.5 - edge_v * 0.5;
+    geom::Primitive::rectangle(corner, edge_u, edge_v)
+}
+
+// A rectangle covering an opening.  It isn't seen, and faces either way.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct PortalDescription {
+    pub center: VectorDescription,
+    // Full length sides, which should be at right angles.
+    pub edge_u: VectorDescription,
+    pub edge_v: VectorDescription,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct DiskLightDescription {
+    pub center: VectorDescription,
+    pub normal: VectorDescription,
+    pub radius: f64,
+    #[serde(default)]
+    pub two_sided: bool,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct TriangleLightDescription {
+    pub vertices: [VectorDescription; 3],
+    #[serde(default)]
+    pub two_sided: bool,
+}
+
+impl TriangleLightDescription {
+    fn to_primitive(self) -> geom::Primitive {
+        let [a, b, c] = [self.vertices[0].to_vector(), self.vertices[1].to_vector(), self.vertices[2].to_vector()];
+        geom::Primitive::triangle(0, [a, b, c], (b - a).cross(c - a).normed())
+    }
+}
+
+// Angles are in radians, from the centre of the cone to its edge.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct SpotLightDescription {
+    pub position: VectorDescription,
+    pub direction: VectorDescription,
+    pub inner_angle: f64,
+    pub outer_angle: f64,
+}
+
+impl SpotLightDescription {
+    pub fn to_spot_light(&self) -> scene::SpotLight {
+        scene::SpotLight::new(
+            self.position.to_vector(),
+            self.direction.to_vector(),
+            self.inner_angle,
+            self.outer_angle,
+        )
+    }
+}
+
+// A light infinitely far away, like the sun.  The intensity is its radiance.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct DistantLightDescription {
+    // Towards the light.
+    pub direction: VectorDescription,
+    // In radians.
+    pub angular_diameter: f64,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ShapeDescription {
@@ -245,68 +571,174 @@ pub struct MeshDescription {
     pub translation: VectorDescription,
     pub rotation: RotationDescription,
     pub scale: f64,
+
+    // Materials for individual sub-meshes of the model, by name, overriding the object's
+    // material.
+    #[serde(default)]
+    pub materials: HashMap<String, MaterialDescription>,
 }
 
 fn default_smooth_normals() -> bool {
     true
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum MaterialDescription {
     Auto,
     Lambertian(LambertianMaterialDescription),
+    OrenNayar(OrenNayarMaterialDescription),
     Gloss(GlossMaterialDescription),
     Mirror(MirrorMaterialDescription),
     CookTorrance(CookTorranceMaterialDescription),
+    Dielectric(DielectricMaterialDescription),
     Fresnel(FresnelMaterialDescription),
+    Mix(MixMaterialDescription),
+    Null,
+    // One of the materials from the scene's `materials` section.
+    Named { name: String },
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
-#[serde(tag = "type")]
-pub enum BasicMaterialDescription {
-    Lambertian(LambertianMaterialDescription),
-    Gloss(GlossMaterialDescription),
-    Mirror(MirrorMaterialDescription),
-    CookTorrance(CookTorranceMaterialDescription),
-}
-
-impl From<&MaterialDescription> for Material {
-    fn from(desc: &MaterialDescription) -> Material {
-        match desc {
+impl MaterialDescription {
+    // `library` must already contain any named materials this refers to.
+    pub fn to_material(&self, library: &HashMap<String, Material>) -> Material {
+        match self {
             MaterialDescription::Auto => panic!("Cannot directly convert Auto material description into material."),
             MaterialDescription::Lambertian(mat) => Material::lambertian(
-                mat.albedo.to_material_colour(), Colour::BLACK
+                mat.albedo.to_material_colour(),
+                mat.emission.map_or(Colour::BLACK, |e| e.to_emittance()),
             ),
+            MaterialDescription::OrenNayar(mat) => Material::oren_nayar(mat.albedo.to_material_colour(), mat.roughness),
             MaterialDescription::Gloss(mat) => Material::gloss(mat.albedo.to_material_colour(), mat.reflectance, mat.metalness),
             MaterialDescription::Mirror(_mat) => Material::mirror(),
-            MaterialDescription::CookTorrance(mat) => Material::cook_torrance(mat.albedo.to_colour(), mat.roughness),
+            MaterialDescription::CookTorrance(mat) => Material::cook_torrance(mat.albedo.to_colour(), MaterialScalar::Static(mat.roughness)),
+            MaterialDescription::Dielectric(mat) => mat.to_material(),
             MaterialDescription::Fresnel(mat) => 
                 Material::fresnel_combination(
-                    mat.diffuse.into(),
-                    mat.specular.into(),
+                    mat.diffuse.to_material(library),
+                    mat.specular.to_material(library),
                     mat.refractive_index
                 ),
+            MaterialDescription::Mix(mat) =>
+                Material::mix(
+                    mat.first.to_material(library),
+                    mat.second.to_material(library),
+                    mat.weight.to_mix_weight()
+                ),
+            MaterialDescription::Null => Material::null(),
+            MaterialDescription::Named { name } => match library.get(name) {
+                Some(material) => material.clone(),
+                None => panic!("Unknown material '{}'", name),
+            },
         }
     }
-}
 
-impl From<BasicMaterialDescription> for BasicMaterial {
-    fn from(desc: BasicMaterialDescription) -> BasicMaterial {
-        match desc {
-            BasicMaterialDescription::Lambertian(mat) => Material::lambertian(
-                mat.albedo.to_material_colour(), Colour::BLACK
-            ).to_basic(),
-            BasicMaterialDescription::Gloss(mat) => Material::gloss(mat.albedo.to_material_colour(), mat.reflectance, mat.metalness).to_basic(),
-            BasicMaterialDescription::Mirror(_mat) => Material::mirror().to_basic(),
-            BasicMaterialDescription::CookTorrance(mat) => Material::cook_torrance(mat.albedo.to_colour(), mat.roughness).to_basic(),
+    // Names of all the materials this refers to, directly or within a mix.
+    fn references(&self) -> Vec<&String> {
+        match self {
+            MaterialDescription::Named { name } => vec![name],
+            MaterialDescription::Fresnel(mat) => {
+                let mut names = mat.diffuse.references();
+                names.append(&mut mat.specular.references());
+                names
+            },
+            MaterialDescription::Mix(mat) => {
+                let mut names = mat.first.references();
+                names.append(&mut mat.second.references());
+                names
+            },
+            _ => vec![],
         }
     }
 }
 
+// Named materials are built once and shared between all the objects that use them, so any
+// textures only get loaded once.
+fn build_material_library(descriptions: &HashMap<String, MaterialDescription>) -> HashMap<String, Material> {
+    let mut library = HashMap::new();
+    let mut in_progress = Vec::new();
+    descriptions.keys().for_each(|name| {
+        add_to_material_library(name, descriptions, &mut library, &mut in_progress);
+    });
+    library
+}
+
+fn add_to_material_library(
+    name: &String,
+    descriptions: &HashMap<String, MaterialDescription>,
+    library: &mut HashMap<String, Material>,
+    in_progress: &mut Vec<String>,
+) {
+    if library.contains_key(name) {
+        return;
+    }
+
+    if in_progress.contains(name) {
+        panic!("Material '{}' refers to itself", name);
+    }
+
+    let description = match descriptions.get(name) {
+        Some(desc) => desc,
+        None => panic!("Unknown material '{}'", name),
+    };
+
+    // Auto means "whatever the model says", so there's nothing to build until it's on a mesh.
+    if let MaterialDescription::Auto = description {
+        panic!("Material '{}' can't be Auto, which only works directly on an object", name);
+    }
+
+    // Build anything this material refers to first.
+    in_progress.push(name.clone());
+    description.references().iter().for_each(|reference| {
+        add_to_material_library(reference, descriptions, library, in_progress);
+    });
+    in_progress.pop();
+
+    println!("Building material '{}'", name);
+    let material = description.to_material(library);
+    library.insert(name.clone(), material);
+}
+
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct LambertianMaterialDescription {
     pub albedo: MaterialColourDescription,
+    #[serde(default)]
+    pub emission: Option<EmissionDescription>,
+}
+
+// Light given off by a surface.  The brightness is either a plain `intensity`, which is the
+// radiance, or the `luminance` in candela per square metre.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct EmissionDescription {
+    // Tints the blackbody colour if `temperature` is given too.
+    #[serde(default = "default_tint")]
+    pub colour: ColourDescription,
+    // In Kelvin.
+    #[serde(default)]
+    pub temperature: Option<f64>,
+    #[serde(default)]
+    pub intensity: Option<f64>,
+    #[serde(default)]
+    pub luminance: Option<f64>,
+}
+
+impl EmissionDescription {
+    pub fn to_emittance(&self) -> Colour {
+        let intensity = match (self.intensity, self.luminance) {
+            (Some(intensity), None) => intensity,
+            (None, Some(luminance)) => luminance / LUMENS_PER_WATT,
+            (None, None) => panic!("Emission needs an intensity or luminance"),
+            (Some(_), Some(_)) => panic!("Emission can't have both an intensity and a luminance"),
+        };
+        emission_colour(self.colour, self.temperature) * intensity
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct OrenNayarMaterialDescription {
+    pub albedo: MaterialColourDescription,
+    // 0 is lambertian, 1 is very rough.
+    pub roughness: f64,
 }
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
@@ -326,10 +758,172 @@ pub struct CookTorranceMaterialDescription {
 }
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct DielectricMaterialDescription {
+    // Not needed if `dispersion` is given.
+    #[serde(default)]
+    pub refractive_index: Option<f64>,
+    #[serde(default = "default_tint")]
+    pub tint: ColourDescription,
+    // How the refractive index varies with wavelength.  Only has an effect in spectral mode.
+    #[serde(default)]
+    pub dispersion: Option<DispersionDescription>,
+}
+
+impl DielectricMaterialDescription {
+    pub fn to_material(&self) -> Material {
+        match (self.refractive_index, self.dispersion) {
+            (refractive_index, Some(dispersion)) => {
+                if refractive_index.is_some() {
+                    println!("[WARN] Ignoring refractive index of dispersive material");
+                }
+                Material::dispersive_dielectric(dispersion.to_dispersion(), self.tint.to_colour())
+            },
+            (Some(refractive_index), None) => Material::dielectric(refractive_index, self.tint.to_colour()),
+            (None, None) => panic!("Dielectric material needs either a refractive index or dispersion"),
+        }
+    }
+}
+
+// Coefficients are for wavelengths in micrometres.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum DispersionDescription {
+    Cauchy { a: f64, b: f64 },
+    Sellmeier { b: [f64; 3], c: [f64; 3] },
+    BK7,
+    FusedSilica,
+    Diamond,
+}
+
+impl DispersionDescription {
+    pub fn to_dispersion(&self) -> Dispersion {
+        match self {
+            DispersionDescription::Cauchy { a, b } => Dispersion::Cauchy { a: *a, b: *b },
+            DispersionDescription::Sellmeier { b, c } => Dispersion::Sellmeier { b: *b, c: *c },
+            DispersionDescription::BK7 => Dispersion::BK7,
+            DispersionDescription::FusedSilica => Dispersion::FUSED_SILICA,
+            DispersionDescription::Diamond => Dispersion::DIAMOND,
+        }
+    }
+}
+
+fn default_tint() -> ColourDescription {
+    ColourDescription { r: 1.0, g: 1.0, b: 1.0 }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct FresnelMaterialDescription {
     pub refractive_index: f64,
-    pub diffuse: BasicMaterialDescription,
-    pub specular: BasicMaterialDescription,
+    pub diffuse: Box<MaterialDescription>,
+    pub specular: Box<MaterialDescription>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MixMaterialDescription {
+    pub first: Box<MaterialDescription>,
+    pub second: Box<MaterialDescription>,
+    // How much of the second material to use.
+    pub weight: MixWeightDescription,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum MixWeightDescription {
+    Constant { value: f64 },
+    Fresnel { refractive_index: f64 },
+    Texture { file: String },
+    Checker { scale: f64 },
+}
+
+impl MixWeightDescription {
+    pub fn to_mix_weight(&self) -> MixWeight {
+        match self {
+            MixWeightDescription::Constant { value } => MixWeight::Constant(*value),
+            MixWeightDescription::Fresnel { refractive_index } => MixWeight::fresnel(*refractive_index),
+            MixWeightDescription::Texture { file } => MixWeight::Texture(Arc::new(Texture::load(file))),
+            MixWeightDescription::Checker { scale } => MixWeight::Checker(*scale),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum MediumDescription {
+    Homogeneous(HomogeneousMediumDescription),
+}
+
+impl MediumDescription {
+    pub fn to_medium(&self) -> Medium {
+        match self {
+            MediumDescription::Homogeneous(m) => Medium::homogeneous(
+                m.absorption.to_colour(),
+                m.scattering.to_colour(),
+                m.anisotropy,
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct HomogeneousMediumDescription {
+    pub absorption: ColourDescription,
+    pub scattering: ColourDescription,
+
+    // Henyey-Greenstein g parameter.  0 for isotropic scattering.
+    #[serde(default)]
+    pub anisotropy: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VolumeDescription {
+    pub grid: GridDescription,
+    pub translation: VectorDescription,
+    pub rotation: RotationDescription,
+    // Length of the longest side of the grid.
+    pub scale: f64,
+
+    // Coefficients at density 1.
+    pub absorption: ColourDescription,
+    pub scattering: ColourDescription,
+
+    #[serde(default)]
+    pub anisotropy: f64,
+
+    // Multiplier applied to all densities in the grid.
+    #[serde(default = "default_density")]
+    pub density: f64,
+}
+
+fn default_density() -> f64 {
+    1.0
+}
+
+impl VolumeDescription {
+    pub fn to_grid_medium(&self) -> GridMedium {
+        let grid = match self.grid {
+            GridDescription::Vol { ref file } => volume::load_vol_file(file),
+            GridDescription::Slices { ref directory } => volume::load_image_slices(directory),
+        };
+
+        GridMedium::new(
+            Arc::new(grid),
+            self.absorption.to_colour() * self.density,
+            self.scattering.to_colour() * self.density,
+            self.anisotropy,
+            self.translation.to_vector(),
+            Matrix3::rotation(self.rotation.yaw, self.rotation.pitch, self.rotation.roll),
+            self.scale,
+        )
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum GridDescription {
+    // Mitsuba .vol file.
+    Vol { file: String },
+    // Directory of images, one per z-slice.
+    Slices { directory: String },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -338,6 +932,7 @@ pub enum SkyboxDescription {
     Flat(FlatSkyboxDescription),
     Gradient(GradientSkyboxDescription),
     Hdri(HdriSkyboxDescription),
+    Sky(SkyDescription),
 }
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
@@ -354,6 +949,147 @@ pub struct GradientSkyboxDescription {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct HdriSkyboxDescription {
     pub filename: String,
+    #[serde(default = "default_hdri_layout")]
+    pub layout: HdriLayoutDescription,
+    #[serde(default = "default_hdri_filter")]
+    pub filter: HdriFilterDescription,
+    // Turns the environment round, e.g. yaw to move the sun.
+    #[serde(default)]
+    pub rotation: RotationDescription,
+    #[serde(default = "default_hdri_intensity")]
+    pub intensity: f64,
+    #[serde(default = "default_tint")]
+    pub tint: ColourDescription,
+    // Projects the bottom half onto the ground.
+    #[serde(default)]
+    pub ground: Option<GroundProjectionDescription>,
+}
+
+// `center` is the point on the ground under where the HDRI was taken, and `height` is how far
+// above the ground it was taken.  The ground covers a disk of the given radius.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct GroundProjectionDescription {
+    pub center: VectorDescription,
+    pub height: f64,
+    pub radius: f64,
+}
+
+fn default_hdri_layout() -> HdriLayoutDescription {
+    HdriLayoutDescription::Equirectangular
+}
+
+fn default_hdri_filter() -> HdriFilterDescription {
+    HdriFilterDescription::Nearest
+}
+
+fn default_hdri_intensity() -> f64 {
+    1.0
+}
+
+impl HdriSkyboxDescription {
+    fn to_settings(&self) -> scene::HdriSettings {
+        scene::HdriSettings {
+            layout: self.layout.to_layout(),
+            filter: self.filter.to_filter(),
+            rotation: Matrix3::rotation(self.rotation.yaw, self.rotation.pitch, self.rotation.roll),
+            scale: self.tint.to_colour() * self.intensity,
+            ground: self.ground.map(|g| scene::GroundProjection::new(g.center.to_vector(), g.height, g.radius)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum HdriLayoutDescription {
+    Equirectangular,
+    CubeMap,
+    Angular,
+}
+
+impl HdriLayoutDescription {
+    fn to_layout(self) -> scene::HdriLayout {
+        match self {
+            HdriLayoutDescription::Equirectangular => scene::HdriLayout::Equirectangular,
+            HdriLayoutDescription::CubeMap => scene::HdriLayout::CubeMap,
+            HdriLayoutDescription::Angular => scene::HdriLayout::Angular,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum HdriFilterDescription {
+    Nearest,
+    Bilinear,
+    Bicubic,
+}
+
+impl HdriFilterDescription {
+    fn to_filter(self) -> scene::HdriFilter {
+        match self {
+            HdriFilterDescription::Nearest => scene::HdriFilter::Nearest,
+            HdriFilterDescription::Bilinear => scene::HdriFilter::Bilinear,
+            HdriFilterDescription::Bicubic => scene::HdriFilter::Bicubic,
+        }
+    }
+}
+
+// Gradients go from the top of the screen to the bottom.  Images are stretched to fill it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum BackgroundDescription {
+    Flat { colour: ColourDescription },
+    Gradient { top: ColourDescription, bottom: ColourDescription },
+    Image { filename: String },
+}
+
+impl BackgroundDescription {
+    pub fn to_background(&self) -> scene::Background {
+        match self {
+            BackgroundDescription::Flat { colour } => scene::Background::Flat(colour.to_colour()),
+            BackgroundDescription::Gradient { top, bottom } => scene::Background::Gradient {
+                top: top.to_colour(),
+                bottom: bottom.to_colour(),
+            },
+            BackgroundDescription::Image { filename } => scene::Background::Image(Arc::new(Texture::load(filename))),
+        }
+    }
+}
+
+// A physically based daylight sky, which also adds a sun light to the scene.
+// It's in physical units, so needs a camera exposure of around -6.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct SkyDescription {
+    pub sun: SunDescription,
+    // From 2 for a very clear sky to 10 for a hazy one.
+    #[serde(default = "default_turbidity")]
+    pub turbidity: f64,
+    // Leave out the sun light, e.g. to add your own.
+    #[serde(default)]
+    pub no_sun_light: bool,
+}
+
+fn default_turbidity() -> f64 {
+    3.0
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum SunDescription {
+    // In radians.  Azimuth is clockwise from +z towards +x.
+    Angles { elevation: f64, azimuth: f64 },
+    // Latitude in degrees, day of the year, and local solar time in hours.  +z is north.
+    Location { latitude: f64, day: f64, time: f64 },
+}
+
+impl SunDescription {
+    pub fn to_direction(&self) -> Vector3 {
+        match self {
+            SunDescription::Angles { elevation, azimuth } => sky::sun_direction(*elevation, *azimuth),
+            SunDescription::Location { latitude, day, time } => {
+                let (elevation, azimuth) = sky::sun_position(*latitude, *day, *time);
+                sky::sun_direction(elevation, azimuth)
+            },
+        }
+    }
 }
 
 impl SkyboxDescription {
@@ -378,8 +1114,146 @@ impl SkyboxDescription {
 
                 println!("Loaded HDRI file with metadata: {:?}", metadata);
 
-                scene::Skybox::hdri(metadata.width, metadata.height, colour_data)
+                scene::Skybox::hdri(metadata.width, metadata.height, colour_data, sky.to_settings())
             },
+            SkyboxDescription::Sky(sky) => scene::Skybox::sky(sky.sun.to_direction(), sky.turbidity),
+        }
+    }
+
+    // The sun to go with the sky, if there is one.
+    pub fn to_sun(&self, id: usize) -> Option<scene::Light> {
+        match self {
+            SkyboxDescription::Sky(sky) if !sky.no_sun_light => {
+                let direction = sky.sun.to_direction();
+                let radiance = sky::sun_radiance(direction, sky.turbidity);
+                if radiance.luminance() <= 0.0 {
+                    println!("[WARN] The sun is below the horizon");
+                    return None;
+                }
+
+                Some(scene::Light {
+                    id,
+                    geometry: scene::LightGeometry::Distant(
+                        scene::DistantLight::new(direction, sky::SUN_ANGULAR_DIAMETER)
+                    ),
+                    colour: radiance / radiance.luminance(),
+                    intensity: radiance.luminance(),
+                    profile: None,
+                    linking: None,
+                })
+            },
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use crate::material::Material;
+    use crate::serde::{build_material_library, LightDescription, MaterialDescription, SceneDescription};
+
+    #[test]
+    fn test_material_library() {
+        let descriptions: HashMap<String, MaterialDescription> = serde_yaml::from_str("
+            coated:
+              type: Fresnel
+              refractive_index: 1.5
+              diffuse: { type: Named, name: red }
+              specular: { type: Mirror }
+            red:
+              type: Lambertian
+              albedo: { type: Rgb, r: 1.0, g: 0.0, b: 0.0 }
+        ").unwrap();
+
+        let library = build_material_library(&descriptions);
+        assert_eq!(library.len(), 2);
+        match library.get("coated") {
+            Some(Material::Mix(_)) => (),
+            other => panic!("Expected a mix material, got {:?}", other),
         }
     }
+
+    #[test]
+    #[should_panic(expected = "refers to itself")]
+    fn test_material_library_cycle() {
+        let descriptions: HashMap<String, MaterialDescription> = serde_yaml::from_str("
+            a:
+              type: Mix
+              weight: { type: Constant, value: 0.5 }
+              first: { type: Named, name: b }
+              second: { type: Mirror }
+            b: { type: Named, name: a }
+        ").unwrap();
+
+        build_material_library(&descriptions);
+    }
+
+    #[test]
+    #[should_panic(expected = "can't be Auto")]
+    fn test_material_library_auto() {
+        let descriptions: HashMap<String, MaterialDescription> = serde_yaml::from_str("
+            a:
+              type: Mix
+              weight: { type: Constant, value: 0.5 }
+              first: { type: Named, name: b }
+              second: { type: Mirror }
+            b: { type: Auto }
+        ").unwrap();
+
+        build_material_library(&descriptions);
+    }
+
+    #[test]
+    #[should_panic(expected = "only meshes have")]
+    fn test_checker_mix_on_sphere() {
+        let description: SceneDescription = serde_yaml::from_str("
+            camera:
+              image_width: 32
+              image_height: 24
+              location: { x: 0.0, y: 0.0, z: -5.0 }
+              orientation: { pitch: 0.0, yaw: 0.0, roll: 0.0 }
+              sensor_width: 0.036
+              sensor_height: 0.024
+              focal_length: 0.05
+              focus_distance: 5.0
+              aperture: 8.0
+            skybox: { type: Flat, colour: { r: 1.0, g: 1.0, b: 1.0 } }
+            lights: []
+            objects:
+              - shape: { type: Sphere, radius: 1.0, center: { x: 0.0, y: 0.0, z: 0.0 } }
+                material:
+                  type: Mix
+                  weight: { type: Checker, scale: 4.0 }
+                  first: { type: Mirror }
+                  second: { type: Lambertian, albedo: { type: Rgb, r: 0.5, g: 0.5, b: 0.5 } }
+        ").unwrap();
+
+        description.scene();
+    }
+
+    #[test]
+    fn test_light_linking() {
+        let light: LightDescription = serde_yaml::from_str("
+            intensity: 1.0
+            geometry: { type: Point, x: 0.0, y: 1.0, z: 0.0 }
+            include: [floor, table]
+            exclude: [table]
+            shadow_exclude: [lamp]
+        ").unwrap();
+
+        let mut names = HashMap::new();
+        names.insert("floor", vec![0]);
+        names.insert("table", vec![1, 2]);
+        names.insert("lamp", vec![3]);
+        let light = light.to_light(0, &names);
+
+        assert!(light.illuminates(Some(0)));
+        assert!(!light.illuminates(Some(1)));
+        assert!(!light.illuminates(Some(3)));
+        assert!(light.illuminates(None));
+        assert!(light.casts_shadows_from(2));
+        assert!(!light.casts_shadows_from(3));
+    }
 }
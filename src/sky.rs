@@ -0,0 +1,183 @@
+use std::f64::consts::PI;
+
+use crate::colour::Colour;
+use crate::spectrum;
+use crate::spectrum::LUMENS_PER_WATT;
+use crate::vector::Vector3;
+
+// Daylight is in physical units, where a radiance of 1 is 683 candela per square metre.  Sunlit
+// scenes need a camera exposure of around -6 to come out right.
+
+// Luminance of the sun's disk when seen from space.
+const SUN_LUMINANCE: f64 = 1.6e9;
+const SUN_TEMPERATURE: f64 = 5778.0;
+
+// The sun subtends about half a degree.
+pub const SUN_ANGULAR_DIAMETER: f64 = 0.0093;
+
+// Unit vector pointing at the sun.
+// Elevation is the angle above the horizon, and azimuth is the angle clockwise from +z (north)
+// towards +x (east).  Both in radians.
+pub fn sun_direction(elevation: f64, azimuth: f64) -> Vector3 {
+    Vector3::new(
+        elevation.cos() * azimuth.sin(),
+        elevation.sin(),
+        elevation.cos() * azimuth.cos(),
+    )
+}
+
+// Approximate position of the sun as (elevation, azimuth), given the latitude in degrees, the day
+// of the year (1 is January 1st) and the local solar time in hours.
+pub fn sun_position(latitude: f64, day: f64, time: f64) -> (f64, f64) {
+    let latitude = latitude.to_radians();
+    let declination = -23.44f64.to_radians() * (2.0 * PI * (day + 10.0) / 365.0).cos();
+    let hour_angle = (15.0 * (time - 12.0)).to_radians();
+
+    let sin_elevation = latitude.sin() * declination.sin()
+        + latitude.cos() * declination.cos() * hour_angle.cos();
+    let elevation = sin_elevation.clamp(-1.0, 1.0).asin();
+
+    let cos_azimuth = (declination.sin() - sin_elevation * latitude.sin())
+        / (elevation.cos() * latitude.cos());
+    let azimuth = cos_azimuth.clamp(-1.0, 1.0).acos();
+
+    // The sun is in the east in the morning, and the west in the afternoon.
+    if hour_angle > 0.0 {
+        (elevation, 2.0 * PI - azimuth)
+    } else {
+        (elevation, azimuth)
+    }
+}
+
+// Preetham, Shirley and Smits, "A Practical Analytic Model for Daylight".
+// Turbidity goes from 2 for a very clear sky to 10 for a hazy one.
+// The sun itself isn't included, that's a separate light.
+#[derive(Clone, Debug)]
+pub struct PreethamSky {
+    sun: Vector3,
+    // Perez coefficients for each of Y, x and y.
+    perez: [[f64; 5]; 3],
+    // Value at the zenith, divided by the Perez function at the zenith.
+    scale: [f64; 3],
+}
+
+impl PreethamSky {
+    pub fn new(sun: Vector3, turbidity: f64) -> PreethamSky {
+        let t = turbidity.clamp(1.7, 10.0);
+        let sun = sun.normed();
+        // Below the horizon the model falls apart, so keep the sun just above it.
+        let theta_sun = f64::min(sun.y.clamp(-1.0, 1.0).acos(), PI / 2.0 - 0.01);
+
+        let perez = [
+            [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+            [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+            [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+        ];
+
+        // Zenith luminance in kcd/m^2, and chromaticity.
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_sun);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+
+        let th = [theta_sun.powi(3), theta_sun.powi(2), theta_sun, 1.0];
+        let dot = |c: [f64; 4]| c[0] * th[0] + c[1] * th[1] + c[2] * th[2] + c[3] * th[3];
+        let zenith_x = t * t * dot([0.00166, -0.00375, 0.00209, 0.0])
+            + t * dot([-0.02903, 0.06377, -0.03202, 0.00394])
+            + dot([0.11693, -0.21196, 0.06052, 0.25886]);
+        let zenith_y = t * t * dot([0.00275, -0.00610, 0.00317, 0.0])
+            + t * dot([-0.04214, 0.08970, -0.04153, 0.00516])
+            + dot([0.15346, -0.26756, 0.06670, 0.26688]);
+
+        let zenith = [zenith_luminance, zenith_x, zenith_y];
+        let mut scale = [0.0; 3];
+        for ix in 0 .. 3 {
+            scale[ix] = zenith[ix] / perez_function(&perez[ix], 0.0, theta_sun);
+        }
+
+        PreethamSky { sun, perez, scale }
+    }
+
+    pub fn radiance(&self, direction: Vector3) -> Colour {
+        // Below the horizon, carry on the colour at the horizon.
+        let cos_theta = f64::max(0.001, direction.y);
+        let direction = Vector3::new(direction.x, cos_theta, direction.z).normed();
+        let theta = direction.y.acos();
+        let gamma = direction.dot(self.sun).clamp(-1.0, 1.0).acos();
+
+        let luminance = self.scale[0] * perez_function(&self.perez[0], theta, gamma);
+        let x = self.scale[1] * perez_function(&self.perez[1], theta, gamma);
+        let y = self.scale[2] * perez_function(&self.perez[2], theta, gamma);
+
+        // kcd/m^2 to our units.
+        let luminance = f64::max(0.0, luminance) * 1000.0 / LUMENS_PER_WATT;
+        let xyz = Colour::rgb(x / y * luminance, luminance, (1.0 - x - y) / y * luminance);
+        spectrum::xyz_to_rgb(xyz)
+    }
+}
+
+fn perez_function(c: &[f64; 5], theta: f64, gamma: f64) -> f64 {
+    let cos_gamma = gamma.cos();
+    (1.0 + c[0] * (c[1] / theta.cos()).exp())
+        * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * cos_gamma * cos_gamma)
+}
+
+// Radiance of the sun's disk after passing through the atmosphere, using the Rayleigh and aerosol
+// scattering from the appendix of the Preetham paper.
+pub fn sun_radiance(sun: Vector3, turbidity: f64) -> Colour {
+    let theta = f64::min(sun.normed().y.clamp(-1.0, 1.0).acos(), PI / 2.0);
+    if theta >= PI / 2.0 {
+        return Colour::BLACK;
+    }
+
+    // How much air the light passes through, relative to straight up.
+    let optical_mass = 1.0 / (theta.cos() + 0.15 * (93.885 - theta.to_degrees()).powf(-1.253));
+    let beta = 0.046_08 * turbidity - 0.045_86;
+
+    let spectrum = |lambda: f64| {
+        let micrometres = lambda / 1000.0;
+        let rayleigh = (-0.008_735 * micrometres.powf(-4.08) * optical_mass).exp();
+        let aerosol = (-beta * micrometres.powf(-1.3) * optical_mass).exp();
+        spectrum::planck(lambda, SUN_TEMPERATURE) * rayleigh * aerosol
+    };
+
+    let unattenuated = spectrum::spectrum_to_rgb(|lambda| spectrum::planck(lambda, SUN_TEMPERATURE));
+    let attenuated = spectrum::spectrum_to_rgb(spectrum);
+    attenuated * (SUN_LUMINANCE / LUMENS_PER_WATT / unattenuated.luminance())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::sky::*;
+
+    #[test]
+    fn test_sun_position() {
+        // Midsummer noon in London.
+        let (elevation, azimuth) = sun_position(51.5, 172.0, 12.0);
+        assert!((elevation.to_degrees() - 62.0).abs() < 1.0, "{}", elevation.to_degrees());
+        assert!((azimuth - PI).abs() < 1e-6);
+
+        // Rises in the east, sets in the west.
+        let (_, morning) = sun_position(51.5, 172.0, 8.0);
+        let (_, evening) = sun_position(51.5, 172.0, 16.0);
+        assert!(sun_direction(0.0, morning).x > 0.0);
+        assert!(sun_direction(0.0, evening).x < 0.0);
+    }
+
+    #[test]
+    fn test_preetham_sky() {
+        let sun = sun_direction(0.5, 0.0);
+        let sky = PreethamSky::new(sun, 3.0);
+
+        // Blue overhead, brighter around the sun.
+        let zenith = sky.radiance(Vector3::new(0.0, 1.0, 0.0));
+        assert!(zenith.b > zenith.r, "{:?}", zenith);
+        let near_sun = sky.radiance(sun_direction(0.55, 0.0));
+        let away = sky.radiance(sun_direction(0.55, PI));
+        assert!(near_sun.luminance() > away.luminance());
+
+        // Low sun is redder than high sun.
+        let high = sun_radiance(sun_direction(1.2, 0.0), 3.0);
+        let low = sun_radiance(sun_direction(0.05, 0.0), 3.0);
+        assert!(high.luminance() > low.luminance());
+        assert!(low.r / low.b > high.r / high.b);
+    }
+}
@@ -0,0 +1,280 @@
+use rand;
+use rand::Rng;
+
+use crate::colour::Colour;
+
+// In spectral mode each path carries three wavelengths at once, one in each channel of a
+// `Colour`.  The first "hero" wavelength is picked uniformly at random, and the other two are
+// spaced evenly around the visible range from it.
+// RGB colours in the scene are upsampled to spectra along the way, and the result of each path is
+// projected back onto the CIE XYZ colour matching functions.
+pub const MIN_WAVELENGTH: f64 = 380.0;
+pub const MAX_WAVELENGTH: f64 = 720.0;
+const WAVELENGTH_RANGE: f64 = MAX_WAVELENGTH - MIN_WAVELENGTH;
+
+// Peak luminous efficacy, for converting between photometric and radiometric units.
+pub const LUMENS_PER_WATT: f64 = 683.0;
+
+// Integral of the Y colour matching function over the visible range.
+const CIE_Y_INTEGRAL: f64 = 106.911_868;
+
+// XYZ of a constant spectrum, normalized so that Y = 1.
+const EQUAL_ENERGY_WHITE: Colour = Colour { r: 0.998_586, g: 1.0, b: 0.999_191 };
+
+#[derive(Clone, Copy, Debug)]
+pub struct Wavelengths {
+    pub lambdas: [f64; 3],
+    // Whether the path is only following the hero wavelength.
+    pub hero_only: bool,
+}
+
+impl Wavelengths {
+    pub fn sample() -> Wavelengths {
+        let u: f64 = rand::thread_rng().gen();
+        Wavelengths::from_hero(MIN_WAVELENGTH + u * WAVELENGTH_RANGE)
+    }
+
+    pub fn from_hero(hero: f64) -> Wavelengths {
+        let rotate = |ix: f64| {
+            MIN_WAVELENGTH + (hero - MIN_WAVELENGTH + ix * WAVELENGTH_RANGE / 3.0) % WAVELENGTH_RANGE
+        };
+        Wavelengths { lambdas: [rotate(0.0), rotate(1.0), rotate(2.0)], hero_only: false }
+    }
+
+    pub fn hero(&self) -> f64 {
+        self.lambdas[0]
+    }
+
+    // Stop following all but the hero wavelength, e.g. when dispersion sends each wavelength a
+    // different way.  Returns the path's throughput, reweighted to account for it.
+    pub fn terminate_secondary(&mut self, throughput: Colour) -> Colour {
+        if self.hero_only {
+            return throughput;
+        }
+
+        self.hero_only = true;
+        Colour::rgb(throughput.r * 3.0, 0.0, 0.0)
+    }
+
+    // The value of the spectrum matching an RGB colour at each of the wavelengths.
+    pub fn upsample(&self, rgb: Colour) -> Colour {
+        Colour::rgb(
+            smits(rgb, self.lambdas[0]),
+            smits(rgb, self.lambdas[1]),
+            smits(rgb, self.lambdas[2]),
+        )
+    }
+
+    // Estimate the XYZ colour of a spectrum from its value at each of the wavelengths.
+    // Normalized so that a constant spectrum of 1 has Y = 1.
+    pub fn to_xyz(&self, values: Colour) -> Colour {
+        let samples = [values.r, values.g, values.b];
+        let mut xyz = Colour::BLACK;
+        for (lambda, value) in self.lambdas.iter().zip(samples.iter()) {
+            xyz += Colour::rgb(cie_x(*lambda), cie_y(*lambda), cie_z(*lambda)) * *value;
+        }
+
+        // Each wavelength has pdf 1 / WAVELENGTH_RANGE.
+        xyz * (WAVELENGTH_RANGE / (3.0 * CIE_Y_INTEGRAL))
+    }
+}
+
+// XYZ to linear sRGB.
+// The sRGB primaries are defined relative to D65, but our spectra are relative to a constant
+// spectrum, so white balance so that it comes out white.
+pub fn xyz_to_rgb(xyz: Colour) -> Colour {
+    let rgb = linear_srgb(xyz);
+    let white = linear_srgb(EQUAL_ENERGY_WHITE);
+    Colour::rgb(
+        f64::max(0.0, rgb.r / white.r),
+        f64::max(0.0, rgb.g / white.g),
+        f64::max(0.0, rgb.b / white.b),
+    )
+}
+
+// The colour of a blackbody at the given temperature in Kelvin, normalised to a luminance of 1.
+// Since we white balance to a constant spectrum, about 5500K comes out white.
+pub fn blackbody(temperature: f64) -> Colour {
+    let rgb = spectrum_to_rgb(|lambda| planck(lambda, temperature));
+    rgb / rgb.luminance()
+}
+
+// The colour of a spectrum, given as a function of wavelength in nanometres.
+pub fn spectrum_to_rgb<F: Fn(f64) -> f64>(spectrum: F) -> Colour {
+    let steps = 100;
+    let step = WAVELENGTH_RANGE / steps as f64;
+    let mut xyz = Colour::BLACK;
+    for ix in 0 .. steps {
+        let lambda = MIN_WAVELENGTH + (ix as f64 + 0.5) * step;
+        xyz += Colour::rgb(cie_x(lambda), cie_y(lambda), cie_z(lambda)) * spectrum(lambda);
+    }
+
+    xyz_to_rgb(xyz * (step / CIE_Y_INTEGRAL))
+}
+
+// Spectral radiance of a blackbody, with the wavelength in nanometres.
+pub fn planck(lambda: f64, temperature: f64) -> f64 {
+    const H: f64 = 6.626_070_15e-34;
+    const C: f64 = 2.997_924_58e8;
+    const K: f64 = 1.380_649e-23;
+
+    let l = lambda * 1e-9;
+    2.0 * H * C * C / (l.powi(5) * ((H * C / (l * K * temperature)).exp() - 1.0))
+}
+
+fn linear_srgb(xyz: Colour) -> Colour {
+    Colour::rgb(
+        3.240_454_2 * xyz.r - 1.537_138_5 * xyz.g - 0.498_531_4 * xyz.b,
+        -0.969_266_0 * xyz.r + 1.876_010_8 * xyz.g + 0.041_556_0 * xyz.b,
+        0.055_643_4 * xyz.r - 0.204_025_9 * xyz.g + 1.057_225_2 * xyz.b,
+    )
+}
+
+// The CIE 1931 colour matching functions, using the multi-lobe fit from Wyman, Sloan and Shirley,
+// "Simple Analytic Approximations to the CIE XYZ Color Matching Functions".
+fn cie_x(lambda: f64) -> f64 {
+    1.056 * lobe(lambda, 599.8, 37.9, 31.0)
+        + 0.362 * lobe(lambda, 442.0, 16.0, 26.7)
+        - 0.065 * lobe(lambda, 501.1, 20.4, 26.2)
+}
+
+fn cie_y(lambda: f64) -> f64 {
+    0.821 * lobe(lambda, 568.8, 46.9, 40.5)
+        + 0.286 * lobe(lambda, 530.9, 16.3, 31.1)
+}
+
+fn cie_z(lambda: f64) -> f64 {
+    1.217 * lobe(lambda, 437.0, 11.8, 36.0)
+        + 0.681 * lobe(lambda, 459.0, 26.0, 13.8)
+}
+
+// A gaussian with a different width either side of the mean.
+fn lobe(lambda: f64, mean: f64, sigma_below: f64, sigma_above: f64) -> f64 {
+    let sigma = if lambda < mean { sigma_below } else { sigma_above };
+    let t = (lambda - mean) / sigma;
+    (-0.5 * t * t).exp()
+}
+
+// Basis spectra from Smits, "An RGB to Spectrum Conversion for Reflectances", sampled at 10
+// evenly spaced wavelengths across the visible range.
+const SMITS_WHITE: [f64; 10] = [1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000];
+const SMITS_CYAN: [f64; 10] = [0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000];
+const SMITS_MAGENTA: [f64; 10] = [1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959];
+const SMITS_YELLOW: [f64; 10] = [0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840];
+const SMITS_RED: [f64; 10] = [0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149];
+const SMITS_GREEN: [f64; 10] = [0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025];
+const SMITS_BLUE: [f64; 10] = [1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496];
+
+// Build the spectrum from white, plus whichever secondary and primary colours make up the rest.
+fn smits(rgb: Colour, lambda: f64) -> f64 {
+    let basis = |spectrum: &[f64; 10]| interpolate(spectrum, lambda);
+    let (r, g, b) = (f64::max(0.0, rgb.r), f64::max(0.0, rgb.g), f64::max(0.0, rgb.b));
+
+    if r <= g && r <= b {
+        if g <= b {
+            r * basis(&SMITS_WHITE) + (g - r) * basis(&SMITS_CYAN) + (b - g) * basis(&SMITS_BLUE)
+        } else {
+            r * basis(&SMITS_WHITE) + (b - r) * basis(&SMITS_CYAN) + (g - b) * basis(&SMITS_GREEN)
+        }
+    } else if g <= r && g <= b {
+        if r <= b {
+            g * basis(&SMITS_WHITE) + (r - g) * basis(&SMITS_MAGENTA) + (b - r) * basis(&SMITS_BLUE)
+        } else {
+            g * basis(&SMITS_WHITE) + (b - g) * basis(&SMITS_MAGENTA) + (r - b) * basis(&SMITS_RED)
+        }
+    } else if r <= g {
+        b * basis(&SMITS_WHITE) + (r - b) * basis(&SMITS_YELLOW) + (g - r) * basis(&SMITS_GREEN)
+    } else {
+        b * basis(&SMITS_WHITE) + (g - b) * basis(&SMITS_YELLOW) + (r - g) * basis(&SMITS_RED)
+    }
+}
+
+fn interpolate(spectrum: &[f64; 10], lambda: f64) -> f64 {
+    let x = (lambda - MIN_WAVELENGTH) / WAVELENGTH_RANGE * 9.0;
+    let ix = x.floor().clamp(0.0, 8.0) as usize;
+    let t = (x - ix as f64).clamp(0.0, 1.0);
+    spectrum[ix] * (1.0 - t) + spectrum[ix + 1] * t
+}
+
+#[cfg(test)]
+mod test {
+    use crate::spectrum::*;
+
+    fn integrate<F: Fn(f64) -> f64>(f: F) -> f64 {
+        let n = 10000;
+        let step = WAVELENGTH_RANGE / n as f64;
+        (0 .. n).map(|ix| f(MIN_WAVELENGTH + (ix as f64 + 0.5) * step) * step).sum()
+    }
+
+    // Render a colour through the spectral pipeline, averaging over many sets of wavelengths.
+    fn round_trip(rgb: Colour) -> Colour {
+        let n = 2000;
+        let mut xyz = Colour::BLACK;
+        for ix in 0 .. n {
+            let hero = MIN_WAVELENGTH + (ix as f64 + 0.5) / n as f64 * WAVELENGTH_RANGE;
+            let wavelengths = Wavelengths::from_hero(hero);
+            xyz += wavelengths.to_xyz(wavelengths.upsample(rgb));
+        }
+        xyz_to_rgb(xyz / n)
+    }
+
+    #[test]
+    fn test_normalization() {
+        let y = integrate(cie_y);
+        assert!((y - CIE_Y_INTEGRAL).abs() < 1e-3, "{}", y);
+        assert!((integrate(cie_x) / y - EQUAL_ENERGY_WHITE.r).abs() < 1e-5);
+        assert!((integrate(cie_z) / y - EQUAL_ENERGY_WHITE.b).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_wavelengths() {
+        let wavelengths = Wavelengths::from_hero(700.0);
+        assert_eq!(wavelengths.hero(), 700.0);
+        for lambda in wavelengths.lambdas.iter() {
+            assert!(*lambda >= MIN_WAVELENGTH && *lambda < MAX_WAVELENGTH);
+        }
+        assert!((wavelengths.lambdas[1] - (700.0 + WAVELENGTH_RANGE / 3.0 - WAVELENGTH_RANGE)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_terminate_secondary() {
+        let mut wavelengths = Wavelengths::from_hero(500.0);
+        let throughput = wavelengths.terminate_secondary(Colour::rgb(0.5, 0.2, 0.1));
+        assert_eq!((throughput.r, throughput.g, throughput.b), (1.5, 0.0, 0.0));
+
+        // Only the first time counts.
+        let throughput = wavelengths.terminate_secondary(throughput);
+        assert_eq!(throughput.r, 1.5);
+    }
+
+    #[test]
+    fn test_blackbody() {
+        let white = blackbody(5455.0);
+        assert!((white.luminance() - 1.0).abs() < 1e-9);
+        // The blackbody colours don't pass exactly through white, but get close.
+        assert!((white.r - 1.0).abs() < 0.1 && (white.g - 1.0).abs() < 0.1 && (white.b - 1.0).abs() < 0.1, "{:?}", white);
+
+        // Candles are orange, and clear skies are blue.
+        let warm = blackbody(1900.0);
+        assert!(warm.r > warm.g && warm.g > warm.b, "{:?}", warm);
+        let cool = blackbody(12000.0);
+        assert!(cool.b > cool.g && cool.g > cool.r, "{:?}", cool);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let white = round_trip(Colour::WHITE);
+        assert!((white.r - 1.0).abs() < 0.01 && (white.g - 1.0).abs() < 0.01 && (white.b - 1.0).abs() < 0.01, "{:?}", white);
+
+        let grey = round_trip(Colour::rgb(0.25, 0.25, 0.25));
+        assert!((grey.g - 0.25).abs() < 0.01, "{:?}", grey);
+
+        // Saturated colours can't make it back exactly, but should stay recognisable.
+        let red = round_trip(Colour::rgb(1.0, 0.0, 0.0));
+        assert!(red.r > 0.8 && red.g < 0.2 && red.b < 0.2, "{:?}", red);
+        let green = round_trip(Colour::rgb(0.0, 1.0, 0.0));
+        assert!(green.g > 0.8 && green.r < 0.2 && green.b < 0.2, "{:?}", green);
+        let blue = round_trip(Colour::rgb(0.0, 0.0, 1.0));
+        assert!(blue.b > 0.8 && blue.r < 0.2 && blue.g < 0.2, "{:?}", blue);
+    }
+}
@@ -8,7 +8,7 @@ pub fn generate_stress_scene(num_spheres: usize) -> serde::SceneDescription {
     let models = HashMap::new();
 
     let objects = (0 .. num_spheres)
-        .map(|_| serde::ObjectDescription{ shape: random_sphere(), material: random_material() })
+        .map(|_| serde::ObjectDescription{ shape: random_sphere(), material: random_material(), medium: None, name: None })
         .collect();
 
     let camera = serde::CameraDescription {
@@ -21,13 +21,14 @@ pub fn generate_stress_scene(num_spheres: usize) -> serde::SceneDescription {
         focal_length: 0.05,
         focus_distance: 10.0,
         aperture: 8.0,
+        exposure: 0.0,
     };
 
     let skybox = serde::SkyboxDescription::Flat(serde::FlatSkyboxDescription{
         colour: serde::ColourDescription{ r: 0.8, g: 0.8, b: 0.8 },
     });
 
-    serde::SceneDescription{ camera, models, skybox, objects, lights: vec![] }
+    serde::SceneDescription{ camera, models, materials: HashMap::new(), skybox, background: None, portals: vec![], objects, lights: vec![], medium: None, volumes: vec![], settings: serde::SettingsDescription::default() }
 }
 
 fn random_sphere() -> serde::ShapeDescription {
@@ -52,6 +53,7 @@ fn random_material() -> serde::MaterialDescription {
         }),
         1 => serde::MaterialDescription::Lambertian(serde::LambertianMaterialDescription{
             albedo: serde::MaterialColourDescription::Rgb { r: colour.r, g: colour.g, b: colour.b },
+            emission: None,
         }),
         _ => serde::MaterialDescription::Mirror(serde::MirrorMaterialDescription{}),
     }
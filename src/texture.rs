@@ -0,0 +1,147 @@
+use image;
+use image::GenericImageView;
+
+use crate::colour::Colour;
+
+// An image which can be looked up by (u, v) texture coordinates.
+// Coordinates wrap, so (1.5, 0.5) is the same as (0.5, 0.5).
+#[derive(Clone, Debug)]
+pub struct Texture {
+    pub width: u32,
+    pub height: u32,
+    data: Vec<Colour>,
+    alpha: Option<Vec<f64>>,
+}
+
+impl Texture {
+    pub fn new(width: u32, height: u32, data: Vec<Colour>) -> Texture {
+        Texture { width, height, data, alpha: None }
+    }
+
+    // Loads an image of colours.
+    pub fn load(filename: &str) -> Texture {
+        // Textures are stored in sRGB, but we want linear colours to render with.
+        Texture::load_with(filename, srgb_to_linear)
+    }
+
+    // Loads an image of non-colour data, like a normal map or height map, which shouldn't be
+    // gamma corrected.
+    pub fn load_linear(filename: &str) -> Texture {
+        Texture::load_with(filename, |c| c as f64 / 255.0)
+    }
+
+    fn load_with<F: Fn(u8) -> f64>(filename: &str, convert: F) -> Texture {
+        println!("Loading texture from '{}'", filename);
+        let img = image::open(filename).expect("Failed to load texture");
+        let (width, height) = img.dimensions();
+
+        let has_alpha = img.color().has_alpha();
+        let rgba = img.to_rgba();
+
+        let data = rgba.pixels()
+            .map(|p| Colour::rgb(convert(p[0]), convert(p[1]), convert(p[2])))
+            .collect();
+
+        let alpha = if has_alpha {
+            Some(rgba.pixels().map(|p| p[3] as f64 / 255.0).collect())
+        } else {
+            None
+        };
+
+        Texture { width, height, data, alpha }
+    }
+
+    // A texture of scalars, made by applying `f` to every pixel's scalar value.
+    pub fn map<F: Fn(f64) -> f64>(&self, f: F) -> Texture {
+        let data = (0 .. self.data.len())
+            .map(|ix| {
+                let x = match self.alpha {
+                    Some(ref alpha) => alpha[ix],
+                    None => self.data[ix].mean(),
+                };
+                Colour::rgb(f(x), f(x), f(x))
+            })
+            .collect();
+        Texture::new(self.width, self.height, data)
+    }
+
+    // Average colour over the whole texture.
+    pub fn mean_colour(&self) -> Colour {
+        self.data.iter().fold(Colour::BLACK, |acc, &c| acc + c) / self.data.len() as f64
+    }
+
+    // Bilinear interpolation of the colour at the given texture coordinates.
+    pub fn colour(&self, u: f64, v: f64) -> Colour {
+        let (ix, weights) = self.bilinear_taps(u, v);
+        (0 .. 4).fold(Colour::BLACK, |acc, t| acc + self.data[ix[t]] * weights[t])
+    }
+
+    // A single value for the given texture coordinates.
+    // Uses the alpha channel if there is one, otherwise the average of the colour channels.
+    pub fn scalar(&self, u: f64, v: f64) -> f64 {
+        match self.alpha {
+            Some(ref alpha) => {
+                let (ix, weights) = self.bilinear_taps(u, v);
+                (0 .. 4).fold(0.0, |acc, t| acc + alpha[ix[t]] * weights[t])
+            },
+            None => self.colour(u, v).mean(),
+        }
+    }
+
+    fn bilinear_taps(&self, u: f64, v: f64) -> ([usize; 4], [f64; 4]) {
+        // Texture coordinates have v pointing up, images have y pointing down.
+        let x = wrap(u) * self.width as f64 - 0.5;
+        let y = (1.0 - wrap(v)) * self.height as f64 - 0.5;
+
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let fx = x - x0;
+        let fy = y - y0;
+
+        let px = |x: f64| (x as i64).rem_euclid(self.width as i64) as usize;
+        let py = |y: f64| (y as i64).rem_euclid(self.height as i64) as usize;
+        let w = self.width as usize;
+
+        let (x0, x1, y0, y1) = (px(x0), px(x0 + 1.0), py(y0), py(y0 + 1.0));
+        (
+            [y0 * w + x0, y0 * w + x1, y1 * w + x0, y1 * w + x1],
+            [(1.0 - fx) * (1.0 - fy), fx * (1.0 - fy), (1.0 - fx) * fy, fx * fy],
+        )
+    }
+}
+
+fn wrap(x: f64) -> f64 {
+    x - x.floor()
+}
+
+fn srgb_to_linear(c: u8) -> f64 {
+    let c = c as f64 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::colour::Colour;
+    use crate::texture::Texture;
+
+    #[test]
+    fn test_texture_lookup() {
+        // 2x1 texture, black on the left and white on the right.
+        let texture = Texture::new(2, 1, vec![Colour::BLACK, Colour::WHITE]);
+
+        // Pixel centres.
+        assert_eq!(texture.colour(0.25, 0.5).r, 0.0);
+        assert_eq!(texture.colour(0.75, 0.5).r, 1.0);
+
+        // Halfway between, and wrapping round the edge.
+        assert_eq!(texture.colour(0.5, 0.5).r, 0.5);
+        assert_eq!(texture.colour(1.0, 0.5).r, 0.5);
+        assert_eq!(texture.colour(1.25, 0.5).r, 0.0);
+
+        assert_eq!(texture.scalar(0.75, 0.5), 1.0);
+    }
+}
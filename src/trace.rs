@@ -1,24 +1,107 @@
+use std::borrow::Cow;
+
 use rand::Rng;
 
 use crate::colour::Colour;
-use crate::geom::{Geometry, Ray};
-use crate::scene::{Entity, Scene};
+use crate::geom::{Collision, Geometry, Ray};
+use crate::medium::{Medium, MediumSample};
+use crate::scene::{Entity, Light, Scene};
+use crate::spectrum::Wavelengths;
+use crate::vector::Vector3;
 
-pub fn trace_ray(scene: &Scene, mut ray: Ray) -> Colour {
+// In spectral mode the path carries the given wavelengths, and the result is the radiance at
+// each of them.  Otherwise it's RGB.
+// `screen` is where the ray left the camera, for looking up the background.
+// `weight` is the camera's weight for the ray, which the light from the scene is multiplied by.
+// The background is shown as it is.
+pub fn trace_ray(scene: &Scene, mut ray: Ray, weight: f64, screen: (f64, f64), mut wavelengths: Option<Wavelengths>) -> Colour {
     let mut throughput = Colour::WHITE;
     let mut colour = Colour::BLACK;
+    let mut background_colour = Colour::BLACK;
     let mut loops = 0;
+    // Medium boundaries passed through, which don't count as bounces.  They're still capped, in
+    // case a ray gets stuck crossing the same surface over and over.
+    let mut crossings = 0;
     let mut last_bounce_specular = true;
+    // Solid angle pdf of the direction picked at the last bounce, for weighting any light it
+    // finds against NEE.
+    let mut last_pdf = 0.0;
+    let mut last_location = ray.origin;
+    // The object the path last bounced off, for light linking.
+    let mut last_object: Option<usize> = None;
+    // Whether the path has come straight from the camera, only passing through medium boundaries.
+    let mut primary = true;
+
+    // Assume the camera is never inside an object.
+    let mut medium: Option<&Medium> = scene.medium.as_ref();
 
     loop {
         if loops > 10 {
             break;
         }
 
-        let (collision, entity) = if let Some((c, e)) = scene.find_intersection(ray) {
+        let intersection = scene.find_intersection(ray);
+
+        // If we're travelling through a medium, the ray might scatter before it gets to the next
+        // surface.
+        if let Some(m) = medium {
+            let m = upsample_medium(m, wavelengths);
+            let max_distance = intersection.as_ref().map_or(std::f64::INFINITY, |(c, _)| c.distance);
+            match m.sample_distance(ray, max_distance) {
+                MediumSample::Scatter { distance, weight } => {
+                    throughput = throughput * weight;
+                    let location = ray.origin + ray.direction * distance;
+
+                    // Next Event Estimation.
+                    let phase = m.phase();
+                    let direct_illumination = sample_direct_lighting(scene, location, None, medium, wavelengths, |light_dir| {
+                        let p = phase.evaluate(ray.direction, light_dir);
+                        (Colour::WHITE * p, p)
+                    });
+                    direct_illumination.check();
+                    colour += direct_illumination * throughput;
+
+                    // Phase function is sampled exactly, so the throughput is unchanged.
+                    let direction = phase.sample(ray.direction);
+                    last_pdf = phase.evaluate(ray.direction, direction);
+                    last_location = location;
+                    last_object = None;
+                    primary = false;
+                    ray = Ray::new(location, direction);
+                    last_bounce_specular = false;
+
+                    if !russian_roulette(&mut throughput, loops) {
+                        break;
+                    }
+
+                    loops += 1;
+                    continue;
+                },
+                MediumSample::Pass { weight } => {
+                    throughput = throughput * weight;
+                    if throughput.max() <= 0.0 {
+                        break;
+                    }
+                },
+            }
+        }
+
+        let (collision, entity) = if let Some((c, e)) = intersection {
             (c, e)
+        } else if let Some(background) = scene.background.as_ref().filter(|_| primary) {
+            // The background hides everything at infinity from the camera, distant lights too.
+            background_colour = throughput * upsample(background.colour(screen), wavelengths);
+            break;
         } else {
-            colour += throughput * scene.skybox.ambient_light(ray.direction * -1);
+            // Like other lights, distant lights and importance sampled skies are also found by
+            // NEE, so are weighted between the two.
+            let weight = light_hit_weight(last_bounce_specular, last_pdf, scene.sky_pdf(last_location, ray.direction * -1));
+            colour += throughput * upsample(scene.skybox.ambient_light(ray.origin, ray.direction * -1), wavelengths) * weight;
+
+            for l in scene.distant_lights(ray.direction).filter(|l| l.illuminates(last_object)) {
+                let weight = path_light_weight(scene, l, ray.direction, last_bounce_specular, last_pdf, last_location);
+                colour += throughput * upsample(l.colour, wavelengths) * l.intensity * weight;
+            }
             break;
         };
 
@@ -29,12 +112,10 @@ pub fn trace_ray(scene: &Scene, mut ray: Ray) -> Colour {
 
         match entity {
             Entity::Light(l) => {
-                // If we hit a light on a specular bounce, just accumulate the light energy and
-                // we're done.
-                // Otherwise we've already taken lights into account via NEE, so don't
-                // accumulate.
-                if last_bounce_specular {
-                    colour += throughput * l.colour * l.intensity;
+                // NEE could also have picked this light, so weight the two between them.
+                if l.shines_towards(collision.front_face) && l.illuminates(last_object) {
+                    let weight = path_light_weight(scene, &l, ray.direction, last_bounce_specular, last_pdf, last_location);
+                    colour += throughput * upsample(l.colour, wavelengths) * l.intensity * weight;
                     colour.check();
                 }
                 break;
@@ -47,48 +128,64 @@ pub fn trace_ray(scene: &Scene, mut ray: Ray) -> Colour {
                         o.material.resolve(&collision, model)
                     },
                     _ => o.material,
+                }.facing(collision.front_face);
+
+                let material = match wavelengths {
+                    Some(ref wl) => material.upsample(wl),
+                    None => material,
                 };
 
+                // Light given off by the surface itself.
+                let vec_out = ray.direction * -1;
+                colour += material.emittance(vec_out, cos_in) * throughput;
+
                 // Next Event Estimation.
-                let direct_illumination = match scene.random_light() {
-                    Some(light) => {
-                        let (in_dir, inv_pdf) = light.sample(collision.location);
-                        let shadow_ray = Ray::new(
-                            collision.location + collision.normal * 0.0001,  // Add the normal as a hack so it doesn't collide with the same object again.
-                            in_dir * -1,
-                        );
-
-                        let occluded = match scene.find_intersection(shadow_ray) {
-                            Some((_, e)) => {
-                                e.id() != light.entity_id()
-                            },
-                            None => false,
-                        };
-
-                        let cos_theta = f64::max(0.0, collision.normal.dot(shadow_ray.direction));
-                        if occluded || cos_theta <= 0.0 {
-                            Colour::BLACK
-                        } else {
-                            let base = light.colour * light.intensity;
-                            let brdf = material.brdf(ray.direction * -1, shadow_ray.direction * -1, collision.normal);
-                            base * brdf * inv_pdf
-                        }
-                    },
-                    None => Colour::BLACK,
-                };
+                let direct_illumination = sample_direct_lighting(scene, offset_origin(&collision, vec_out), Some(o.id), medium, wavelengths, |light_dir| {
+                    let cos_theta = collision.normal.dot(light_dir);
+                    if cos_theta <= 0.0 {
+                        (Colour::BLACK, 0.0)
+                    } else {
+                        let vec_in = light_dir * -1;
+                        (material.brdf(vec_out, vec_in, collision.normal), material.weight_pdf(vec_out, vec_in, collision.normal))
+                    }
+                });
 
                 direct_illumination.check();
                 colour += direct_illumination * throughput;
                 colour.check();
 
-                let (direction, pdf, brdf, is_specular) = material.sample(ray.direction * -1, collision.normal);
-                last_bounce_specular = is_specular;
+                let (direction, pdf, brdf, is_specular) = material.sample(vec_out, collision.normal);
+
+                // Medium boundaries don't scatter the path, so it carries on as it was.
+                if !material.is_null() {
+                    last_bounce_specular = is_specular;
+                    last_pdf = pdf;
+                    last_location = collision.location;
+                    last_object = Some(o.id);
+                    primary = false;
+                }
+
+                // Some sampling methods can produce impossible directions, which just absorb the
+                // light.
+                if pdf <= 0.0 {
+                    break;
+                }
 
                 // Next bounce.
-                let new_ray = Ray::new(
-                    collision.location + collision.normal * 0.0001,  // Add the normal as a hack so it doesn't collide with the same object again.
-                    direction,
-                );
+                let new_ray = Ray::new(offset_origin(&collision, direction), direction);
+
+                // Passing through the surface takes us into (or out of) the object's medium.
+                if direction.dot(collision.normal) < 0.0 {
+                    medium = scene.medium_across(o.id, &collision);
+
+                    // Each wavelength would have been bent a different way, and we've only
+                    // followed the hero wavelength.
+                    if material.is_dispersive() {
+                        if let Some(ref mut wl) = wavelengths {
+                            throughput = wl.terminate_secondary(throughput);
+                        }
+                    }
+                }
 
                 let attenuation = brdf / pdf;
                 throughput = throughput * attenuation;
@@ -97,17 +194,18 @@ pub fn trace_ray(scene: &Scene, mut ray: Ray) -> Colour {
                     break;
                 }
 
-                let emittance = material.emittance(ray.direction * -1, cos_in);
-                colour += emittance * throughput;
-                
-                // Chance for the material to eat the ray.
-                if loops >= 2 {
-                    let survival_chance = throughput.max();
-                    if rand::thread_rng().gen::<f64>() > survival_chance {
+                if material.is_null() {
+                    crossings += 1;
+                    if crossings > 100 {
                         break;
                     }
+                    ray = new_ray;
+                    continue;
+                }
 
-                    throughput = throughput / survival_chance;
+                // Chance for the material to eat the ray.
+                if !russian_roulette(&mut throughput, loops) {
+                    break;
                 }
 
                 ray = new_ray;
@@ -117,5 +215,259 @@ pub fn trace_ray(scene: &Scene, mut ray: Ray) -> Colour {
         loops += 1;
     }
 
-    colour
+    colour * weight + background_colour
+}
+
+// Returns false if the path should be terminated.  Paths carrying more than they started with,
+// like those from lights, always carry on.
+pub fn russian_roulette(throughput: &mut Colour, loops: u32) -> bool {
+    if loops >= 2 {
+        let survival_chance = f64::min(1.0, throughput.max());
+        if rand::thread_rng().gen::<f64>() > survival_chance {
+            return false;
+        }
+
+        *throughput = *throughput / survival_chance;
+    }
+    true
+}
+
+pub fn upsample(colour: Colour, wavelengths: Option<Wavelengths>) -> Colour {
+    match wavelengths {
+        Some(wl) => wl.upsample(colour),
+        None => colour,
+    }
+}
+
+fn upsample_medium(medium: &Medium, wavelengths: Option<Wavelengths>) -> Cow<'_, Medium> {
+    match wavelengths {
+        Some(wl) => Cow::Owned(medium.upsample(&wl)),
+        None => Cow::Borrowed(medium),
+    }
+}
+
+// Add the normal as a hack so it doesn't collide with the same object again.
+// Which side of the surface we want to be on depends on which way we're heading.
+pub fn offset_origin(collision: &Collision, direction: Vector3) -> Vector3 {
+    if direction.dot(collision.normal) >= 0.0 {
+        collision.location + collision.normal * 0.0001
+    } else {
+        collision.location - collision.normal * 0.0001
+    }
+}
+
+// Multiple importance sampling with the power heuristic.
+pub fn power_heuristic(pdf: f64, other_pdf: f64) -> f64 {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b <= 0.0 { 0.0 } else { a / (a + b) }
+}
+
+// Weight for light found by following the path, which NEE would have found with `light_pdf`.
+// Specular bounces can't be matched by NEE, so get all of it.
+pub fn light_hit_weight(last_bounce_specular: bool, last_pdf: f64, light_pdf: f64) -> f64 {
+    if last_bounce_specular { 1.0 } else { power_heuristic(last_pdf, light_pdf) }
+}
+
+// Weight for a light found by following the path in `direction` from `last_location`.
+// Linked lights are left to NEE, other than after specular bounces.
+pub fn path_light_weight(scene: &Scene, light: &Light, direction: Vector3, last_bounce_specular: bool, last_pdf: f64, last_location: Vector3) -> f64 {
+    if light.is_linked() && !last_bounce_specular {
+        return 0.0;
+    }
+    let light_pdf = scene.light_probability(light, last_location) * light.pdf(last_location, direction);
+    light_hit_weight(last_bounce_specular, last_pdf, light_pdf)
+}
+
+// Pick a random light and compute the light arriving at `location` from it, plus the light from
+// the sky if it can be sampled.
+// `object` is the object `location` is on, if any, which not every light may light.
+// `scattering` gives the fraction of light arriving from the given direction which is scattered
+// back along the path, and the pdf of the path continuing in that direction.
+fn sample_direct_lighting<'a, F>(
+    scene: &'a Scene,
+    location: Vector3,
+    object: Option<usize>,
+    medium: Option<&'a Medium>,
+    wavelengths: Option<Wavelengths>,
+    scattering: F,
+) -> Colour
+where F: Fn(Vector3) -> (Colour, f64) {
+    // The fraction of light heading along `in_dir` which reaches `location` and is scattered back
+    // along the path, and the pdf of the path finding the light by itself.
+    let arriving = |in_dir: Vector3, distance: f64, light: Option<&Light>| {
+        let shadow_ray = Ray::new(location, in_dir * -1);
+
+        let (f, scattering_pdf) = scattering(shadow_ray.direction);
+        if f.max() <= 0.0 {
+            return (Colour::BLACK, 0.0);
+        }
+
+        (f * shadow_transmittance(scene, shadow_ray, light, distance, medium, wavelengths), scattering_pdf)
+    };
+
+    let from_light = match scene.random_light(location) {
+        Some((light, probability)) if light.illuminates(object) => {
+            let (in_dir, distance, inv_pdf) = light.sample(location);
+            if inv_pdf <= 0.0 {
+                Colour::BLACK
+            } else {
+                let (arrived, scattering_pdf) = arriving(in_dir, distance, Some(light));
+                let weight = if light.is_delta() || light.is_linked() {
+                    1.0
+                } else {
+                    power_heuristic(probability / inv_pdf, scattering_pdf)
+                };
+                let base = upsample(light.colour, wavelengths) * light.intensity;
+                arrived * base * (inv_pdf * weight / probability)
+            }
+        },
+        _ => Colour::BLACK,
+    };
+
+    let from_sky = match scene.sample_sky(location) {
+        Some((in_dir, pdf)) if pdf > 0.0 => {
+            let (arrived, scattering_pdf) = arriving(in_dir, f64::INFINITY, None);
+            let radiance = upsample(scene.skybox.ambient_light(location, in_dir), wavelengths);
+            arrived * radiance * (power_heuristic(pdf, scattering_pdf) / pdf)
+        },
+        _ => Colour::BLACK,
+    };
+
+    from_light + from_sky
+}
+
+// The fraction of light which makes it from the light along the shadow ray.
+// Lights at infinity have no entity, and just need a clear view of the sky.
+// Null surfaces (medium boundaries) are passed through, accumulating the transmittance of each
+// medium along the way, as are objects which don't cast shadows from the light.  Anything else
+// blocks the light completely.
+fn shadow_transmittance<'a>(
+    scene: &'a Scene,
+    mut ray: Ray,
+    light: Option<&Light>,
+    distance: f64,
+    mut medium: Option<&'a Medium>,
+    wavelengths: Option<Wavelengths>,
+) -> Colour {
+    let mut transmittance = Colour::WHITE;
+    let mut remaining = distance;
+    let light_id = light.map(|l| l.entity_id());
+
+    loop {
+        let (collision, entity) = match scene.find_intersection(ray) {
+            Some((c, e)) if c.distance < remaining || Some(e.id()) == light_id => (c, e),
+            _ => {
+                if let Some(m) = medium {
+                    transmittance = transmittance * upsample_medium(m, wavelengths).transmittance(ray, remaining);
+                }
+                return transmittance;
+            },
+        };
+
+        if let Some(m) = medium {
+            transmittance = transmittance * upsample_medium(m, wavelengths).transmittance(ray, collision.distance);
+        }
+
+        match entity {
+            Entity::Light(l) if Some(l.entity_id()) == light_id => return transmittance,
+            Entity::Object(o) if o.material.is_null() => {
+                medium = scene.medium_across(o.id, &collision);
+                ray = Ray::new(offset_origin(&collision, ray.direction), ray.direction);
+                remaining -= collision.distance;
+            },
+            Entity::Object(o) if matches!(light, Some(l) if !l.casts_shadows_from(o.id)) => {
+                ray = Ray::new(offset_origin(&collision, ray.direction), ray.direction);
+                remaining -= collision.distance;
+            },
+            _ => return Colour::BLACK,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::f64::consts::PI;
+
+    use crate::colour::Colour;
+    use crate::geom::{Geometry, Primitive, Ray};
+    use crate::material::{Material, MaterialColour};
+    use crate::model::ModelLibrary;
+    use crate::scene::{Background, GroundProjection, HdriSettings, Object, Scene, Skybox};
+    use crate::trace::*;
+    use crate::vector::Vector3;
+
+    #[test]
+    fn test_background_unweighted() {
+        let ball = Object {
+            id: 0,
+            geometry: Geometry::Primitive(Primitive::sphere(Vector3::new(0.0, -10.0, 0.0), 1.0)),
+            material: Material::lambertian(MaterialColour::Static(Colour::WHITE), Colour::BLACK),
+            medium: None,
+        };
+        let mut scene = Scene::new(ModelLibrary::new(), vec![ball], vec![], Skybox::flat(Colour::WHITE), None);
+        let ray = Ray::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 1.0));
+
+        // The sky is exposed like the rest of the scene, but the background isn't.
+        assert_eq!(trace_ray(&scene, ray, 0.25, (0.5, 0.5), None).g, 0.25);
+        scene.background = Some(Background::Flat(Colour::rgb(0.5, 0.5, 0.5)));
+        assert_eq!(trace_ray(&scene, ray, 0.25, (0.5, 0.5), None).g, 0.5);
+    }
+
+    #[test]
+    fn test_medium_boundaries_not_bounces() {
+        // More shells than the path has bounces, all of which should be passed straight through.
+        let shells = (0 .. 12).map(|ix| Object {
+            id: ix,
+            geometry: Geometry::Primitive(Primitive::sphere(Vector3::new(0.0, 0.0, 0.0), 1.0 + ix as f64)),
+            material: Material::null(),
+            medium: None,
+        }).collect();
+        let scene = Scene::new(ModelLibrary::new(), shells, vec![], Skybox::flat(Colour::WHITE), None);
+        let ray = Ray::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 1.0));
+
+        assert_eq!(trace_ray(&scene, ray, 1.0, (0.5, 0.5), None).g, 1.0);
+    }
+
+    #[test]
+    fn test_ground_projected_sky() {
+        // Dim everywhere, with a bright ring on the ground.
+        let (width, height) = (16, 8);
+        let data = (0 .. width * height)
+            .map(|ix| if ix / width == 5 { Colour::rgb(1.0, 1.0, 1.0) } else { Colour::rgb(0.01, 0.01, 0.01) })
+            .collect();
+        let settings = HdriSettings {
+            ground: Some(GroundProjection::new(Vector3::new(0.0, 0.0, 0.0), 1.0, 5.0)),
+            ..HdriSettings::default()
+        };
+        let ball = Object {
+            id: 0,
+            geometry: Geometry::Primitive(Primitive::sphere(Vector3::new(2.0, 1.5, 0.0), 1.0)),
+            material: Material::lambertian(MaterialColour::Static(Colour::WHITE), Colour::BLACK),
+            medium: None,
+        };
+        let scene = Scene::new(ModelLibrary::new(), vec![ball], vec![], Skybox::hdri(width, height, data, settings), None);
+
+        // Looking up at the bottom of the ball, which only sees the ground.  NEE picks directions
+        // as if the ground wasn't projected, but the light it misses is still found by sampling
+        // the material.
+        let ray = Ray::new(Vector3::new(2.0, 0.1, 0.0), Vector3::new(0.0, 1.0, 0.0));
+        let n = 20_000;
+        let total = (0 .. n).map(|_| trace_ray(&scene, ray, 1.0, (0.5, 0.5), None).g).sum::<f64>() / n as f64;
+
+        // Everything the bottom of the ball sees, by brute force.
+        let bottom = Vector3::new(2.0, 0.5, 0.0);
+        let steps = 500;
+        let mut expected = 0.0;
+        for iy in 0 .. steps / 2 {
+            for ix in 0 .. 2 * steps {
+                let theta = PI / 2.0 + PI * (iy as f64 + 0.5) / steps as f64;
+                let phi = PI * (ix as f64 + 0.5) / steps as f64;
+                let looking = Vector3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin());
+                let radiance = scene.skybox.ambient_light(bottom, looking * -1).g;
+                expected += radiance * -looking.y * theta.sin() * (PI / steps as f64).powi(2) / PI;
+            }
+        }
+
+        assert!((total - expected).abs() < 0.03 * expected, "{} vs {}", total, expected);
+    }
 }
@@ -0,0 +1,169 @@
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+
+use image;
+
+// A 3D grid of densities, e.g. for clouds and smoke.
+// Densities are stored x-fastest, then y, then z.
+#[derive(Clone, Debug)]
+pub struct DensityGrid {
+    pub width: usize,
+    pub height: usize,
+    pub depth: usize,
+    pub max_density: f64,
+    data: Vec<f64>,
+}
+
+impl DensityGrid {
+    pub fn new(width: usize, height: usize, depth: usize, data: Vec<f64>) -> DensityGrid {
+        if data.len() != width * height * depth {
+            panic!("Density grid of size {}x{}x{} must have {} values, got {}", width, height, depth, width * height * depth, data.len());
+        }
+        let max_density = data.iter().cloned().fold(0.0, f64::max);
+        DensityGrid { width, height, depth, max_density, data }
+    }
+
+    fn voxel(&self, x: usize, y: usize, z: usize) -> f64 {
+        self.data[(z * self.height + y) * self.width + x]
+    }
+
+    // Trilinearly interpolated density at a point in voxel coordinates, where voxel centres are
+    // at half-integer coordinates.
+    // Anything outside the grid has zero density.
+    pub fn density(&self, x: f64, y: f64, z: f64) -> f64 {
+        if x < 0.0 || y < 0.0 || z < 0.0
+            || x > self.width as f64 || y > self.height as f64 || z > self.depth as f64 {
+            return 0.0;
+        }
+
+        let clamp = |v: f64, n: usize| f64::max(0.0, f64::min((n - 1) as f64, v - 0.5));
+        let (x, y, z) = (clamp(x, self.width), clamp(y, self.height), clamp(z, self.depth));
+
+        let (x0, y0, z0) = (x.floor() as usize, y.floor() as usize, z.floor() as usize);
+        let (x1, y1, z1) = (
+            usize::min(x0 + 1, self.width - 1),
+            usize::min(y0 + 1, self.height - 1),
+            usize::min(z0 + 1, self.depth - 1),
+        );
+        let (fx, fy, fz) = (x - x0 as f64, y - y0 as f64, z - z0 as f64);
+
+        let lerp = |a: f64, b: f64, t: f64| a * (1.0 - t) + b * t;
+        let c00 = lerp(self.voxel(x0, y0, z0), self.voxel(x1, y0, z0), fx);
+        let c10 = lerp(self.voxel(x0, y1, z0), self.voxel(x1, y1, z0), fx);
+        let c01 = lerp(self.voxel(x0, y0, z1), self.voxel(x1, y0, z1), fx);
+        let c11 = lerp(self.voxel(x0, y1, z1), self.voxel(x1, y1, z1), fx);
+        lerp(lerp(c00, c10, fy), lerp(c01, c11, fy), fz)
+    }
+}
+
+// Loads a grid in Mitsuba's binary .vol format.
+// Only single-precision float data is supported.  Only the first channel is used.
+pub fn load_vol_file(filename: &str) -> DensityGrid {
+    let mut bytes = Vec::new();
+    File::open(filename)
+        .and_then(|mut f| f.read_to_end(&mut bytes))
+        .expect("Failed to read volume file");
+
+    if bytes.len() < 48 || &bytes[0..3] != b"VOL" || bytes[3] != 3 {
+        panic!("'{}' is not a version 3 .vol file", filename);
+    }
+
+    let read_i32 = |offset: usize| {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(&bytes[offset .. offset + 4]);
+        i32::from_le_bytes(buf)
+    };
+    let read_f32 = |offset: usize| {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(&bytes[offset .. offset + 4]);
+        f32::from_le_bytes(buf)
+    };
+
+    let encoding = read_i32(4);
+    if encoding != 1 {
+        panic!("Unsupported .vol encoding {}, only float32 (1) is supported", encoding);
+    }
+
+    let width = read_i32(8) as usize;
+    let height = read_i32(12) as usize;
+    let depth = read_i32(16) as usize;
+    let channels = read_i32(20) as usize;
+
+    // Skip the bounding box, we position the grid with a transform instead.
+    let data_start = 48;
+    let num_voxels = width * height * depth;
+    if bytes.len() < data_start + num_voxels * channels * 4 {
+        panic!("Volume file '{}' is truncated", filename);
+    }
+
+    let data = (0 .. num_voxels)
+        .map(|ix| read_f32(data_start + ix * channels * 4) as f64)
+        .collect();
+
+    println!("Loaded {}x{}x{} density grid from '{}'", width, height, depth, filename);
+    DensityGrid::new(width, height, depth, data)
+}
+
+// Loads a grid from a directory of images, one per z-slice, in filename order.
+// Density is the average of the RGB channels, scaled to [0, 1].
+pub fn load_image_slices(directory: &str) -> DensityGrid {
+    let mut paths: Vec<std::path::PathBuf> = fs::read_dir(directory)
+        .expect("Failed to read slice directory")
+        .map(|entry| entry.expect("Failed to read directory entry").path())
+        .filter(|path| path.is_file())
+        .collect();
+    paths.sort();
+
+    if paths.is_empty() {
+        panic!("No image slices found in '{}'", directory);
+    }
+
+    let mut width = 0;
+    let mut height = 0;
+    let mut data: Vec<f64> = Vec::new();
+    for path in paths.iter() {
+        let slice = image::open(path).expect("Failed to load image slice").to_rgb();
+        let (w, h) = slice.dimensions();
+        if data.is_empty() {
+            width = w as usize;
+            height = h as usize;
+        } else if w as usize != width || h as usize != height {
+            panic!("Image slice {:?} is {}x{}, expected {}x{}", path, w, h, width, height);
+        }
+
+        // Images have y pointing down, but we want it pointing up.
+        for y in (0 .. h).rev() {
+            for x in 0 .. w {
+                let p = slice.get_pixel(x, y);
+                data.push((p[0] as f64 + p[1] as f64 + p[2] as f64) / (3.0 * 255.0));
+            }
+        }
+    }
+
+    println!("Loaded {}x{}x{} density grid from {} slices in '{}'", width, height, paths.len(), paths.len(), directory);
+    DensityGrid::new(width, height, paths.len(), data)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::volume::DensityGrid;
+
+    #[test]
+    fn test_density_interpolation() {
+        let grid = DensityGrid::new(2, 1, 1, vec![0.0, 1.0]);
+        assert_eq!(grid.max_density, 1.0);
+
+        // Voxel centres.
+        assert_eq!(grid.density(0.5, 0.5, 0.5), 0.0);
+        assert_eq!(grid.density(1.5, 0.5, 0.5), 1.0);
+
+        // Halfway between.
+        assert_eq!(grid.density(1.0, 0.5, 0.5), 0.5);
+
+        // Clamped at the edges, zero outside.
+        assert_eq!(grid.density(1.9, 0.5, 0.5), 1.0);
+        assert_eq!(grid.density(2.1, 0.5, 0.5), 0.0);
+        assert_eq!(grid.density(-0.1, 0.5, 0.5), 0.0);
+    }
+}
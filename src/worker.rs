@@ -3,11 +3,13 @@ use std::sync::Arc;
 use crossbeam::channel;
 use crossbeam::channel::select;
 
+use crate::bdpt::trace_bidirectional;
 use crate::camera::Camera;
 use crate::colour::Colour;
 use crate::matrix::Matrix3;
 use crate::scene::Scene;
 use crate::sampling::{CorrelatedMultiJitteredSampler, Disk, IntoPattern, Square};
+use crate::spectrum::Wavelengths;
 use crate::trace::trace_ray;
 use crate::vector::Vector3;
 
@@ -71,13 +73,31 @@ impl Worker {
         let patterns = sensor_pattern.zip(lens_pattern);
 
         patterns.for_each(|(sensor_sample, lens_sample)| {
-            let samples = req.iter_pixels().map(|(x, y)| {
+            let mut splats = Vec::new();
+            let samples: Vec<(u32, u32, Colour)> = req.iter_pixels().map(|(x, y)| {
                 let (ray, weight) = self.camera.get_ray_for_pixel(x, y, sensor_sample, lens_sample);
-                let colour = trace_ray(&self.scene, ray) * weight;
-                (x, y, colour)
+                let screen = self.camera.screen_position(x, y, sensor_sample);
+                let wavelengths = if self.scene.spectral { Some(Wavelengths::sample()) } else { None };
+
+                // The estimator expects XYZ in spectral mode.
+                let to_estimator = |colour: Colour| match wavelengths {
+                    Some(wl) => wl.to_xyz(colour),
+                    None => colour,
+                };
+
+                if self.scene.bidirectional {
+                    let (colour, light_splats) = trace_bidirectional(&self.scene, &self.camera, ray, weight, screen, wavelengths);
+                    splats.extend(light_splats.into_iter().map(|(sx, sy, c)| (sx, sy, to_estimator(c))));
+                    (x, y, to_estimator(colour))
+                } else {
+                    (x, y, to_estimator(trace_ray(&self.scene, ray, weight, screen, wavelengths)))
+                }
             }).collect();
 
-            match self.result_tx.send(RenderResult{ epoch: self.epoch, samples }) {
+            // Each camera sample traced a light path too.
+            let light_paths = if self.scene.bidirectional { samples.len() as u64 } else { 0 };
+
+            match self.result_tx.send(RenderResult{ epoch: self.epoch, samples, splats, light_paths }) {
                 Ok(_) => (),
                 Err(err) => {
                     panic!("Failed to send samples to main thread: {}", err);
@@ -189,6 +209,10 @@ impl Iterator for PixelGridIter {
 pub struct RenderResult {
     pub epoch: u64,
     pub samples: Vec<(u32, u32, Colour)>,
+    // Light traced straight to the camera by the bidirectional path tracer, which can land on
+    // any pixel.
+    pub splats: Vec<(u32, u32, Colour)>,
+    pub light_paths: u64,
 }
 
 #[cfg(test)]